@@ -0,0 +1,111 @@
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+};
+
+use serde_json::Value;
+
+/// The registry of connected streaming clients, each one receiving the notifications broadcast by
+/// the server through its own channel.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<SyncSender<Value>>>,
+}
+
+/// The values queued for a subscriber, one not keeping up is removed, disconnecting its
+/// receiver once it received the queued ones.
+const MAX_QUEUED: usize = 1024;
+
+impl Subscribers {
+    /// Registers a new subscriber, which is removed once the returned receiver is dropped.
+    pub(crate) fn subscribe(&self) -> Receiver<Value> {
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUED);
+        self.lock().push(tx);
+        rx
+    }
+
+    /// Sends a JSON RPC notification to every subscriber, pruning the disconnected ones. Returns
+    /// the number of subscribers the notification was sent to.
+    pub(crate) fn broadcast(&self, method: &str, params: Value) -> usize {
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        self.send(notification)
+    }
+
+    /// Sends a JSON value to every subscriber, pruning the disconnected ones and the ones whose
+    /// queue is full. Returns the number of subscribers it was sent to.
+    pub(crate) fn send(&self, value: Value) -> usize {
+        let mut senders = self.lock();
+        senders.retain(|sender| sender.try_send(value.clone()).is_ok());
+        senders.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<SyncSender<Value>>> {
+        self.senders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A handle pushing JSON events to the clients of the server-sent events endpoint, see
+/// [`crate::Config::events_path`], from any thread of the application.
+#[derive(Clone)]
+pub struct EventSender {
+    pub(crate) subscribers: Arc<Subscribers>,
+}
+
+impl EventSender {
+    /// Sends an event to all the connected clients, returns the number of clients it was sent
+    /// to.
+    pub fn send(&self, event: Value) -> usize {
+        self.subscribers.send(event)
+    }
+}
+
+impl fmt::Debug for EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let clients = self.subscribers.lock().len();
+        f.debug_struct("EventSender")
+            .field("clients", &clients)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Subscribers, MAX_QUEUED};
+
+    #[test]
+    fn broadcast() {
+        let subscribers = Subscribers::default();
+        assert_eq!(subscribers.broadcast("block", json!([1])), 0);
+
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        assert_eq!(subscribers.broadcast("block", json!([2])), 2);
+        let expected = json!({"jsonrpc": "2.0", "method": "block", "params": [2]});
+        assert_eq!(first.recv().unwrap(), expected);
+        assert_eq!(second.recv().unwrap(), expected);
+
+        // disconnected subscribers are pruned
+        drop(first);
+        assert_eq!(subscribers.broadcast("block", json!([3])), 1);
+        assert_eq!(subscribers.lock().len(), 1);
+        assert_eq!(second.recv().unwrap()["params"], json!([3]));
+
+        // as are the ones not keeping up
+        for i in 0..MAX_QUEUED {
+            assert_eq!(subscribers.broadcast("block", json!([i])), 1);
+        }
+        assert_eq!(subscribers.broadcast("block", json!([])), 0);
+        assert_eq!(second.iter().count(), MAX_QUEUED);
+    }
+}
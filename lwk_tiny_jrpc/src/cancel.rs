@@ -0,0 +1,140 @@
+//! Cancellation of in-flight requests with the built-in `rpc.cancel` method.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::Id;
+
+/// Signals a method handler that the client cancelled its request with `rpc.cancel`, available
+/// in the [`crate::RequestContext`].
+///
+/// Cancellation is cooperative: long running handlers are expected to check
+/// [`CancellationToken::is_cancelled`] periodically and return early, for instance with an
+/// error.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Whether the request was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+}
+
+/// The cancellation tokens of the requests being handled, by client IP address and request id.
+///
+/// Ids are chosen by the clients, so a client can only cancel its own requests, identified by
+/// its IP address. Requests without an address, such as the ones received on a Unix socket,
+/// share the `None` address. Different requests of a client may share an id, cancelling it
+/// cancels all of them.
+#[derive(Default)]
+pub(crate) struct Cancellations {
+    requests: Mutex<HashMap<Key, Vec<CancellationToken>>>,
+}
+
+type Key = (Option<IpAddr>, String);
+
+impl Cancellations {
+    /// Registers a request of `client` until the returned guard is dropped.
+    pub(crate) fn register(&self, client: Option<IpAddr>, id: &Id) -> Registration<'_> {
+        let key = key(client, id);
+        let token = CancellationToken::default();
+        self.lock()
+            .entry(key.clone())
+            .or_default()
+            .push(token.clone());
+        Registration {
+            cancellations: self,
+            key,
+            token,
+        }
+    }
+
+    /// Cancels the requests of `client` with the given id, returns whether there was any.
+    pub(crate) fn cancel(&self, client: Option<IpAddr>, id: &Id) -> bool {
+        match self.lock().get(&key(client, id)) {
+            Some(tokens) => {
+                tokens.iter().for_each(CancellationToken::cancel);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Vec<CancellationToken>>> {
+        self.requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ids aren't hashable, their JSON representation is
+fn key(client: Option<IpAddr>, id: &Id) -> Key {
+    (client, serde_json::to_string(id).unwrap_or_default())
+}
+
+pub(crate) struct Registration<'a> {
+    cancellations: &'a Cancellations,
+    key: Key,
+    token: CancellationToken,
+}
+
+impl Registration<'_> {
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut requests = self.cancellations.lock();
+        if let Some(tokens) = requests.get_mut(&self.key) {
+            tokens.retain(|token| !Arc::ptr_eq(&token.0, &self.token.0));
+            if tokens.is_empty() {
+                requests.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancellations() {
+        let cancellations = Cancellations::default();
+        let id = Id::Number(1.into());
+        let client = Some(IpAddr::from([10, 0, 0, 1]));
+        let stranger = Some(IpAddr::from([10, 0, 0, 2]));
+        assert!(!cancellations.cancel(client, &id));
+
+        let first = cancellations.register(client, &id);
+        let second = cancellations.register(client, &id);
+        let other = cancellations.register(client, &Id::String("1".into()));
+        let unix = cancellations.register(None, &id);
+        assert!(!cancellations.cancel(stranger, &id));
+        assert!(!first.token().is_cancelled());
+        assert!(cancellations.cancel(client, &id));
+        assert!(first.token().is_cancelled());
+        assert!(second.token().is_cancelled());
+        assert!(!other.token().is_cancelled());
+        assert!(!unix.token().is_cancelled());
+
+        drop(first);
+        assert!(cancellations.cancel(client, &id));
+        drop(second);
+        assert!(!cancellations.cancel(client, &id));
+        assert!(cancellations.lock().len() == 2);
+    }
+}
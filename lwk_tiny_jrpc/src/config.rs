@@ -1,15 +1,341 @@
-use std::{num::NonZeroU8, path::PathBuf};
+use std::{
+    collections::HashMap, fmt, io, net::IpAddr, num::NonZeroU8, path::PathBuf, str::FromStr,
+    sync::Arc, time::Duration,
+};
 
+use serde_json::Value;
 use tiny_http::Header;
 
-#[derive(Debug, Clone)]
+use crate::{middleware::Middleware, GlobPattern, RequestContext};
+
+/// See [`Config::accept_filter`].
+pub type AcceptFilter = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
+
+/// See [`Config::get_routes`].
+pub type GetHandler = Arc<dyn Fn(&RequestContext) -> tiny_http::ResponseBox + Send + Sync>;
+
+/// See [`Config::readiness_checks`].
+pub type ReadinessCheck = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// See [`Config::on_send_error`].
+pub type SendErrorHook = Arc<dyn Fn(&io::Error) + Send + Sync>;
+
+/// See [`Config::on_tick`].
+pub type TickHook = Arc<dyn Fn() + Send + Sync>;
+
+/// A custom error document, see [`Config::error_pages`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorPage {
+    /// An HTML file, read on every error so that it can be updated while running.
+    File(PathBuf),
+    /// An HTML page.
+    Inline(String),
+}
+
+/// The cross-origin requests browsers are allowed to make, see [`Config::cors`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorsPolicy {
+    /// The allowed origins, such as `https://wallet.example`, `None` allowing any origin.
+    pub allowed_origins: Option<Vec<String>>,
+    /// The request headers allowed besides the CORS-safelisted ones, answered to preflights.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers can cache the answer to a preflight, if `None` they use their default.
+    pub max_age: Option<Duration>,
+}
+
+impl CorsPolicy {
+    /// Allows requests from any origin, with the `Content-Type` header.
+    pub fn any_origin() -> Self {
+        Self {
+            allowed_origins: None,
+            allowed_headers: vec!["Content-Type".to_string()],
+            max_age: None,
+        }
+    }
+
+    /// Allows requests from the given origins only, with the `Content-Type` header.
+    pub fn origins<I, S>(origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed_origins: Some(origins.into_iter().map(Into::into).collect()),
+            ..Self::any_origin()
+        }
+    }
+
+    /// Adds a request header to [`CorsPolicy::allowed_headers`], such as `Authorization`.
+    pub fn with_allowed_header(mut self, header: &str) -> Self {
+        self.allowed_headers.push(header.to_string());
+        self
+    }
+
+    /// Sets [`CorsPolicy::max_age`], sent as `Access-Control-Max-Age` in whole seconds.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Returns true if requests from `origin` are allowed, comparing it case-insensitively and
+    /// ignoring a trailing `/` on both sides.
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.trim_end_matches('/');
+        match &self.allowed_origins {
+            None => true,
+            Some(origins) => origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
-    /// Additional headers to add to GET and OPTIONS requests.
+    /// Additional headers to add to GET, OPTIONS and POST responses, they can be replaced while
+    /// running with [`crate::JsonRpcServer::set_headers`].
     pub headers: Vec<Header>,
+    /// Validate the `Origin` of the requests against the policy, adding the
+    /// `Access-Control-Allow-Origin` header reflecting it to the responses when allowed, and
+    /// answering the OPTIONS preflights with the allowed methods and headers. Requests from other
+    /// origins are still handled, but browsers don't let their scripts read the responses. Don't
+    /// also configure the CORS headers in [`Config::headers`].
+    pub cors: Option<CorsPolicy>,
     /// The number of threads to use for serving requests.
     pub num_threads: NonZeroU8,
+    /// The stack size in bytes of the threads serving requests, which run the method handler.
+    /// If `None` the platform default is used.
+    pub thread_stack_size: Option<usize>,
     /// The path to serve HTTP GET requests from.
     pub serve_dir: Option<PathBuf>,
+    /// Files compiled into the binary, such as with `include_bytes!`, by path relative to the
+    /// root without the leading slash, such as `index.html` or `js/app.js`. They are served
+    /// before the files of [`Config::serve_dir`], which isn't required, with the same headers.
+    pub embedded_assets: HashMap<String, &'static [u8]>,
+    /// Answer all GET and HEAD requests with HTTP status 404, without serving files or the
+    /// [`Config::index_message`], for servers only handling JSON RPC requests. The endpoints set
+    /// explicitly, [`Config::get_routes`], [`Config::events_path`] and the probes, are still
+    /// answered.
+    pub disable_get: bool,
+    /// Serve symlinks in [`Config::serve_dir`] pointing outside of it, such as to shared assets.
+    /// If false they are answered with HTTP status 404. Paths containing `..` are always
+    /// rejected.
+    pub follow_symlinks: bool,
+    /// Serve the `index.html` of [`Config::serve_dir`] for GET requests of missing paths
+    /// without a file extension, such as `/wallet/settings`, so that single-page apps can route
+    /// them on the client side. Missing paths with an extension, such as `/app.js`, are still
+    /// answered with 404.
+    pub spa_fallback: bool,
+    /// Answer GET requests of directories without an `index.html` in [`Config::serve_dir`] with
+    /// the list of their entries, name, size and modification time, as JSON for clients
+    /// accepting `application/json` and as an HTML page otherwise. If false they are answered
+    /// with HTTP status 404.
+    pub directory_listing: bool,
+    /// Handlers of GET and HEAD requests by URL path pattern, such as `pset/*.base64` or
+    /// `metrics`, answering them instead of [`Config::serve_dir`], even if
+    /// [`Config::disable_get`] is set. The handler of the first pattern matching the path is
+    /// called on the worker thread, the additional headers are added to its response.
+    pub get_routes: Vec<(GlobPattern, GetHandler)>,
+    /// The URL path accepting JSON RPC requests, such as `/rpc`. POST requests to other paths are
+    /// answered with HTTP status 404. If `None`, JSON RPC requests are accepted on any path.
+    pub rpc_path: Option<String>,
+    /// The plain text returned to GET requests to `/` when [`Config::serve_dir`] is not set, such
+    /// as a browser opening the server URL. If `None` they are answered with HTTP status 500.
+    pub index_message: Option<String>,
+    /// The URL path of the server-sent events endpoint, such as `/events`. GET requests to it
+    /// are answered with a `text/event-stream` carrying the events sent with
+    /// [`crate::EventSender`] and the notifications sent with
+    /// [`crate::JsonRpcServer::broadcast`], one `data` line of JSON each. Each connected client
+    /// holds a thread, not a worker, and is disconnected if it doesn't keep up with the events.
+    /// If `None` there is no such endpoint.
+    pub events_path: Option<String>,
+    /// The maximum number of clients connected to [`Config::events_path`], further ones are
+    /// answered with HTTP status 503.
+    pub max_event_streams: usize,
+    /// The URL path of the liveness endpoint, such as `/health`, answering GET and HEAD
+    /// requests with HTTP status 200 while the worker threads are serving requests. If `None`
+    /// there is no such endpoint.
+    pub health_path: Option<String>,
+    /// The URL path of the readiness endpoint, such as `/ready`, answering GET and HEAD requests
+    /// with HTTP status 200 if all the [`Config::readiness_checks`] pass, and 503 if any fails
+    /// or the server is draining. The JSON body lists the result of each check. If `None` there
+    /// is no such endpoint.
+    pub ready_path: Option<String>,
+    /// Named checks run on every request to [`Config::ready_path`], such as whether a backend
+    /// is reachable, returning the reason of the failure.
+    pub readiness_checks: Vec<(String, ReadinessCheck)>,
+    /// The address of an additional listener accepting WebSocket connections, speaking JSON RPC
+    /// with the same handler, see [`crate::websocket`]. Each connection holds a thread, not a
+    /// worker, handling its requests in order. Use port 0 to let the OS choose one, see
+    /// [`crate::JsonRpcServer::websocket_addr`]. Connections are admitted like HTTP requests,
+    /// through [`Config::accept_filter`], [`Config::trusted_proxies`] and the [`Config::cors`]
+    /// origins, and each one holds a slot of [`Config::max_concurrent_per_ip`] while open.
+    #[cfg(feature = "websocket")]
+    pub websocket_addr: Option<std::net::SocketAddr>,
+    /// The maximum number of open WebSocket connections, further ones are refused with HTTP
+    /// status 503.
+    #[cfg(feature = "websocket")]
+    pub max_websocket_connections: usize,
+    /// The PEM file of the certificate chain served by [`crate::JsonRpcServer::new_tls`].
+    #[cfg(feature = "tls")]
+    pub tls_cert_path: Option<PathBuf>,
+    /// The PEM file of the private key of the certificate.
+    #[cfg(feature = "tls")]
+    pub tls_key_path: Option<PathBuf>,
+    /// Read the certificate and key files again when the process receives SIGHUP, such as after
+    /// renewing the certificate, without restarting the server. This replaces the default
+    /// action of SIGHUP, terminating the process. See also
+    /// [`crate::JsonRpcServer::reload_tls`].
+    #[cfg(feature = "tls")]
+    pub tls_reload_on_sighup: bool,
+    /// The PEM file of the CA certificates issuing client certificates. If set, the HTTPS
+    /// listener only accepts clients presenting a certificate issued by one of them, available
+    /// to handlers as [`crate::RequestContext::client_certificate`].
+    #[cfg(feature = "tls")]
+    pub tls_client_ca_path: Option<PathBuf>,
+    /// Compress the response bodies of at least this size, JSON RPC responses and static
+    /// files, with gzip or deflate as preferred by the client `Accept-Encoding` header.
+    /// Streamed results are not compressed.
+    #[cfg(feature = "compression")]
+    pub compress_min_bytes: Option<usize>,
+    /// The maximum number of requests of a given method handled concurrently across all
+    /// threads. Requests exceeding the limit are rejected with HTTP status 503.
+    pub method_concurrency: HashMap<String, usize>,
+    /// The maximum number of JSON RPC requests from a single client IP address handled
+    /// concurrently. Requests exceeding the limit are rejected with HTTP status 429.
+    pub max_concurrent_per_ip: Option<usize>,
+    /// Long poll methods and their timeout. Their handlers are expected to wait for new data at
+    /// most for the timeout, for instance with [`crate::Subscription::wait`], returning
+    /// [`crate::Response::long_poll_timeout`] if none arrived.
+    /// Handlers still running once the timeout expires are left running in the background while
+    /// the client receives [`crate::Response::long_poll_timeout`].
+    ///
+    /// Each blocked long poll holds a worker thread, so [`Config::num_threads`] bounds the
+    /// number of concurrent long polls together with the other requests, limiting them with
+    /// [`Config::method_concurrency`] keeps workers available for other methods.
+    pub long_poll: HashMap<String, Duration>,
+    /// The maximum time a method handler can take, other than the [`Config::long_poll`] ones. If
+    /// exceeded the client receives an error, the method is logged and the request is cancelled
+    /// through [`RequestContext::cancellation`]. The handler is left running in the background,
+    /// since threads can't be interrupted, and no longer counts against
+    /// [`Config::method_concurrency`].
+    ///
+    /// The handlers run on a separate thread when set, so that the worker can answer.
+    pub request_timeout: Option<Duration>,
+    /// Reject with HTTP status 508 the requests made by method handlers of this server, instead
+    /// of risking a deadlock when all the workers wait for each other. Requests dispatched in
+    /// process by the thread of a handler are detected, as are the ones received from a loopback
+    /// address that is the local address of a socket of this process while a handler is running,
+    /// which includes requests made by other threads of the process. Those can't be detected on
+    /// platforms other than Unix. Rejecting an HTTP request requires a free worker: with a
+    /// single worker the call still blocks until the client times out.
+    pub reentrancy_guard: bool,
+    /// The maximum size of a JSON RPC request body, larger requests are rejected with HTTP
+    /// status 413, checking the declared `Content-Length` before reading and the bytes read for
+    /// chunked or compressed bodies. Independently of this, request bodies over 1 GiB are always
+    /// rejected.
+    pub max_body_bytes: Option<u64>,
+    /// Reject JSON RPC requests without an `application/json` `Content-Type` header. If false,
+    /// the body is parsed as JSON regardless of the `Content-Type`, for clients sending an
+    /// unusual or missing one.
+    pub require_json_content_type: bool,
+    /// Also accept the `application/json-rpc` media type when requiring a JSON `Content-Type`.
+    pub accept_json_rpc_content_type: bool,
+    /// The maximum number of requests in a batch, larger batches are rejected as a whole with
+    /// HTTP status 413 before handling any of their requests.
+    pub max_batch_size: Option<usize>,
+    /// The maximum size of a serialized JSON RPC response, larger responses are replaced with
+    /// an internal error and their size is logged, as a safety valve for misbehaving handlers.
+    pub max_response_bytes: Option<usize>,
+    /// Add the time spent in the method handler to every response, as the `elapsed_ms` of the
+    /// `_meta` extension object, for profiling slow methods.
+    pub response_timing: bool,
+    /// Reject requests with scalar `params`, the specification requires an array or an object
+    /// when present.
+    pub strict_params: bool,
+    /// Reject requests with members other than `jsonrpc`, `id`, `method` and `params`, which
+    /// are ignored otherwise.
+    pub deny_unknown_fields: bool,
+    /// Reject requests without an `id`, which are handled as notifications otherwise.
+    pub require_id: bool,
+    /// Accept JSON RPC 1.0 requests, with `jsonrpc` omitted or `"1.0"`, as sent by legacy
+    /// bitcoind style clients. Their responses have both `result` and `error`, one of them
+    /// `null`, and requests with a `null` id are notifications.
+    pub jsonrpc_1_compat: bool,
+    /// Answer the built-in maintenance methods `rpc.ping`, `rpc.version`, `rpc.uptime` and
+    /// `rpc.threads` without calling the method handler, as well as `rpc.cancel`, cancelling the
+    /// in-flight requests of the same client IP address with the id given in its params, see
+    /// [`crate::CancellationToken`]. If false they are rejected like the other methods with the
+    /// reserved `rpc.` prefix.
+    pub builtin_methods: bool,
+    /// The request header carrying an idempotency key, such as `Idempotency-Key`. Successful
+    /// responses to requests with a key are kept for `idempotency_ttl` and replayed to requests
+    /// repeating the key instead of calling the method handler again, letting clients safely
+    /// retry side-effecting methods. Keys are scoped to the client IP address and `Authorization`
+    /// header, and reusing a key for a different method or params is answered with a conflict
+    /// error. The most recent 10000 responses are kept.
+    pub idempotency_header: Option<String>,
+    /// How long responses to requests with an idempotency key are kept.
+    pub idempotency_ttl: Duration,
+    /// Log JSON RPC request and response bodies at trace level, truncated if too long.
+    pub log_bodies: bool,
+    /// Respond with an HTML page instead of a JSON RPC error object or plain text to clients
+    /// preferring HTML according to their `Accept` header, such as browsers.
+    pub html_errors: bool,
+    /// Custom HTML pages of GET and HEAD error responses by HTTP status, such as 404 and 500,
+    /// sent instead of the plain text or [`Config::html_errors`] ones. If a page file can't be
+    /// read the default response is sent.
+    pub error_pages: HashMap<u16, ErrorPage>,
+    /// The maximum number of requests served on a single keep-alive connection. The response to
+    /// the last one carries a `Connection: close` header, after which clients are expected to
+    /// close the connection and open a new one.
+    ///
+    /// Connections are told apart by the client address and port.
+    pub max_requests_per_connection: Option<usize>,
+    /// Calls, as method and params, run once at startup by [`crate::JsonRpcServer::try_new`] to
+    /// catch handler wiring mistakes before serving requests. A call fails if the handler returns
+    /// an error response.
+    pub self_test: Vec<(String, Option<Value>)>,
+    /// Files served from [`Config::serve_dir`] that browsers should save instead of displaying,
+    /// they are sent with a `Content-Disposition: attachment` header. Entries starting with a dot
+    /// match a file extension, such as `.json`, others match a subpath, such as `/export`.
+    pub download_paths: Vec<String>,
+    /// `Cache-Control` header values of files served from [`Config::serve_dir`], the value of
+    /// the first pattern matching the request path is used, such as `no-cache` for `index.html`
+    /// and `max-age=31536000, immutable` for `**/*.js` hashed file names. Directory requests
+    /// are matched as the `index.html` they serve.
+    pub cache_control: Vec<(GlobPattern, String)>,
+    /// `Content-Type` header values of files served from [`Config::serve_dir`] by lowercase
+    /// file extension without the dot, such as `wasm`. They take precedence over the built-in
+    /// types of common web files, files with an unknown extension are sent without the header.
+    pub mime_types: HashMap<String, String>,
+    /// The number of errors retained for [`crate::JsonRpcServer::recent_errors`], 0 disables
+    /// retaining them.
+    pub max_recent_errors: usize,
+    /// Called with the client IP address of every request before handling it, requests for which
+    /// it returns false are answered with HTTP status 403 and the connection is closed, without
+    /// parsing the body. Requests received on a Unix socket have no IP address and are always
+    /// accepted.
+    pub accept_filter: Option<AcceptFilter>,
+    /// The addresses of the reverse proxies, such as nginx, whose `X-Forwarded-For` and
+    /// `X-Real-IP` headers are trusted. The client address of their requests, as seen by the
+    /// [`Config::accept_filter`], the per IP limits, the logs and the [`RequestContext`], is the
+    /// last one in `X-Forwarded-For` not of a trusted proxy, or the one in `X-Real-IP`, with port
+    /// 0. The headers of requests from other addresses are ignored.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Called when sending a response fails. The error is logged regardless.
+    ///
+    /// Note tiny_http ignores most errors caused by the client closing the connection early.
+    pub on_send_error: Option<SendErrorHook>,
+    /// Called periodically by a single worker thread, for housekeeping such as cache eviction
+    /// without starting a separate thread.
+    ///
+    /// It runs roughly every 100 milliseconds between requests handled by the first worker, so
+    /// calls are delayed while that worker handles a long request. It should return quickly since
+    /// the worker doesn't serve requests meanwhile.
+    pub on_tick: Option<TickHook>,
+    /// The middleware run around the method handler, see [`Middleware`].
+    pub middleware: Vec<Arc<dyn Middleware>>,
 }
 
 impl Config {
@@ -18,20 +344,191 @@ impl Config {
     }
 }
 
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Config");
+        debug
+            .field("headers", &self.headers)
+            .field("cors", &self.cors)
+            .field("num_threads", &self.num_threads)
+            .field("thread_stack_size", &self.thread_stack_size)
+            .field("serve_dir", &self.serve_dir)
+            .field(
+                "embedded_assets",
+                &self.embedded_assets.keys().collect::<Vec<_>>(),
+            )
+            .field("disable_get", &self.disable_get)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("spa_fallback", &self.spa_fallback)
+            .field("directory_listing", &self.directory_listing)
+            .field(
+                "get_routes",
+                &self
+                    .get_routes
+                    .iter()
+                    .map(|(pattern, _)| pattern)
+                    .collect::<Vec<_>>(),
+            )
+            .field("rpc_path", &self.rpc_path)
+            .field("index_message", &self.index_message)
+            .field("events_path", &self.events_path)
+            .field("max_event_streams", &self.max_event_streams)
+            .field("health_path", &self.health_path)
+            .field("ready_path", &self.ready_path)
+            .field(
+                "readiness_checks",
+                &self
+                    .readiness_checks
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            );
+        #[cfg(feature = "websocket")]
+        debug
+            .field("websocket_addr", &self.websocket_addr)
+            .field("max_websocket_connections", &self.max_websocket_connections);
+        #[cfg(feature = "tls")]
+        debug
+            .field("tls_cert_path", &self.tls_cert_path)
+            .field("tls_key_path", &self.tls_key_path)
+            .field("tls_reload_on_sighup", &self.tls_reload_on_sighup)
+            .field("tls_client_ca_path", &self.tls_client_ca_path);
+        #[cfg(feature = "compression")]
+        debug.field("compress_min_bytes", &self.compress_min_bytes);
+        debug
+            .field("method_concurrency", &self.method_concurrency)
+            .field("max_concurrent_per_ip", &self.max_concurrent_per_ip)
+            .field("long_poll", &self.long_poll)
+            .field("request_timeout", &self.request_timeout)
+            .field("reentrancy_guard", &self.reentrancy_guard)
+            .field("max_body_bytes", &self.max_body_bytes)
+            .field("require_json_content_type", &self.require_json_content_type)
+            .field(
+                "accept_json_rpc_content_type",
+                &self.accept_json_rpc_content_type,
+            )
+            .field("max_batch_size", &self.max_batch_size)
+            .field("max_response_bytes", &self.max_response_bytes)
+            .field("response_timing", &self.response_timing)
+            .field("strict_params", &self.strict_params)
+            .field("deny_unknown_fields", &self.deny_unknown_fields)
+            .field("require_id", &self.require_id)
+            .field("jsonrpc_1_compat", &self.jsonrpc_1_compat)
+            .field("builtin_methods", &self.builtin_methods)
+            .field("idempotency_header", &self.idempotency_header)
+            .field("idempotency_ttl", &self.idempotency_ttl)
+            .field("log_bodies", &self.log_bodies)
+            .field("html_errors", &self.html_errors)
+            .field("error_pages", &self.error_pages)
+            .field(
+                "max_requests_per_connection",
+                &self.max_requests_per_connection,
+            )
+            .field("self_test", &self.self_test)
+            .field("download_paths", &self.download_paths)
+            .field("cache_control", &self.cache_control)
+            .field("mime_types", &self.mime_types)
+            .field("max_recent_errors", &self.max_recent_errors)
+            .field("accept_filter", &self.accept_filter.as_ref().map(|_| "Fn"))
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("on_send_error", &self.on_send_error.as_ref().map(|_| "Fn"))
+            .field("on_tick", &self.on_tick.as_ref().map(|_| "Fn"))
+            .field(
+                "middleware",
+                &self
+                    .middleware
+                    .iter()
+                    .map(|_| "Middleware")
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             headers: Vec::new(),
+            cors: None,
             num_threads: NonZeroU8::new(4).expect("non-zero"),
+            thread_stack_size: None,
             serve_dir: None,
+            embedded_assets: HashMap::new(),
+            disable_get: false,
+            follow_symlinks: false,
+            spa_fallback: false,
+            directory_listing: false,
+            get_routes: Vec::new(),
+            rpc_path: None,
+            index_message: Some(DEFAULT_INDEX_MESSAGE.to_string()),
+            events_path: None,
+            max_event_streams: 256,
+            health_path: None,
+            ready_path: None,
+            readiness_checks: Vec::new(),
+            #[cfg(feature = "websocket")]
+            websocket_addr: None,
+            #[cfg(feature = "websocket")]
+            max_websocket_connections: 256,
+            #[cfg(feature = "tls")]
+            tls_cert_path: None,
+            #[cfg(feature = "tls")]
+            tls_key_path: None,
+            #[cfg(feature = "tls")]
+            tls_reload_on_sighup: false,
+            #[cfg(feature = "tls")]
+            tls_client_ca_path: None,
+            #[cfg(feature = "compression")]
+            compress_min_bytes: None,
+            method_concurrency: HashMap::new(),
+            max_concurrent_per_ip: None,
+            long_poll: HashMap::new(),
+            request_timeout: None,
+            reentrancy_guard: false,
+            max_body_bytes: None,
+            require_json_content_type: true,
+            accept_json_rpc_content_type: false,
+            max_batch_size: None,
+            max_response_bytes: None,
+            response_timing: false,
+            strict_params: false,
+            deny_unknown_fields: false,
+            require_id: false,
+            jsonrpc_1_compat: false,
+            builtin_methods: true,
+            idempotency_header: None,
+            idempotency_ttl: Duration::from_secs(600),
+            log_bodies: false,
+            html_errors: false,
+            error_pages: HashMap::new(),
+            max_requests_per_connection: None,
+            self_test: Vec::new(),
+            download_paths: Vec::new(),
+            cache_control: Vec::new(),
+            mime_types: HashMap::new(),
+            max_recent_errors: 16,
+            accept_filter: None,
+            trusted_proxies: Vec::new(),
+            on_send_error: None,
+            on_tick: None,
+            middleware: Vec::new(),
         }
     }
 }
 
+/// The default [`Config::index_message`].
+const DEFAULT_INDEX_MESSAGE: &str = "This is a JSON-RPC endpoint; POST application/json";
+
+/// Headers added by [`ConfigBuilder::secure_defaults`].
+const SECURE_HEADERS: [&str; 3] = [
+    "X-Content-Type-Options: nosniff",
+    "X-Frame-Options: DENY",
+    "Content-Security-Policy: default-src 'self'",
+];
+
+#[derive(Default)]
 pub struct ConfigBuilder {
-    headers: Vec<Header>,
-    num_threads: NonZeroU8,
-    serve_dir: Option<PathBuf>,
+    config: Config,
 }
 
 impl ConfigBuilder {
@@ -40,35 +537,354 @@ impl ConfigBuilder {
     }
 
     pub fn with_headers(mut self, headers: Vec<Header>) -> Self {
-        self.headers = headers;
+        self.config.headers = headers;
+        self
+    }
+
+    /// Adds a header, replacing any previously configured header with the same field.
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.config.headers.retain(|h| h.field != header.field);
+        self.config.headers.push(header);
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.config.cors = Some(cors);
+        self
+    }
+
+    /// Adds `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY` and a basic
+    /// `Content-Security-Policy` to the configured headers.
+    ///
+    /// Headers already configured with the same field are kept, so individual values can be
+    /// overridden by setting them with [`ConfigBuilder::with_header`] before or after this call.
+    pub fn secure_defaults(mut self) -> Self {
+        for header in SECURE_HEADERS {
+            let header = Header::from_str(header).expect("valid header");
+            if !self.config.headers.iter().any(|h| h.field == header.field) {
+                self.config.headers.push(header);
+            }
+        }
         self
     }
 
     pub fn with_num_threads(mut self, num: NonZeroU8) -> Self {
-        self.num_threads = num;
+        self.config.num_threads = num;
+        self
+    }
+
+    pub fn with_thread_stack_size(mut self, size: Option<usize>) -> Self {
+        self.config.thread_stack_size = size;
         self
     }
 
     pub fn with_serve_dir(mut self, dir: Option<PathBuf>) -> Self {
-        self.serve_dir = dir;
+        self.config.serve_dir = dir;
         self
     }
 
-    pub fn build(self) -> Config {
-        Config {
-            headers: self.headers,
-            num_threads: self.num_threads,
-            serve_dir: self.serve_dir,
-        }
+    /// Adds an entry to [`Config::embedded_assets`], a leading slash in `path` is ignored.
+    pub fn with_embedded_asset(mut self, path: &str, data: &'static [u8]) -> Self {
+        self.config
+            .embedded_assets
+            .insert(path.trim_start_matches('/').to_string(), data);
+        self
     }
-}
 
-impl Default for ConfigBuilder {
-    fn default() -> Self {
-        Self {
-            headers: Vec::new(),
-            num_threads: NonZeroU8::new(4).expect("non-zero"),
-            serve_dir: None,
-        }
+    pub fn with_disable_get(mut self, disable: bool) -> Self {
+        self.config.disable_get = disable;
+        self
+    }
+
+    pub fn with_follow_symlinks(mut self, follow: bool) -> Self {
+        self.config.follow_symlinks = follow;
+        self
+    }
+
+    pub fn with_spa_fallback(mut self, fallback: bool) -> Self {
+        self.config.spa_fallback = fallback;
+        self
+    }
+
+    pub fn with_directory_listing(mut self, listing: bool) -> Self {
+        self.config.directory_listing = listing;
+        self
+    }
+
+    /// Adds an entry to [`Config::get_routes`], entries are matched in the order added.
+    pub fn with_get_route<F>(mut self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RequestContext) -> tiny_http::ResponseBox + Send + Sync + 'static,
+    {
+        self.config
+            .get_routes
+            .push((GlobPattern::new(pattern), Arc::new(handler)));
+        self
+    }
+
+    pub fn with_rpc_path(mut self, path: Option<&str>) -> Self {
+        self.config.rpc_path = path.map(str::to_string);
+        self
+    }
+
+    pub fn with_index_message(mut self, message: Option<String>) -> Self {
+        self.config.index_message = message;
+        self
+    }
+
+    pub fn with_events_path(mut self, path: Option<&str>) -> Self {
+        self.config.events_path = path.map(str::to_string);
+        self
+    }
+
+    pub fn with_max_event_streams(mut self, max: usize) -> Self {
+        self.config.max_event_streams = max;
+        self
+    }
+
+    pub fn with_health_path(mut self, path: Option<&str>) -> Self {
+        self.config.health_path = path.map(str::to_string);
+        self
+    }
+
+    pub fn with_ready_path(mut self, path: Option<&str>) -> Self {
+        self.config.ready_path = path.map(str::to_string);
+        self
+    }
+
+    /// Adds an entry to [`Config::readiness_checks`].
+    pub fn with_readiness_check<F>(mut self, name: &str, check: F) -> Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.config
+            .readiness_checks
+            .push((name.to_string(), Arc::new(check)));
+        self
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket_addr(mut self, addr: Option<std::net::SocketAddr>) -> Self {
+        self.config.websocket_addr = addr;
+        self
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn with_max_websocket_connections(mut self, max: usize) -> Self {
+        self.config.max_websocket_connections = max;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, cert_path: PathBuf, key_path: PathBuf) -> Self {
+        self.config.tls_cert_path = Some(cert_path);
+        self.config.tls_key_path = Some(key_path);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls_client_ca(mut self, ca_path: Option<PathBuf>) -> Self {
+        self.config.tls_client_ca_path = ca_path;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls_reload_on_sighup(mut self, reload: bool) -> Self {
+        self.config.tls_reload_on_sighup = reload;
+        self
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn with_compress_min_bytes(mut self, min: Option<usize>) -> Self {
+        self.config.compress_min_bytes = min;
+        self
+    }
+
+    pub fn with_method_concurrency(mut self, method: &str, max: usize) -> Self {
+        self.config
+            .method_concurrency
+            .insert(method.to_string(), max);
+        self
+    }
+
+    pub fn with_max_concurrent_per_ip(mut self, max: Option<usize>) -> Self {
+        self.config.max_concurrent_per_ip = max;
+        self
+    }
+
+    pub fn with_long_poll(mut self, method: &str, timeout: Duration) -> Self {
+        self.config.long_poll.insert(method.to_string(), timeout);
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    pub fn with_reentrancy_guard(mut self, guard: bool) -> Self {
+        self.config.reentrancy_guard = guard;
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, max: Option<u64>) -> Self {
+        self.config.max_body_bytes = max;
+        self
+    }
+
+    pub fn with_require_json_content_type(mut self, require: bool) -> Self {
+        self.config.require_json_content_type = require;
+        self
+    }
+
+    pub fn with_accept_json_rpc_content_type(mut self, accept: bool) -> Self {
+        self.config.accept_json_rpc_content_type = accept;
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max: Option<usize>) -> Self {
+        self.config.max_batch_size = max;
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max: Option<usize>) -> Self {
+        self.config.max_response_bytes = max;
+        self
+    }
+
+    pub fn with_response_timing(mut self, enabled: bool) -> Self {
+        self.config.response_timing = enabled;
+        self
+    }
+
+    pub fn with_strict_params(mut self, strict: bool) -> Self {
+        self.config.strict_params = strict;
+        self
+    }
+
+    pub fn with_deny_unknown_fields(mut self, deny: bool) -> Self {
+        self.config.deny_unknown_fields = deny;
+        self
+    }
+
+    pub fn with_require_id(mut self, require: bool) -> Self {
+        self.config.require_id = require;
+        self
+    }
+
+    pub fn with_jsonrpc_1_compat(mut self, enabled: bool) -> Self {
+        self.config.jsonrpc_1_compat = enabled;
+        self
+    }
+
+    pub fn with_builtin_methods(mut self, enabled: bool) -> Self {
+        self.config.builtin_methods = enabled;
+        self
+    }
+
+    pub fn with_idempotency(mut self, header: &str, ttl: Duration) -> Self {
+        self.config.idempotency_header = Some(header.to_string());
+        self.config.idempotency_ttl = ttl;
+        self
+    }
+
+    pub fn with_log_bodies(mut self, log: bool) -> Self {
+        self.config.log_bodies = log;
+        self
+    }
+
+    pub fn with_html_errors(mut self, html: bool) -> Self {
+        self.config.html_errors = html;
+        self
+    }
+
+    /// Adds an entry to [`Config::error_pages`].
+    pub fn with_error_page(mut self, status: u16, page: ErrorPage) -> Self {
+        self.config.error_pages.insert(status, page);
+        self
+    }
+
+    pub fn with_max_requests_per_connection(mut self, max: Option<usize>) -> Self {
+        self.config.max_requests_per_connection = max;
+        self
+    }
+
+    /// Adds a call to [`Config::self_test`].
+    pub fn with_self_test(mut self, method: &str, params: Option<Value>) -> Self {
+        self.config.self_test.push((method.to_string(), params));
+        self
+    }
+
+    pub fn with_download_paths(mut self, paths: Vec<String>) -> Self {
+        self.config.download_paths = paths;
+        self
+    }
+
+    /// Adds an entry to [`Config::cache_control`], entries are matched in the order added.
+    ///
+    /// A `pattern` starting with a dot matches a file extension in any directory, such as `.js`
+    /// for `**/*.js`.
+    pub fn with_cache_control(mut self, pattern: &str, value: &str) -> Self {
+        let pattern = if pattern.starts_with('.') {
+            GlobPattern::new(&format!("**/*{}", pattern))
+        } else {
+            GlobPattern::new(pattern)
+        };
+        self.config.cache_control.push((pattern, value.to_string()));
+        self
+    }
+
+    /// Adds an entry to [`Config::mime_types`], the extension is matched case-insensitively.
+    pub fn with_mime_type(mut self, extension: &str, content_type: &str) -> Self {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.config
+            .mime_types
+            .insert(extension, content_type.to_string());
+        self
+    }
+
+    pub fn with_max_recent_errors(mut self, max: usize) -> Self {
+        self.config.max_recent_errors = max;
+        self
+    }
+
+    pub fn with_accept_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(IpAddr) -> bool + Send + Sync + 'static,
+    {
+        self.config.accept_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Adds an entry to [`Config::trusted_proxies`].
+    pub fn with_trusted_proxy(mut self, proxy: IpAddr) -> Self {
+        self.config.trusted_proxies.push(proxy);
+        self
+    }
+
+    pub fn with_on_send_error<F>(mut self, on_send_error: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.config.on_send_error = Some(Arc::new(on_send_error));
+        self
+    }
+
+    pub fn with_on_tick<F>(mut self, on_tick: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.config.on_tick = Some(Arc::new(on_tick));
+        self
+    }
+
+    /// Adds a middleware on top of the previously added ones.
+    pub fn with_middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.config.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
     }
 }
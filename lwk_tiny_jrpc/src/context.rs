@@ -0,0 +1,77 @@
+//! The HTTP request a JSON RPC request was received with.
+
+use std::{net::SocketAddr, time::SystemTime};
+
+use tiny_http::Header;
+
+use crate::CancellationToken;
+
+/// The transport details of a JSON RPC request, passed to handlers created with
+/// [`crate::JsonRpcServer::new_with_context`] for per client policies and logging.
+///
+/// The requests of a batch share the context of the HTTP request carrying them, except for the
+/// cancellation token of each request. Contexts are only created by the server, fields may be
+/// added, such as with the `tls` feature.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RequestContext {
+    /// The address of the client, `None` for requests received on a Unix socket or through the
+    /// tower service. For requests from a [`crate::Config::trusted_proxies`] it's the address
+    /// given by the forwarded headers.
+    pub remote_addr: Option<SocketAddr>,
+    /// The URL of the HTTP request, the path and query.
+    pub url: String,
+    /// The headers of the HTTP request.
+    pub headers: Vec<Header>,
+    /// When the worker started handling the HTTP request.
+    pub received_at: SystemTime,
+    /// Set when the client cancels the request with `rpc.cancel`, see [`CancellationToken`].
+    pub cancellation: CancellationToken,
+    /// The certificate the client authenticated with, for requests received by the HTTPS
+    /// listener if [`crate::Config::tls_client_ca_path`] is set.
+    #[cfg(feature = "tls")]
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// A client certificate verified by the HTTPS listener, identifying the client for per client
+/// authorization.
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The DER encoded certificate.
+    pub der: Vec<u8>,
+    /// The lowercase hex SHA-256 of `der`, as printed by
+    /// `openssl x509 -outform der | sha256sum`.
+    pub fingerprint: String,
+}
+
+#[cfg(feature = "tls")]
+impl ClientCertificate {
+    pub(crate) fn new(der: Vec<u8>) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &der);
+        let fingerprint = crate::util::hex(digest.as_ref());
+        Self { der, fingerprint }
+    }
+}
+
+impl RequestContext {
+    pub(crate) fn new(remote_addr: Option<SocketAddr>, url: &str, headers: Vec<Header>) -> Self {
+        Self {
+            remote_addr,
+            url: url.to_string(),
+            headers,
+            received_at: SystemTime::now(),
+            cancellation: CancellationToken::default(),
+            #[cfg(feature = "tls")]
+            client_certificate: None,
+        }
+    }
+
+    /// Returns the value of the first header named `field`, case insensitively.
+    pub fn header(&self, field: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(field))
+            .map(|h| h.value.as_str())
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Display, io};
+use std::{fmt::Display, io, ops::RangeInclusive, time::Duration};
 
 use crate::RpcError;
 
@@ -33,6 +33,15 @@ pub enum InnerError {
     #[error("Serde JSON Error: {0}")]
     Serde(#[from] serde_json::Error),
 
+    #[error("The result could not be serialized: {0}")]
+    ResultSerialization(serde_json::Error),
+
+    #[error("Invalid Request: {0}")]
+    InvalidRequest(serde_json::Error),
+
+    #[error("Response too large, it exceeds the maximum of {0} bytes")]
+    ResponseTooLarge(usize),
+
     #[error("Request is missing Content-Type Header")]
     NoContentType,
 
@@ -44,6 +53,66 @@ pub enum InnerError {
 
     #[error("'jsonrpc' version should be '2.0'")]
     InvalidVersion,
+
+    #[error("Too many concurrent '{0}' requests")]
+    MethodBusy(String),
+
+    #[error("Invalid HTTP header")]
+    InvalidHeader,
+
+    #[error("Server is draining and not accepting new requests")]
+    Draining,
+
+    #[error("Invalid Content-Length header")]
+    InvalidContentLength,
+
+    #[error("Request body exceeds the maximum size of {0} bytes")]
+    BodyTooLarge(u64),
+
+    #[error("Empty batch")]
+    EmptyBatch,
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("A request with the same idempotency key is in progress or has different params")]
+    IdempotencyConflict,
+
+    #[error("Unknown request field '{0}'")]
+    UnknownField(String),
+
+    #[error("Request is missing the 'id'")]
+    MissingId,
+
+    #[error("Batch exceeds the maximum size of {0} requests")]
+    BatchTooLarge(usize),
+
+    #[error("'params' must be an array or an object")]
+    ScalarParams,
+
+    #[error("No new data before the long poll timeout of {0:?}")]
+    LongPollTimeout(Duration),
+
+    #[error("Method '{method}' exceeded the request timeout of {timeout:?}")]
+    RequestTimeout { method: String, timeout: Duration },
+
+    #[error("Request made by a method handler of this server")]
+    Reentrant,
+
+    #[error("JSON RPC requests must be sent with HTTP POST")]
+    HttpMethodNotAllowed,
+
+    #[error("Too many concurrent requests from the client address")]
+    TooManyRequests,
+
+    #[error("Invalid params: {0}")]
+    InvalidParams(String),
+
+    #[error("Unsupported Content-Encoding")]
+    UnsupportedEncoding,
+
+    #[error("Self test call to '{method}' failed: {message}")]
+    SelfTest { method: String, message: String },
 }
 
 impl From<String> for Error {
@@ -56,7 +125,31 @@ impl From<String> for Error {
     }
 }
 
+impl From<RpcError> for Error {
+    fn from(error: RpcError) -> Self {
+        // the server error codes outside of USER_CODES identify the errors of this library
+        let code = if SERVER_ERROR_CODES.contains(&error.code) {
+            ImplementationDefinedCode::new(error.code).unwrap_or(GENERIC)
+        } else {
+            ImplementationDefinedCode(error.code)
+        };
+        Error::Implementation(ImplementationDefinedError {
+            message: error.message,
+            code,
+            data: error.data,
+        })
+    }
+}
+
 impl Error {
+    /// The HTTP status code of the response carrying this error.
+    pub(crate) fn http_status(&self) -> u16 {
+        match self {
+            Error::Inner(e) => e.http_status(),
+            Error::Implementation(_) | Error::Stop => 200,
+        }
+    }
+
     pub fn new_implementation_defined(
         e: &impl Display,
         code: ImplementationDefinedCode,
@@ -70,6 +163,23 @@ impl Error {
     }
 }
 
+impl InnerError {
+    /// The HTTP status code of the response carrying this error.
+    pub(crate) fn http_status(&self) -> u16 {
+        match self {
+            InnerError::MethodBusy(_) | InnerError::Draining => 503,
+            InnerError::InvalidContentLength => 400,
+            InnerError::TooManyRequests => 429,
+            InnerError::HttpMethodNotAllowed => 405,
+            InnerError::BodyTooLarge(_) | InnerError::BatchTooLarge(_) => 413,
+            InnerError::UnsupportedEncoding => 415,
+            InnerError::Reentrant => 508,
+            InnerError::IdempotencyConflict => 409,
+            _ => 200,
+        }
+    }
+}
+
 pub(crate) trait AsRpcError {
     fn as_rpc_error(&self) -> RpcError;
 }
@@ -79,10 +189,38 @@ impl AsRpcError for InnerError {
         let (code, data) = match self {
             InnerError::Io(_) => (IO_ERROR, None),
             InnerError::Serde(_) => (PARSE_ERROR, None),
+            InnerError::InvalidRequest(_) => (INVALID_REQUEST, None),
+            InnerError::ResponseTooLarge(_) => (INTERNAL_ERROR, None),
+            InnerError::ResultSerialization(_) => (INTERNAL_ERROR, None),
             InnerError::NoContentType => (NO_CONTENT_TYPE, None),
             InnerError::WrongContentType => (WRONG_CONTENT_TYPE, None),
             InnerError::ReservedMethodPrefix => (METHOD_RESERVED, None),
             InnerError::InvalidVersion => (INVALID_VERSION, None),
+            InnerError::MethodBusy(_) => (SERVER_BUSY, None),
+            InnerError::InvalidHeader => (INTERNAL_ERROR, None),
+            InnerError::Draining => (SERVER_BUSY, None),
+            InnerError::InvalidContentLength => (INVALID_REQUEST, None),
+            InnerError::BodyTooLarge(_) => (INVALID_REQUEST, None),
+            InnerError::BatchTooLarge(_) => (INVALID_REQUEST, None),
+            InnerError::EmptyBatch => (INVALID_REQUEST, None),
+            InnerError::UnknownField(_) | InnerError::MissingId => (INVALID_REQUEST, None),
+            InnerError::ScalarParams => (INVALID_PARAMS, None),
+            InnerError::TooManyRequests => (SERVER_BUSY, None),
+            InnerError::HttpMethodNotAllowed => (INVALID_REQUEST, None),
+            InnerError::Reentrant => (REENTRANT, None),
+            InnerError::IdempotencyConflict => (SERVER_BUSY, None),
+            InnerError::Tls(_) => (INTERNAL_ERROR, None),
+            InnerError::LongPollTimeout(timeout) => (
+                LONG_POLL_TIMEOUT,
+                Some(serde_json::json!({ "timeout_ms": timeout.as_millis() as u64 })),
+            ),
+            InnerError::RequestTimeout { timeout, .. } => (
+                REQUEST_TIMEOUT,
+                Some(serde_json::json!({ "timeout_ms": timeout.as_millis() as u64 })),
+            ),
+            InnerError::InvalidParams(_) => (INVALID_PARAMS, None),
+            InnerError::UnsupportedEncoding => (INVALID_REQUEST, None),
+            InnerError::SelfTest { .. } => (INTERNAL_ERROR, None),
         };
 
         RpcError {
@@ -119,27 +257,50 @@ impl AsRpcError for Error {
 
 // from: https://www.jsonrpc.org/specification#error_object
 
-// -32700 	Parse error 	Invalid JSON was received by the server.  An error occurred on the server while parsing the JSON text.
-const PARSE_ERROR: i64 = -32_700;
+/// Invalid JSON was received by the server. An error occurred on the server while parsing the
+/// JSON text.
+pub const PARSE_ERROR: i64 = -32_700;
 
-// -32600 	Invalid Request 	The JSON sent is not a valid Request object.
-// const INVALID_REQUEST: i64 = -32_600; // TODO if failing to parse the request object, try to parse as Value and if succesfull return this instead of PARSE_ERROR
+/// The JSON sent is not a valid Request object.
+pub const INVALID_REQUEST: i64 = -32_600;
 
-// -32601 	Method not found 	The method does not exist / is not available.
-pub(crate) const METHOD_NOT_FOUND: i64 = -32_601;
+/// The method does not exist / is not available.
+pub const METHOD_NOT_FOUND: i64 = -32_601;
 
-// -32602 	Invalid params 	Invalid method parameter(s).
-// const INVALID_PARAMS: i64 = -32_602;
+/// Invalid method parameter(s).
+pub const INVALID_PARAMS: i64 = -32_602;
 
-// -32603 	Internal error 	Internal JSON-RPC error.
-// const INTERNAL_ERROR: i64 = -32_603;
+/// Internal JSON-RPC error.
+pub const INTERNAL_ERROR: i64 = -32_603;
+
+/// The codes reserved by the specification, applications should use codes outside of it.
+pub const RESERVED_CODES: RangeInclusive<i64> = -32_768..=-32_000;
+
+/// The codes reserved for implementation-defined server errors, within [`RESERVED_CODES`].
+pub const SERVER_ERROR_CODES: RangeInclusive<i64> = -32_099..=-32_000;
+
+/// The server error codes available to applications through [`ImplementationDefinedCode`], the
+/// rest of [`SERVER_ERROR_CODES`] is used by this library.
+pub const USER_CODES: RangeInclusive<i64> = -32_089..=-32_005;
+
+/// Returns true if `code` is reserved by the specification, such as the pre-defined errors and
+/// the server errors.
+pub fn is_reserved_code(code: i64) -> bool {
+    RESERVED_CODES.contains(&code)
+}
 
 // -32000 to -32099 	Server error 	Reserved for implementation-defined server-errors.
 const IO_ERROR: i64 = -32_000;
+// generic server error, shared with IO_ERROR
+const SERVER_BUSY: i64 = -32_000;
 const NO_CONTENT_TYPE: i64 = -32_001;
 const WRONG_CONTENT_TYPE: i64 = -32_002;
 const METHOD_RESERVED: i64 = -32_003;
 const INVALID_VERSION: i64 = -32_004;
+// library codes added later are taken from -32090 to -32098, outside of USER_CODES
+const LONG_POLL_TIMEOUT: i64 = -32_090;
+const REENTRANT: i64 = -32_091;
+const REQUEST_TIMEOUT: i64 = -32_092;
 
 // GENERIC = -32_098, // TODO remove
 const STOP_ERROR: i64 = -32_099;
@@ -147,8 +308,9 @@ const STOP_ERROR: i64 = -32_099;
 #[derive(Debug)]
 pub struct ImplementationDefinedCode(i64);
 impl ImplementationDefinedCode {
+    /// Returns `None` if `val` is outside of [`USER_CODES`].
     pub const fn new(val: i64) -> Option<Self> {
-        if val > -32004 || val < -32099 {
+        if val > *USER_CODES.end() || val < *USER_CODES.start() {
             None
         } else {
             Some(Self(val))
@@ -171,3 +333,46 @@ impl Display for ImplementationDefinedCode {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn library_codes() {
+        let codes = [
+            IO_ERROR,
+            NO_CONTENT_TYPE,
+            WRONG_CONTENT_TYPE,
+            METHOD_RESERVED,
+            INVALID_VERSION,
+            LONG_POLL_TIMEOUT,
+            REENTRANT,
+            REQUEST_TIMEOUT,
+            STOP_ERROR,
+        ];
+        for (i, code) in codes.iter().enumerate() {
+            assert!(SERVER_ERROR_CODES.contains(code), "{}", code);
+            assert!(!USER_CODES.contains(code), "{}", code);
+            assert!(!codes[..i].contains(code), "{}", code);
+        }
+        assert!(ImplementationDefinedCode::new(*USER_CODES.start()).is_some());
+        assert!(ImplementationDefinedCode::new(*USER_CODES.end()).is_some());
+        assert!(ImplementationDefinedCode::new(INVALID_VERSION).is_none());
+        assert!(ImplementationDefinedCode::new(LONG_POLL_TIMEOUT).is_none());
+    }
+
+    #[test]
+    fn rpc_error_codes() {
+        let code = |code| match Error::from(RpcError::new(code)) {
+            Error::Implementation(err) => i64::from(err.code),
+            _ => panic!("not an implementation error"),
+        };
+        assert_eq!(code(-1), -1);
+        assert_eq!(code(INVALID_PARAMS), INVALID_PARAMS);
+        assert_eq!(code(*USER_CODES.start()), *USER_CODES.start());
+        assert_eq!(code(REQUEST_TIMEOUT), i64::from(GENERIC));
+        assert_eq!(code(STOP_ERROR), i64::from(GENERIC));
+        assert_eq!(code(IO_ERROR), i64::from(GENERIC));
+    }
+}
@@ -0,0 +1,85 @@
+//! Glob patterns matching URL paths.
+
+use std::fmt;
+
+/// A pattern matching URL paths, such as `assets/**/*.js`.
+///
+/// Patterns are matched against the whole path without the leading slash:
+/// - `?` matches any character except `/`
+/// - `*` matches any sequence of characters except `/`
+/// - `**/` matches zero or more directories, `**` at the end matches anything
+/// - other characters match themselves
+#[derive(Clone, PartialEq, Eq)]
+pub struct GlobPattern {
+    pattern: Vec<char>,
+}
+
+impl GlobPattern {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.trim_start_matches('/').chars().collect(),
+        }
+    }
+
+    /// Returns true if `path`, with or without the leading slash, matches the pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let path: Vec<char> = path.trim_start_matches('/').chars().collect();
+        matches(&self.pattern, &path)
+    }
+}
+
+impl fmt::Debug for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pattern: String = self.pattern.iter().collect();
+        f.debug_tuple("GlobPattern").field(&pattern).finish()
+    }
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            matches(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == '/' && matches(rest, &path[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=segment).any(|i| matches(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => path.first().is_some_and(|c| *c != '/') && matches(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GlobPattern;
+
+    #[test]
+    fn glob() {
+        let cases = [
+            ("index.html", "/index.html", true),
+            ("index.html", "/sub/index.html", false),
+            ("/index.html", "index.html", true),
+            ("*.js", "/app.3f2a.js", true),
+            ("*.js", "/assets/app.js", false),
+            ("**/*.js", "/app.js", true),
+            ("**/*.js", "/assets/js/app.js", true),
+            ("**/*.js", "/assets/app.css", false),
+            ("assets/**", "/assets/a/b.png", true),
+            ("assets/**", "/other/b.png", false),
+            ("app.????.js", "/app.3f2a.js", true),
+            ("app.????.js", "/app.3f2.js", false),
+            ("a?c", "/a/c", false),
+            ("*", "/", true),
+        ];
+        for (pattern, path, expected) in cases {
+            let glob = GlobPattern::new(pattern);
+            assert_eq!(glob.matches(path), expected, "{} {}", pattern, path);
+        }
+    }
+}
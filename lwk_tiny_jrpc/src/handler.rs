@@ -0,0 +1,111 @@
+//! The application callbacks invoked by the worker threads.
+
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use tiny_http::Header;
+
+use crate::{Error, Request, RequestContext, Response};
+
+/// Authenticates requests and handles them, erasing the state and principal types from the
+/// request dispatch.
+pub(crate) trait Dispatch: Send + Sync + 'static {
+    type Principal: Clone + Send + 'static;
+
+    /// Authenticates a request from its headers, before reading its body.
+    fn authenticate(&self, headers: &[Header]) -> Result<Self::Principal, Error>;
+
+    /// Calls the method handler.
+    fn call(
+        &self,
+        request: Request,
+        principal: Self::Principal,
+        context: &RequestContext,
+    ) -> Result<Response, Error>;
+
+    /// Returns true if the method handler answers the OpenRPC `rpc.discover` method, which is
+    /// otherwise rejected with the other reserved methods.
+    #[cfg(feature = "openrpc")]
+    fn answers_discover(&self) -> bool {
+        false
+    }
+}
+
+/// A [`Dispatch`] answering `rpc.discover`, such as the handler of a [`crate::Router`].
+#[cfg(feature = "openrpc")]
+pub(crate) struct Discoverable<D: ?Sized>(pub(crate) Arc<D>);
+
+#[cfg(feature = "openrpc")]
+impl<D: Dispatch + ?Sized> Dispatch for Discoverable<D> {
+    type Principal = D::Principal;
+
+    fn authenticate(&self, headers: &[Header]) -> Result<Self::Principal, Error> {
+        self.0.authenticate(headers)
+    }
+
+    fn call(
+        &self,
+        request: Request,
+        principal: Self::Principal,
+        context: &RequestContext,
+    ) -> Result<Response, Error> {
+        self.0.call(request, principal, context)
+    }
+
+    fn answers_discover(&self) -> bool {
+        true
+    }
+}
+
+/// A [`Dispatch`] made of an authentication callback, a method handler and the state shared
+/// between handler calls.
+pub(crate) struct Handler<T, P, A, F> {
+    state: Arc<Mutex<T>>,
+    auth: A,
+    func: F,
+    principal: PhantomData<fn() -> P>,
+}
+
+impl<T, P, A, F> Handler<T, P, A, F> {
+    pub(crate) fn new(state: Arc<Mutex<T>>, auth: A, func: F) -> Self {
+        Self {
+            state,
+            auth,
+            func,
+            principal: PhantomData,
+        }
+    }
+}
+
+impl<T, P, A, F> Dispatch for Handler<T, P, A, F>
+where
+    A: Fn(&[Header]) -> Result<P, Error> + Send + Sync + 'static,
+    F: Fn(Request, P, &RequestContext, Arc<Mutex<T>>) -> Result<Response, Error>
+        + Send
+        + Sync
+        + 'static,
+    T: Send + 'static,
+    P: Clone + Send + 'static,
+{
+    type Principal = P;
+
+    fn authenticate(&self, headers: &[Header]) -> Result<P, Error> {
+        (self.auth)(headers)
+    }
+
+    fn call(
+        &self,
+        request: Request,
+        principal: P,
+        context: &RequestContext,
+    ) -> Result<Response, Error> {
+        (self.func)(request, principal, context, self.state.clone())
+    }
+}
+
+/// The authentication used when none is configured, accepting every request.
+pub(crate) fn no_auth(_headers: &[Header]) -> Result<(), Error> {
+    Ok(())
+}
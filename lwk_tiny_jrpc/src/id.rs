@@ -0,0 +1,125 @@
+//! Generation of JSON RPC request ids, for clients.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use crate::Id;
+
+/// How [`IdGenerator`] creates request ids.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// Numbers counting up from 1, predictable ids easing debugging.
+    #[default]
+    Sequential,
+    /// Random numbers, at most 2^53 - 1 to be exactly represented by JavaScript clients.
+    Random,
+    /// Random version 4 UUID strings, avoiding collisions when multiplexing requests of
+    /// different clients.
+    Uuid,
+}
+
+/// Creates request ids according to an [`IdStrategy`], it can be shared between threads.
+#[derive(Debug, Default)]
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    next: AtomicU64,
+}
+
+/// The largest integer exactly represented by a JavaScript number.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+impl IdGenerator {
+    pub fn new(strategy: IdStrategy) -> Self {
+        Self {
+            strategy,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub fn strategy(&self) -> IdStrategy {
+        self.strategy
+    }
+
+    /// Returns the id of the next request.
+    pub fn next_id(&self) -> Id {
+        match self.strategy {
+            IdStrategy::Sequential => {
+                Id::Number((self.next.fetch_add(1, Ordering::Relaxed) + 1).into())
+            }
+            IdStrategy::Random => Id::Number((random_u64() & MAX_SAFE_INTEGER).into()),
+            IdStrategy::Uuid => Id::String(uuid_v4()),
+        }
+    }
+}
+
+fn uuid_v4() -> String {
+    let high = random_u64();
+    let low = random_u64();
+    // version 4 and RFC 4122 variant bits
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+// ids only need to be unique, not unpredictable: hashes a counter with randomly keyed SipHash
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn strategies() {
+        let generator = IdGenerator::default();
+        assert_eq!(generator.strategy(), IdStrategy::Sequential);
+        let ids: Vec<_> = (0..3).map(|_| generator.next_id()).collect();
+        assert_eq!(ids, [1, 2, 3].map(|n| Id::Number(n.into())));
+
+        let generator = IdGenerator::new(IdStrategy::Random);
+        let mut numbers = HashSet::new();
+        for _ in 0..100 {
+            match generator.next_id() {
+                Id::Number(n) => {
+                    let n = n.as_u64().unwrap();
+                    assert!(n <= MAX_SAFE_INTEGER && numbers.insert(n))
+                }
+                id => panic!("unexpected {:?}", id),
+            }
+        }
+
+        let generator = IdGenerator::new(IdStrategy::Uuid);
+        let mut uuids = HashSet::new();
+        for _ in 0..100 {
+            match generator.next_id() {
+                Id::String(uuid) => {
+                    assert_eq!(uuid.len(), 36);
+                    assert_eq!(&uuid[14..15], "4");
+                    assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+                    assert!(uuids.insert(uuid));
+                }
+                id => panic!("unexpected {:?}", id),
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Replays the outcome of an operation submitted again with the same idempotency key, such as a
+/// retried request whose response was lost, instead of executing it twice.
+pub(crate) struct IdempotencyCache<K, V> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<K, Entry<V>>>,
+}
+
+/// Completed operations are evicted, oldest first, when the cache holds this many entries.
+const MAX_ENTRIES: usize = 10_000;
+
+enum Entry<V> {
+    /// The operation with the given fingerprint is executing.
+    Pending(u64),
+    /// The operation with the given fingerprint completed at the given instant.
+    Done(u64, Instant, V),
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> IdempotencyCache<K, V> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: MAX_ENTRIES,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Executes `f` unless the `key` was submitted in the last `ttl`, in which case the cached
+    /// outcome is returned. Outcomes for which `cache` returns false aren't cached, so that the
+    /// operation can be retried. Returns `None` if the operation with the same key is still
+    /// executing, if it was submitted with a different `fingerprint`, identifying the operation,
+    /// or if the cache is full of executing operations.
+    pub(crate) fn run(
+        &self,
+        key: K,
+        fingerprint: u64,
+        f: impl FnOnce() -> V,
+        cache: impl FnOnce(&V) -> bool,
+    ) -> Option<V> {
+        {
+            let mut entries = self.lock();
+            let ttl = self.ttl;
+            entries.retain(|_, entry| match entry {
+                Entry::Pending(_) => true,
+                Entry::Done(_, at, _) => at.elapsed() < ttl,
+            });
+            match entries.get(&key) {
+                Some(Entry::Pending(_)) => return None,
+                Some(Entry::Done(done, _, value)) => {
+                    return (*done == fingerprint).then(|| value.clone())
+                }
+                None => {}
+            }
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .filter_map(|(key, entry)| match entry {
+                        Entry::Pending(_) => None,
+                        Entry::Done(_, at, _) => Some((*at, key)),
+                    })
+                    .min_by_key(|(at, _)| *at)
+                    .map(|(_, key)| key.clone());
+                entries.remove(&oldest?);
+            }
+            entries.insert(key.clone(), Entry::Pending(fingerprint));
+        }
+
+        // removes the pending entry if `f` panics
+        struct Pending<'a, K: Eq + Hash, V> {
+            cache: &'a IdempotencyCache<K, V>,
+            key: &'a K,
+        }
+        impl<K: Eq + Hash, V> Drop for Pending<'_, K, V> {
+            fn drop(&mut self) {
+                let mut entries = self.cache.entries.lock().unwrap_or_else(|p| p.into_inner());
+                if let Some(Entry::Pending(_)) = entries.get(self.key) {
+                    entries.remove(self.key);
+                }
+            }
+        }
+        let pending = Pending {
+            cache: self,
+            key: &key,
+        };
+
+        let value = f();
+        if cache(&value) {
+            let entry = Entry::Done(fingerprint, Instant::now(), value.clone());
+            self.lock().insert(key.clone(), entry);
+        }
+        drop(pending);
+        Some(value)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, Entry<V>>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread, time::Duration};
+
+    use super::IdempotencyCache;
+
+    #[test]
+    fn idempotency() {
+        let cache = IdempotencyCache::new(Duration::from_millis(100));
+        let mut calls = 0;
+        let mut run = |key: &str, value: i32| {
+            cache.run(
+                key.to_string(),
+                0,
+                || {
+                    calls += 1;
+                    value
+                },
+                |value| *value > 0,
+            )
+        };
+        assert_eq!(run("a", 1), Some(1));
+        assert_eq!(run("a", 2), Some(1));
+        assert_eq!(run("b", 3), Some(3));
+        // failures aren't cached
+        assert_eq!(run("c", -1), Some(-1));
+        assert_eq!(run("c", 4), Some(4));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(run("a", 5), Some(5));
+        assert_eq!(calls, 5);
+
+        // concurrent duplicates are rejected
+        let barrier = Barrier::new(2);
+        thread::scope(|scope| {
+            let first = scope.spawn(|| {
+                cache.run(
+                    "d".to_string(),
+                    0,
+                    || {
+                        barrier.wait();
+                        barrier.wait();
+                        6
+                    },
+                    |_| true,
+                )
+            });
+            barrier.wait();
+            assert_eq!(cache.run("d".to_string(), 0, || 7, |_| true), None);
+            barrier.wait();
+            assert_eq!(first.join().unwrap(), Some(6));
+        });
+    }
+
+    #[test]
+    fn fingerprint() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        assert_eq!(cache.run("a", 1, || 1, |_| true), Some(1));
+        assert_eq!(cache.run("a", 1, || 2, |_| true), Some(1));
+        // the key can't be reused for another operation
+        assert_eq!(cache.run("a", 2, || 3, |_| true), None);
+    }
+
+    #[test]
+    fn max_entries() {
+        let mut cache = IdempotencyCache::new(Duration::from_secs(60));
+        cache.max_entries = 3;
+        for key in 0..=3 {
+            assert_eq!(cache.run(key, 0, || key, |_| true), Some(key));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(cache.lock().len(), 3);
+        // the oldest entry was evicted
+        assert_eq!(cache.run(0, 0, || 42, |_| true), Some(42));
+        assert_eq!(cache.run(3, 0, || 42, |_| true), Some(3));
+    }
+}
@@ -2,37 +2,423 @@
 #![cfg_attr(not(test), deny(clippy::unwrap_used))]
 
 use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
     fmt::Display,
     fs::File,
-    io::{ErrorKind, Read},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream},
+    path::{Component, Path},
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, MutexGuard, RwLock,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+pub use broadcast::EventSender;
+use broadcast::Subscribers;
+pub use cancel::CancellationToken;
+use cancel::Cancellations;
 pub use config::Config;
+#[cfg(feature = "tls")]
+pub use context::ClientCertificate;
+pub use context::RequestContext;
 pub use error::Error;
-use error::{AsRpcError, InnerError, METHOD_NOT_FOUND};
+use error::{AsRpcError, InnerError, INTERNAL_ERROR, METHOD_NOT_FOUND};
+pub use glob::GlobPattern;
+use handler::{no_auth, Dispatch, Handler};
+use idempotency::IdempotencyCache;
+use limit::{ConnectionLimiter, IpLimiter, Semaphore, SemaphorePermit};
+#[cfg(feature = "macros")]
+pub use lwk_tiny_jrpc_macros::rpc_method;
+pub use router::Router;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
+pub use subscription::Subscription;
 use tiny_http::Server;
 use tiny_http::{Header, Response as HttpResponse};
 
+mod broadcast;
+mod cancel;
 pub mod config;
+mod context;
 pub mod error;
+pub mod glob;
+mod handler;
+pub mod id;
+mod idempotency;
+mod limit;
+pub mod loopback;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "openrpc")]
+pub mod openrpc;
+mod reentrancy;
+pub mod router;
+pub mod stdio;
+mod subscription;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tower")]
+pub mod tower;
+#[cfg(feature = "tracing-propagation")]
+pub mod trace;
+mod util;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 // re-export
 pub use tiny_http;
 
+/// A JSON RPC server handling requests on a pool of worker threads.
+///
+/// Requests pipelined on a keep-alive connection may be handled concurrently by different
+/// workers, their responses are still written in the order the requests arrived: tiny_http hands
+/// out the response writers of a connection sequentially, each one blocking until the previous
+/// response is complete. Responses must therefore always be written through the
+/// [`tiny_http::Request`], never on a cloned socket.
 pub struct JsonRpcServer {
-    server: Arc<Server>,
+    servers: Vec<Arc<Server>>,
     handles: Vec<JoinHandle<Result<(), Error>>>,
-    running: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+    #[cfg(feature = "websocket")]
+    websocket_addr: Option<std::net::SocketAddr>,
+    #[cfg(feature = "tls")]
+    tls: Option<(std::net::SocketAddr, Arc<tls::TlsAcceptor>)>,
+    /// The socket files created by [`JsonRpcServer::bind`], removed on stop.
+    #[cfg(unix)]
+    unix_paths: Mutex<Vec<std::path::PathBuf>>,
+}
+
+/// The lifecycle state of a [`JsonRpcServer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerState {
+    /// Requests are being served.
+    Running,
+    /// New JSON RPC requests are rejected with HTTP status 503, requests already being handled
+    /// are completed. The server is stopped once none is left.
+    Draining,
+    /// The worker threads are terminating or terminated.
+    Stopped,
+}
+
+impl From<u8> for ServerState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ServerState::Running,
+            1 => ServerState::Draining,
+            _ => ServerState::Stopped,
+        }
+    }
+}
+
+/// An address to listen on, see [`JsonRpcServer::bind`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listener {
+    /// A TCP address, port 0 picks a free one.
+    Tcp(std::net::SocketAddr),
+    /// A Unix socket at `path` with file permissions `mode`, see [`JsonRpcServer::bind_unix`].
+    #[cfg(unix)]
+    Unix { path: std::path::PathBuf, mode: u32 },
+}
+
+/// The utilization of the worker threads, see [`JsonRpcServer::worker_stats`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The number of workers handling a request.
+    pub busy: usize,
+    /// The number of workers waiting for a request.
+    pub idle: usize,
+    /// The number of workers, [`Config::num_threads`] for each listener.
+    pub total: usize,
+}
+
+/// State shared between the [`JsonRpcServer`] and its worker threads.
+struct Shared {
     config: Config,
+    state: AtomicU8,
+    in_flight: AtomicUsize,
+    busy_workers: AtomicUsize,
+    method_limits: HashMap<String, Arc<Semaphore>>,
+    event_streams: Arc<Semaphore>,
+    headers: RwLock<Vec<Header>>,
+    connections: Option<ConnectionLimiter>,
+    ip_limits: Option<IpLimiter>,
+    recent_errors: Mutex<VecDeque<String>>,
+    status_counts: Mutex<HashMap<u16, u64>>,
+    subscribers: Arc<Subscribers>,
+    idempotency: Option<IdempotencyCache<IdempotencyKey, (u16, Reply)>>,
+    cancellations: Cancellations,
+    #[cfg(feature = "tls")]
+    tls_peers: tls::Peers,
+    reentrancy: Arc<reentrancy::Handlers>,
+    started: Instant,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+}
+
+impl Shared {
+    fn new(config: Config) -> Self {
+        let method_limits = config
+            .method_concurrency
+            .iter()
+            .map(|(method, max)| (method.clone(), Arc::new(Semaphore::new(*max))))
+            .collect();
+        let idempotency = config
+            .idempotency_header
+            .as_ref()
+            .map(|_| IdempotencyCache::new(config.idempotency_ttl));
+        Self {
+            headers: RwLock::new(config.headers.clone()),
+            connections: config
+                .max_requests_per_connection
+                .map(ConnectionLimiter::new),
+            ip_limits: config.max_concurrent_per_ip.map(IpLimiter::new),
+            event_streams: Arc::new(Semaphore::new(config.max_event_streams)),
+            config,
+            state: AtomicU8::new(ServerState::Running as u8),
+            in_flight: AtomicUsize::new(0),
+            busy_workers: AtomicUsize::new(0),
+            method_limits,
+            recent_errors: Mutex::new(VecDeque::new()),
+            status_counts: Mutex::new(HashMap::new()),
+            subscribers: Arc::default(),
+            idempotency,
+            cancellations: Cancellations::default(),
+            #[cfg(feature = "tls")]
+            tls_peers: tls::Peers::default(),
+            reentrancy: Arc::default(),
+            started: Instant::now(),
+            #[cfg(feature = "metrics")]
+            metrics: metrics::Metrics::default(),
+        }
+    }
+
+    // sends the response, closing the connection if it reached the configured request limit
+    fn respond<R: Read>(
+        &self,
+        http_request: tiny_http::Request,
+        mut response: HttpResponse<R>,
+    ) -> io::Result<()> {
+        for header in self.cors_headers(&http_request) {
+            response.add_header(header);
+        }
+        self.count_response(response.status_code().0, response.data_length());
+        let close = match (&self.connections, self.remote_addr(&http_request)) {
+            (Some(connections), Some(addr)) => connections.count(addr),
+            _ => false,
+        };
+        let result = if close {
+            respond_and_close(http_request, response)
+        } else {
+            http_request.respond(response)
+        };
+        self.check_sent(result)
+    }
+
+    // returns the headers reflecting the `Origin` of the request if the CORS policy allows it
+    fn cors_headers(&self, http_request: &tiny_http::Request) -> Vec<Header> {
+        let mut headers = Vec::new();
+        let origin = find_header(http_request.headers(), "Origin");
+        if let (Some(cors), Some(origin)) = (&self.config.cors, origin) {
+            if cors.allows(origin) {
+                headers.extend(Header::from_bytes("Access-Control-Allow-Origin", origin));
+                headers.extend(Header::from_bytes("Vary", "Origin"));
+            }
+        }
+        headers
+    }
+
+    // counts a response with the given HTTP status and body length, whether sending it succeeds
+    // or not
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn count_response(&self, status: u16, length: Option<usize>) {
+        *lock_state(&self.status_counts).entry(status).or_default() += 1;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_bytes_out(length.unwrap_or(0) as u64);
+    }
+
+    // calls the configured hook if sending a response failed
+    fn check_sent(&self, result: io::Result<()>) -> io::Result<()> {
+        if let (Err(e), Some(on_send_error)) = (&result, &self.config.on_send_error) {
+            on_send_error(e);
+        }
+        result
+    }
+
+    // logs the error and keeps it among the recent ones
+    fn record_error(&self, message: String) {
+        tracing::error!("{}", message);
+        let max = self.config.max_recent_errors;
+        if max == 0 {
+            return;
+        }
+        let mut recent_errors = lock_state(&self.recent_errors);
+        if recent_errors.len() >= max {
+            recent_errors.pop_front();
+        }
+        recent_errors.push_back(message);
+    }
+
+    // returns true if JSON RPC requests are accepted at the path of `url`
+    fn is_rpc_path(&self, url: &str) -> bool {
+        self.config
+            .rpc_path
+            .as_deref()
+            .map_or(true, |rpc_path| url_path(url) == rpc_path)
+    }
+
+    // returns the address of the client of the request, the one of the TLS client for requests
+    // forwarded by the TLS listener
+    fn remote_addr(&self, http_request: &tiny_http::Request) -> Option<std::net::SocketAddr> {
+        let addr = http_request.remote_addr().copied();
+        #[cfg(feature = "tls")]
+        if let Some(peer) = addr.and_then(|addr| self.tls_peers.get(&addr)) {
+            return Some(peer.addr);
+        }
+        addr
+    }
+
+    // returns the address of the client of the request, the one given by the forwarded headers
+    // for requests from a trusted proxy
+    fn client_addr(&self, http_request: &tiny_http::Request) -> Option<std::net::SocketAddr> {
+        let addr = self.remote_addr(http_request)?;
+        Some(self.forwarded_addr(addr, http_request.headers()))
+    }
+
+    // returns the address given by the forwarded headers if `addr` is a trusted proxy, `addr`
+    // otherwise
+    fn forwarded_addr(
+        &self,
+        addr: std::net::SocketAddr,
+        headers: &[Header],
+    ) -> std::net::SocketAddr {
+        let proxies = &self.config.trusted_proxies;
+        if !proxies.contains(&addr.ip()) {
+            return addr;
+        }
+        let forwarded = forwarded_ip(headers, proxies);
+        forwarded.map_or(addr, |ip| std::net::SocketAddr::new(ip, 0))
+    }
+
+    // returns true if `url` is the liveness or the readiness endpoint
+    fn is_probe_path(&self, url: &str) -> bool {
+        let path = Some(url_path(url));
+        self.config.health_path.as_deref() == path || self.config.ready_path.as_deref() == path
+    }
+
+    // returns the handler of the first GET route matching the path of `url`
+    fn get_route(&self, url: &str) -> Option<&config::GetHandler> {
+        let path = url_path(url);
+        self.config
+            .get_routes
+            .iter()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(_, handler)| handler)
+    }
+
+    // returns true if `url` is the server-sent events endpoint
+    fn is_events_path(&self, url: &str) -> bool {
+        self.config.events_path.as_deref() == Some(url_path(url))
+    }
+
+    // returns the current additional headers, the read lock is released before responding
+    fn headers(&self) -> Vec<Header> {
+        self.headers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn state(&self) -> ServerState {
+        self.state.load(Ordering::SeqCst).into()
+    }
+
+    fn set_state(&self, state: ServerState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    // moves a draining server to stopped once there are no requests in flight
+    fn finish_draining(&self) {
+        if self.in_flight.load(Ordering::SeqCst) == 0 {
+            let _ = self.state.compare_exchange(
+                ServerState::Draining as u8,
+                ServerState::Stopped as u8,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+    }
+}
+
+/// Counts a JSON RPC request as in flight until dropped.
+struct InFlight<'a>(&'a Shared);
+
+impl<'a> InFlight<'a> {
+    fn new(shared: &'a Shared) -> Self {
+        shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(shared)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.finish_draining();
+    }
+}
+
+/// Keeps the limits of a request held while its handler runs past the timeout of the request:
+/// the request stays in flight, in its method concurrency limit and in its client IP slot.
+struct Overrun {
+    shared: Arc<Shared>,
+    ip: Option<IpAddr>,
+    _permit: Option<SemaphorePermit>,
+}
+
+impl Overrun {
+    fn new(shared: &Arc<Shared>, ip: Option<IpAddr>, permit: Option<SemaphorePermit>) -> Self {
+        shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        if let (Some(limiter), Some(ip)) = (&shared.ip_limits, ip) {
+            limiter.hold(ip);
+        }
+        Self {
+            shared: shared.clone(),
+            ip,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for Overrun {
+    fn drop(&mut self) {
+        if let (Some(limiter), Some(ip)) = (&self.shared.ip_limits, self.ip) {
+            limiter.release(ip);
+        }
+        self.shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.shared.finish_draining();
+    }
+}
+
+/// Counts a worker as busy until dropped.
+struct BusyWorker<'a>(&'a Shared);
+
+impl<'a> BusyWorker<'a> {
+    fn new(shared: &'a Shared) -> Self {
+        shared.busy_workers.fetch_add(1, Ordering::SeqCst);
+        Self(shared)
+    }
+}
+
+impl Drop for BusyWorker<'_> {
+    fn drop(&mut self) {
+        self.0.busy_workers.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl JsonRpcServer {
@@ -42,194 +428,653 @@ impl JsonRpcServer {
         F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
         T: Send + 'static,
     {
-        Self::run(Arc::new(server), config, state, func)
+        Self::run(vec![Arc::new(server)], config, Self::handler(state, func))
+    }
+
+    /// Creates and runs a new JSON RPC Server accepting HTTPS connections at `addr`, with the
+    /// certificate and key of [`Config::tls_cert_path`] and [`Config::tls_key_path`].
+    ///
+    /// The TLS connections are forwarded to a tiny_http server listening on the loopback
+    /// interface, which must not be exposed. The requests are handled as usual, with the address
+    /// of the TLS client as remote address. Each connection holds a thread forwarding it.
+    #[cfg(feature = "tls")]
+    pub fn new_tls<F, T>(
+        addr: impl std::net::ToSocketAddrs,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        Self::with_tls(addr, config, |backend, config| {
+            Self::new(backend, config, state, func)
+        })
+    }
+
+    /// Creates and runs a new JSON RPC Server accepting HTTPS connections like
+    /// [`JsonRpcServer::new_tls`], passing `func` the [`RequestContext`] of each request like
+    /// [`JsonRpcServer::new_with_context`], including the client certificate if requested.
+    #[cfg(feature = "tls")]
+    pub fn new_tls_with_context<F, T>(
+        addr: impl std::net::ToSocketAddrs,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, &RequestContext, Arc<Mutex<T>>) -> Result<Response, Error>
+            + Send
+            + Sync
+            + 'static,
+        T: Send + 'static,
+    {
+        Self::with_tls(addr, config, |backend, config| {
+            Self::new_with_context(backend, config, state, func)
+        })
+    }
+
+    // runs the server created by `new` with a loopback tiny_http server and forwards the TLS
+    // connections accepted at `addr` to it
+    #[cfg(feature = "tls")]
+    fn with_tls(
+        addr: impl std::net::ToSocketAddrs,
+        config: Config,
+        new: impl FnOnce(Server, Config) -> Self,
+    ) -> Result<Self, Error> {
+        let acceptor = Arc::new(tls::TlsAcceptor::new(&config)?);
+        let listener = std::net::TcpListener::bind(addr).map_err(InnerError::Io)?;
+        let tls_addr = listener.local_addr().map_err(InnerError::Io)?;
+        let backend = Server::http("127.0.0.1:0").map_err(|e| InnerError::Tls(e.to_string()))?;
+        let mut server = new(backend, config);
+        let backend_addr = server
+            .server_addr()
+            .to_ip()
+            .expect("listening on an IP address");
+        let (shared, tls_acceptor) = (server.shared.clone(), acceptor.clone());
+        thread::spawn(move || tls::listen(listener, backend_addr, tls_acceptor, shared));
+        server.tls = Some((tls_addr, acceptor));
+        Ok(server)
+    }
+
+    /// Creates and runs a new JSON RPC Server listening on a Unix socket at `path`, with file
+    /// permissions `mode`, such as `0o600` to allow only the current user to connect.
+    ///
+    /// A stale socket file left by a server that didn't stop cleanly is replaced, an error is
+    /// returned if another server is accepting connections on it. The socket file is removed
+    /// when the server is stopped.
+    #[cfg(unix)]
+    pub fn bind_unix<F, T>(
+        path: impl AsRef<std::path::Path>,
+        mode: u32,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let path = path.as_ref().to_path_buf();
+        Self::bind(vec![Listener::Unix { path, mode }], config, state, func)
+    }
+
+    /// Creates and runs a new JSON RPC Server listening on all the `listeners`, such as a TCP
+    /// port and a Unix socket, like [`JsonRpcServer::new_with_servers`]. The listeners share the
+    /// handler and the state, [`Config::num_threads`] workers are started for each one.
+    ///
+    /// Unix sockets are created like in [`JsonRpcServer::bind_unix`]. If binding any listener
+    /// fails the sockets already created are removed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `listeners` is empty.
+    pub fn bind<F, T>(
+        listeners: Vec<Listener>,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let mut servers = Vec::with_capacity(listeners.len());
+        #[cfg(unix)]
+        let mut unix_paths = Vec::new();
+        for listener in listeners {
+            let server = match listener {
+                Listener::Tcp(addr) => {
+                    Server::http(addr).map_err(|e| InnerError::Io(io::Error::other(e)))
+                }
+                #[cfg(unix)]
+                Listener::Unix { path, mode } => bind_unix_socket(&path, mode).map(|server| {
+                    unix_paths.push(path);
+                    server
+                }),
+            };
+            match server {
+                Ok(server) => servers.push(server),
+                Err(e) => {
+                    #[cfg(unix)]
+                    for path in unix_paths {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        let rpc = Self::new_with_servers(servers, config, state, func);
+        #[cfg(unix)]
+        {
+            *lock_state(&rpc.unix_paths) = unix_paths;
+        }
+        Ok(rpc)
+    }
+
+    /// Creates and runs a new JSON RPC Server dispatching requests to the handlers registered in
+    /// `router`.
+    pub fn new_with_router<T>(
+        server: Server,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        router: Router<T>,
+    ) -> Self
+    where
+        T: Send + 'static,
+    {
+        let handler = Self::handler(state, move |request, state| router.handle(request, state));
+        #[cfg(feature = "openrpc")]
+        let handler = Arc::new(handler::Discoverable(handler));
+        Self::run(vec![Arc::new(server)], config, handler)
+    }
+
+    /// Creates and runs a new JSON RPC Server authenticating requests with `auth`.
+    ///
+    /// `auth` is called with the request headers before reading the body, the principal it
+    /// returns, such as the user owning a token, is passed to `func` along with the state, so
+    /// that handlers can authorize the requested method. Requests failing authentication are
+    /// answered with HTTP status 401 and the error returned by `auth`. The principal is cloned for
+    /// each request of a batch.
+    pub fn new_with_auth<A, P, F, T>(
+        server: Server,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        auth: A,
+        func: F,
+    ) -> Self
+    where
+        A: Fn(&[Header]) -> Result<P, Error> + Send + Sync + 'static,
+        F: Fn(Request, P, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+        T: Send + 'static,
+        P: Clone + Send + 'static,
+    {
+        let func =
+            move |request, principal, _: &RequestContext, state| func(request, principal, state);
+        let handler = Arc::new(Handler::new(state, auth, func));
+        Self::run(vec![Arc::new(server)], config, handler)
+    }
+
+    /// Creates and runs a new JSON RPC Server passing `func` the [`RequestContext`] of each
+    /// request, such as the client address and the HTTP headers.
+    pub fn new_with_context<F, T>(
+        server: Server,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Self
+    where
+        F: Fn(Request, &RequestContext, Arc<Mutex<T>>) -> Result<Response, Error>
+            + Send
+            + Sync
+            + 'static,
+        T: Send + 'static,
+    {
+        let func =
+            move |request, _: (), context: &RequestContext, state| func(request, context, state);
+        let handler = Arc::new(Handler::new(state, no_auth, func));
+        Self::run(vec![Arc::new(server)], config, handler)
+    }
+
+    /// Creates and runs a new JSON RPC Server serving the same requests on several listeners,
+    /// such as a TCP port and a Unix socket. [`Config::num_threads`] workers are started for each
+    /// listener.
+    ///
+    /// Methods returning a single address or server refer to the first one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `servers` is empty.
+    pub fn new_with_servers<F, T>(
+        servers: Vec<Server>,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Self
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        assert!(!servers.is_empty(), "at least one server is required");
+        let servers = servers.into_iter().map(Arc::new).collect();
+        Self::run(servers, config, Self::handler(state, func))
+    }
+
+    /// Like [`JsonRpcServer::new`], but once the server threads are started runs the
+    /// [`Config::self_test`] calls through the request dispatch, returning an error and stopping
+    /// the server if any of them fails. [`JsonRpcServer::new`] doesn't run the self test calls.
+    pub fn try_new<F, T>(
+        server: Server,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let self_test = config.self_test.clone();
+        let handler = Self::handler(state, func);
+        let mut rpc = Self::run(vec![Arc::new(server)], config, handler.clone());
+        let context = RequestContext::new(None, "/", vec![]);
+        for (method, params) in self_test {
+            let request = Request {
+                jsonrpc: "2.0".to_string(),
+                id: Some(Id::String("self-test".to_string())),
+                method: method.clone(),
+                params,
+            };
+            let failure = match handle_jsonrpc_request(request, (), &context, &rpc.shared, &handler)
+            {
+                Ok(response) => response.error.map(|e| e.message),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(message) = failure {
+                rpc.stop();
+                rpc.join_threads();
+                return Err(InnerError::SelfTest { method, message }.into());
+            }
+        }
+        Ok(rpc)
+    }
+
+    /// Like [`JsonRpcServer::new`], but blocks until a connection to the server address
+    /// succeeds, so that clients can connect as soon as it returns. Returns an error and stops
+    /// the server if no connection succeeds within `timeout`.
+    ///
+    /// Unix socket listeners accept connections once bound and are not probed.
+    pub fn new_ready<F, T>(
+        server: Server,
+        config: Config,
+        state: Arc<Mutex<T>>,
+        func: F,
+        timeout: Duration,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let mut rpc = Self::new(server, config, state, func);
+        let Some(addr) = rpc.server_addr().to_ip() else {
+            return Ok(rpc);
+        };
+        // connect to the loopback address when listening on all interfaces
+        let addr = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+            IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+            _ => addr,
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match TcpStream::connect_timeout(&addr, remaining.max(Duration::from_millis(1))) {
+                Ok(_) => return Ok(rpc),
+                Err(e) if Instant::now() >= deadline => {
+                    rpc.stop();
+                    rpc.join_threads();
+                    return Err(InnerError::Io(e).into());
+                }
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
     }
 
     /// Returns a reference to the [`tiny_http::ListenAddr`] of the server.
     pub fn server_addr(&self) -> tiny_http::ListenAddr {
-        self.server.server_addr()
+        self.server().server_addr()
+    }
+
+    /// Returns the [`tiny_http::ListenAddr`] of every listener, in the order the servers were
+    /// given.
+    pub fn addrs(&self) -> Vec<tiny_http::ListenAddr> {
+        self.servers.iter().map(|s| s.server_addr()).collect()
+    }
+
+    /// Returns a reference to the underlying [`tiny_http::Server`].
+    ///
+    /// This is meant for advanced integrations needing lower-level control, such as calling
+    /// [`Server::unblock`]. Requests received directly from it bypass the JSON RPC handling.
+    pub fn server(&self) -> &Server {
+        &self.servers[0]
     }
 
     /// Returns the IP port unless the underlying tiny_http server is listening on a Unix socket.
     pub fn port(&self) -> Option<u16> {
-        self.server.server_addr().to_ip().map(|addr| addr.port())
+        self.server_addr().to_ip().map(|addr| addr.port())
+    }
+
+    /// Returns the address the WebSocket listener is bound to, `None` if
+    /// [`Config::websocket_addr`] is not set or binding it failed.
+    #[cfg(feature = "websocket")]
+    pub fn websocket_addr(&self) -> Option<std::net::SocketAddr> {
+        self.websocket_addr
+    }
+
+    /// Returns the address of the HTTPS listener of a server created with
+    /// [`JsonRpcServer::new_tls`].
+    #[cfg(feature = "tls")]
+    pub fn tls_addr(&self) -> Option<std::net::SocketAddr> {
+        self.tls.as_ref().map(|(addr, _)| *addr)
+    }
+
+    /// Reads the TLS certificate and key files again, the connections established afterwards
+    /// use them. On failure, such as a missing file, the current ones are kept.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self) -> Result<(), Error> {
+        match &self.tls {
+            Some((_, acceptor)) => Ok(acceptor.reload()?),
+            None => Err(InnerError::Tls("not a TLS server".into()).into()),
+        }
+    }
+
+    /// Replaces the additional headers added to responses, worker threads use them starting from
+    /// the next request.
+    ///
+    /// The headers in [`JsonRpcServer::config`] are not updated and remain the initial ones.
+    pub fn set_headers(&self, headers: Vec<Header>) {
+        *self
+            .shared
+            .headers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = headers;
     }
 
     /// Returns a reference to the [`Config`] used when creating the JSON RPC Server.
     pub fn config(&self) -> &Config {
-        &self.config
+        &self.shared.config
     }
 
-    fn run<F, T>(server: Arc<Server>, config: Config, state: Arc<Mutex<T>>, func: F) -> Self
+    // wraps a method handler not needing authentication
+    fn handler<F, T>(state: Arc<Mutex<T>>, func: F) -> Arc<impl Dispatch<Principal = ()>>
     where
-        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
         T: Send + 'static,
     {
+        let func = move |request: Request, _: (), _: &RequestContext, state: Arc<Mutex<T>>| {
+            func(request, state)
+        };
+        Arc::new(Handler::new(state, no_auth, func))
+    }
+
+    fn run<D: Dispatch>(servers: Vec<Arc<Server>>, config: Config, handler: Arc<D>) -> Self {
         let mut handles = Vec::with_capacity(4);
-        let running = Arc::new(AtomicBool::new(true));
+        let shared = Arc::new(Shared::new(config));
 
-        for _ in 0..config.num_threads.get() {
+        let workers = (0..shared.config.num_threads.get()).flat_map(|_| servers.iter());
+        for (index, server) in workers.enumerate() {
             let server = server.clone();
-            let func = func.clone();
-            let state = state.clone();
-            let running = running.clone();
-            let config = config.clone();
-            let handle = thread::spawn(move || {
+            let handler = handler.clone();
+            let shared = shared.clone();
+            let mut builder = thread::Builder::new();
+            if let Some(size) = shared.config.thread_stack_size {
+                builder = builder.stack_size(size);
+            }
+            let handle = builder.spawn(move || {
+                let config = &shared.config;
+                // only the first worker ticks
+                let on_tick = config.on_tick.as_ref().filter(|_| index == 0);
+                let mut last_tick = Instant::now();
                 loop {
+                    if let Some(on_tick) = on_tick {
+                        if last_tick.elapsed() >= POLL_INTERVAL {
+                            on_tick();
+                            last_tick = Instant::now();
+                        }
+                    }
+
                     // receive http request
-                    let mut http_request = match server.recv_timeout(Duration::from_millis(100)) {
+                    let http_request = match server.recv_timeout(POLL_INTERVAL) {
                         Ok(Some(request)) => request,
                         Ok(None) => {
                             // timeout, checks we aren't stopped
-                            if running.load(Ordering::SeqCst) {
-                                continue;
-                            } else {
+                            shared.finish_draining();
+                            if shared.state() == ServerState::Stopped {
                                 break;
+                            } else {
+                                continue;
                             }
                         }
                         Err(err) => {
                             // not much to do if recv fails
-                            tracing::error!("recv error: {}", err);
+                            shared.record_error(format!("recv error: {}", err));
                             continue;
                         }
                     };
+                    let _busy = BusyWorker::new(&shared);
+
+                    if let (Some(filter), Some(addr)) =
+                        (&config.accept_filter, shared.client_addr(&http_request))
+                    {
+                        if !filter(addr.ip()) {
+                            reject_filtered(http_request, &shared);
+                            continue;
+                        }
+                    }
+
+                    if http_request
+                        .body_length()
+                        .is_some_and(|len| len as u64 > MAX_UNREAD_BODY)
+                    {
+                        reject_oversized_body(http_request, &shared);
+                        continue;
+                    }
 
                     // check request method
                     match http_request.method() {
-                        tiny_http::Method::Get => {
-                            // respond to the http GET request
-                            let Some(mut path) = config.serve_dir.clone() else {
-                                let message = "No serve_dir defined in server config.";
-                                let response =
-                                    HttpResponse::from_string(message).with_status_code(500);
-                                send_http_response(http_request, response, message);
-                                continue;
-                            };
-                            // remove starting slash
-                            let file_name = http_request
-                                .url()
-                                .strip_prefix('/')
-                                .expect("url starts with slash");
-                            path.push(file_name);
-                            // add index.html to directories
-                            if path.is_dir() {
-                                path.push("index.html");
-                            }
-                            match File::open(path) {
-                                Ok(mut file) => {
-                                    let mut buf = Vec::new();
-                                    match file.read_to_end(&mut buf) {
-                                        Ok(n) => tracing::trace!("GET: read {} bytes", n),
-                                        Err(e) => {
-                                            let message = "500: Internal error";
-                                            let response = HttpResponse::from_string(message)
-                                                .with_status_code(500);
-                                            send_http_response(
-                                                http_request,
-                                                response,
-                                                format!("{}: {}", message, e).as_str(),
-                                            );
-                                            continue;
-                                        }
-                                    }
-                                    // todo: content-type headers, this is non-trivial and not strictly necessary right now
-                                    let response = HttpResponse::from_data(buf);
-                                    let message = "File for GET request";
-                                    send_http_response(http_request, response, message);
-                                }
-                                Err(e) if matches!(e.kind(), ErrorKind::NotFound) => {
-                                    // 404
-                                    let message = "404: File not found";
-                                    let response =
-                                        HttpResponse::from_string(message).with_status_code(404);
-                                    send_http_response(http_request, response, message);
-                                }
-                                Err(e) => {
-                                    // 500
-                                    let message = "500: Internal error";
-                                    let response =
-                                        HttpResponse::from_string(message).with_status_code(500);
-                                    send_http_response(
-                                        http_request,
-                                        response,
-                                        format!("{}: {}", message, e).as_str(),
-                                    );
-                                }
-                            }
+                        tiny_http::Method::Get if shared.is_events_path(http_request.url()) => {
+                            handle_events(http_request, &shared);
+                        }
+                        tiny_http::Method::Get | tiny_http::Method::Head
+                            if shared.is_probe_path(http_request.url()) =>
+                        {
+                            handle_probe(http_request, &shared);
+                        }
+                        tiny_http::Method::Get | tiny_http::Method::Head
+                            if shared.get_route(http_request.url()).is_some() =>
+                        {
+                            handle_get_route(http_request, &shared);
+                        }
+                        tiny_http::Method::Get | tiny_http::Method::Head if config.disable_get => {
+                            let message = "404: Not Found";
+                            let response = error_response(&http_request, config, 404, message);
+                            send_http_response(&shared, http_request, response, message);
+                        }
+                        tiny_http::Method::Get | tiny_http::Method::Head => {
+                            // tiny_http omits the body in responses to HEAD requests
+                            handle_get(http_request, &shared);
                         }
                         tiny_http::Method::Options => {
-                            // respond to the http OPTIONS request, normally for CORS
-                            let allow = Header::from_str("Allow: GET, POST, OPTIONS")
-                                .expect("valid header");
-                            let mut response = HttpResponse::empty(204).with_header(allow);
-                            for header in config.headers.clone().into_iter() {
-                                response.add_header(header);
-                            }
-                            let message = "OPTIONS request";
-                            send_http_response(http_request, response, message);
+                            handle_options(http_request, &shared);
+                        }
+                        tiny_http::Method::Post if !shared.is_rpc_path(http_request.url()) => {
+                            // rejected before reading the body, tiny_http discards it only
+                            // after the response is sent
+                            let message = "404: Not found";
+                            let response = error_response(&http_request, config, 404, message);
+                            send_http_response(&shared, http_request, response, message);
                         }
                         tiny_http::Method::Post => {
-                            // validate/parse the jsonrpc POST request
-                            let response = match validate_jsonrpc_request(&mut http_request) {
-                                Ok(request) => {
-                                    // handle the request
-                                    let id = request.id.clone();
-                                    match handle_jsonrpc_request(
-                                        request,
-                                        state.clone(),
-                                        func.clone(),
-                                    ) {
-                                        Ok(response) => response,
-                                        Err(Error::Stop) => {
-                                            running.store(false, Ordering::SeqCst);
-                                            Response::from_error(id, Error::Stop)
-                                        }
-                                        Err(err) => Response::from_error(id, err),
-                                    }
-                                }
-                                Err(err) => {
-                                    // no id since we couldn't validate the request...
-                                    Response::from_error(None, err)
-                                }
-                            };
-
-                            // send the response
-                            if let Err(err) =
-                                send_jsonrpc_response(http_request, response, &config.headers)
-                            {
-                                tracing::error!("send_response error: {}", err);
-                            }
+                            handle_post(http_request, &shared, &handler);
                         }
                         other => {
                             let message =
                                 format!("500: Internal error - method {} not implemented.", other);
-                            let response =
-                                HttpResponse::from_string(&message).with_status_code(500);
-                            send_http_response(http_request, response, &message);
+                            let response = error_response(&http_request, config, 500, &message);
+                            send_http_response(&shared, http_request, response, &message);
+                        }
+                    }
+
+                    // the server was stopped while handling, such as by a handler returning
+                    // `Error::Stop`: the response is sent, wake up the idle workers to terminate
+                    if shared.state() == ServerState::Stopped {
+                        for _ in 0..config.num_threads.get() {
+                            server.unblock();
                         }
+                        break;
                     }
                 }
                 Ok(())
             });
+            // like thread::spawn, panics if the OS fails to create the thread
+            let handle = handle.expect("failed to spawn thread");
             handles.push(handle);
         }
+        // without workers requests would never be answered while `is_running` reports true
+        assert!(
+            !handles.is_empty(),
+            "no worker thread started, check Config::num_threads and the servers"
+        );
+
+        #[cfg(feature = "websocket")]
+        let websocket_addr = shared.config.websocket_addr.and_then(|addr| {
+            let listener = std::net::TcpListener::bind(addr)
+                .and_then(|listener| Ok((listener.local_addr()?, listener)));
+            match listener {
+                Ok((local_addr, listener)) => {
+                    let (shared, handler) = (shared.clone(), handler.clone());
+                    thread::spawn(move || websocket::listen(listener, shared, handler));
+                    Some(local_addr)
+                }
+                Err(err) => {
+                    tracing::error!("failed to bind the websocket listener: {}", err);
+                    shared.record_error(format!("websocket bind error: {}", err));
+                    None
+                }
+            }
+        });
 
         Self {
-            server,
+            servers,
             handles,
-            running,
-            config,
+            shared,
+            #[cfg(feature = "websocket")]
+            websocket_addr,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(unix)]
+            unix_paths: Mutex::new(Vec::new()),
         }
     }
 
-    /// Stops the server.
+    /// Stops the server. Idle workers terminate immediately, the ones handling a request after
+    /// sending its response.
     pub fn stop(&self) {
-        self.running.store(false, Ordering::SeqCst);
+        self.shared.set_state(ServerState::Stopped);
+        #[cfg(unix)]
+        for path in lock_state(&self.unix_paths).drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+        for server in &self.servers {
+            for _ in 0..self.shared.config.num_threads.get() {
+                server.unblock();
+            }
+        }
+    }
+
+    /// Stops accepting new JSON RPC requests, answering them with HTTP status 503, while letting
+    /// the ones already being handled finish. The server is then stopped.
+    pub fn drain(&self) {
+        let _ = self.shared.state.compare_exchange(
+            ServerState::Running as u8,
+            ServerState::Draining as u8,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        self.shared.finish_draining();
+    }
+
+    /// Returns a snapshot of the request metrics collected so far.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> metrics::MetricsSnapshot {
+        self.shared.metrics.snapshot()
+    }
+
+    /// Returns the request metrics and the [`JsonRpcServer::status_counts`] in the Prometheus
+    /// text exposition format, to serve them from a `/metrics` endpoint.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_prometheus(&self) -> String {
+        self.metrics().to_prometheus(&self.status_counts())
+    }
+
+    /// Returns the most recent errors encountered while serving requests, oldest first, such as
+    /// failures receiving requests, sending responses, or handler errors.
+    ///
+    /// At most [`Config::max_recent_errors`] are retained.
+    pub fn recent_errors(&self) -> Vec<String> {
+        lock_state(&self.shared.recent_errors)
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the number of responses sent by HTTP status code, across JSON RPC, file and
+    /// OPTIONS requests.
+    pub fn status_counts(&self) -> HashMap<u16, u64> {
+        lock_state(&self.shared.status_counts).clone()
+    }
+
+    /// Registers a streaming client, such as an SSE or WebSocket connection, returning the
+    /// receiver of the notifications sent with [`JsonRpcServer::broadcast`]. The client is
+    /// unregistered once the receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::Receiver<Value> {
+        self.shared.subscribers.subscribe()
+    }
+
+    /// Sends a JSON RPC notification, a request without id, to all the connected streaming
+    /// clients, such as a new block arriving. Returns the number of clients it was sent to.
+    pub fn broadcast(&self, method: &str, params: Value) -> usize {
+        self.shared.subscribers.broadcast(method, params)
+    }
+
+    /// Returns a handle sending events to the clients of the server-sent events endpoint, see
+    /// [`Config::events_path`].
+    pub fn event_sender(&self) -> EventSender {
+        EventSender {
+            subscribers: self.shared.subscribers.clone(),
+        }
+    }
+
+    /// Returns how many workers are currently handling a request, to help sizing
+    /// [`Config::num_threads`].
+    pub fn worker_stats(&self) -> WorkerStats {
+        let total = self.shared.config.num_threads.get() as usize * self.servers.len();
+        let busy = self.shared.busy_workers.load(Ordering::SeqCst).min(total);
+        WorkerStats {
+            busy,
+            idle: total - busy,
+            total,
+        }
+    }
+
+    /// Returns the current [`ServerState`].
+    pub fn state(&self) -> ServerState {
+        self.shared.state()
     }
 
     /// Returns true unless the server has been stopped.
     pub fn is_running(&self) -> bool {
-        self.running.load(Ordering::SeqCst)
+        self.shared.state() != ServerState::Stopped
     }
 
     /// Waits for the server threads to finish by calling `join` on each associated [`JoinHandle`].
@@ -240,329 +1085,5442 @@ impl JsonRpcServer {
     }
 }
 
-// sends the response and debug logs the status code and message, or logs the error.
-fn send_http_response<R>(http_request: tiny_http::Request, response: HttpResponse<R>, message: &str)
-where
-    R: Read,
-{
-    let status = response.status_code();
-    match http_request.respond(response) {
-        Ok(()) => tracing::debug!(
-            "Sent response with status code: {:?} and response message: {}",
-            status,
-            message
-        ),
-        Err(e) => tracing::error!("Error sending response: {}", e),
+/// Stops the server when dropped, the worker threads exit shortly after and release the port.
+///
+/// Dropping doesn't wait for the threads, [`JsonRpcServer::join_threads`] must be called
+/// explicitly to block until they are finished.
+impl Drop for JsonRpcServer {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
-fn validate_jsonrpc_request(http_request: &mut tiny_http::Request) -> Result<Request, InnerError> {
-    tracing::debug!(
-        "received request - method: {:?}, url: {:?}, headers: {:?}",
-        http_request.method(),
-        http_request.url(),
-        http_request.headers()
+// binds a tiny_http server to a Unix socket at `path` with permissions `mode`, replacing a
+// stale socket file
+#[cfg(unix)]
+fn bind_unix_socket(path: &std::path::Path, mode: u32) -> Result<Server, InnerError> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            let message = format!("{} is in use", path.display());
+            return Err(InnerError::Io(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                message,
+            )));
+        }
+        // other files are left alone and binding fails
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let server = Server::http_unix(path).map_err(|e| InnerError::Io(io::Error::other(e)))?;
+    let permissions = std::fs::Permissions::from_mode(mode);
+    if let Err(e) = std::fs::set_permissions(path, permissions) {
+        let _ = std::fs::remove_file(path);
+        return Err(e.into());
+    }
+    Ok(server)
+}
+
+/// Locks the state `Mutex`, recovering it if poisoned.
+///
+/// A handler panicking while holding the state lock poisons it, and every following
+/// [`Mutex::lock`] returns an error. Handlers locking through this function keep working on the
+/// state as the panicking handler left it: it may be inconsistent if the panic happened halfway
+/// through an update, so this should be used only when the state can't be left in an invalid
+/// condition, or when that is preferable to an unusable server.
+///
+/// Note the poison flag is not cleared, so every access to the state should go through this
+/// function.
+pub fn lock_state<T>(state: &Mutex<T>) -> MutexGuard<'_, T> {
+    state.lock().unwrap_or_else(|poisoned| {
+        tracing::warn!("Recovering poisoned state lock");
+        poisoned.into_inner()
+    })
+}
+
+/// How long workers wait for a request before checking whether the server was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a comment is sent to idle server-sent events clients, detecting the disconnected
+/// ones and keeping proxies from closing the connection.
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// tiny_http discards the unread body of a dropped request by allocating a buffer as large as the
+/// remaining declared Content-Length, which aborts the process when the allocation fails.
+const MAX_UNREAD_BODY: u64 = 1 << 30;
+
+// answers a request declaring a body larger than MAX_UNREAD_BODY and closes its connection.
+// Neither dropping nor reading the body is safe, tiny_http also discards it when the client
+// closes the connection early, so the request body reader is never dropped and the socket is
+// closed underneath it instead.
+fn reject_oversized_body(http_request: tiny_http::Request, shared: &Shared) {
+    tracing::warn!(
+        "Rejecting request declaring a body of {:?} bytes",
+        http_request.body_length()
     );
+    let addr = http_request.remote_addr().copied();
+    let response = HttpResponse::from_string("413: Request body too large")
+        .with_status_code(413)
+        .with_header(Header::from_bytes("Connection", "close").expect("valid header"));
+    shared.count_response(413, response.data_length());
+    // upgrade writes the response and hands over the connection without dropping the body reader
+    let mut stream = http_request.upgrade("HTTP/1.1", response);
+    let _ = stream.flush();
+    std::mem::forget(stream);
+    if let Some(addr) = addr {
+        close_connection(addr);
+    }
+}
+
+// shuts down and closes the sockets of the connection from `peer`. Their owners, the request
+// body reader and the tiny_http connection waiting for it, are leaked and never use them again.
+fn close_connection(peer: std::net::SocketAddr) {
+    visit_sockets(|stream| {
+        let connected = stream.peer_addr().is_ok_and(|addr| addr == peer);
+        if connected {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        connected
+    });
+}
+
+// calls `visit` with the TCP sockets open in the process, closing the ones for which it returns
+// true. There are none on other platforms than Unix.
+fn visit_sockets(mut visit: impl FnMut(&TcpStream) -> bool) {
+    #[cfg(unix)]
+    {
+        use std::{mem::ManuallyDrop, os::unix::io::FromRawFd};
+
+        let Ok(entries) = std::fs::read_dir("/dev/fd") else {
+            return;
+        };
+        let fds: Vec<i32> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        for fd in fds {
+            // SAFETY: the descriptor is only closed if `visit` asks to, otherwise it is left
+            // untouched, calls on descriptors other than sockets fail
+            let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+            if visit(&stream) {
+                drop(ManuallyDrop::into_inner(stream));
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = &mut visit;
+}
+
+// responds 403 to a request rejected by the accept filter and closes the connection
+fn reject_filtered(http_request: tiny_http::Request, shared: &Shared) {
+    let addr = shared.client_addr(&http_request);
+    tracing::debug!("Rejecting request from {:?} by the accept filter", addr);
+    let response = error_response(&http_request, &shared.config, 403, "403: Forbidden");
+    shared.count_response(403, response.data_length());
+    if let Err(e) = shared.check_sent(respond_and_close(http_request, response)) {
+        shared.record_error(format!("Error sending response: {}", e));
+    }
+}
+
+// tiny_http keeps connections alive unless the client asks otherwise, ignoring `Connection`
+// headers in responses, so the response is written by hand with a `Connection: close` header.
+// Well behaved clients then close the connection.
+fn respond_and_close<R: Read>(
+    http_request: tiny_http::Request,
+    response: HttpResponse<R>,
+) -> io::Result<()> {
+    let status = response.status_code();
+    let headers = response.headers().to_vec();
+    let is_head = http_request.method() == &tiny_http::Method::Head;
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)?;
+
+    let mut writer = http_request.into_writer();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\n",
+        status.0,
+        status.default_reason_phrase()
+    )?;
+    for header in headers {
+        write!(writer, "{}: {}\r\n", header.field, header.value)?;
+    }
+    write!(
+        writer,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    if !is_head {
+        writer.write_all(&body)?;
+    }
+    writer.flush()
+}
+
+// sends the response and debug logs the status code and message, or logs the error.
+fn send_http_response<R>(
+    shared: &Shared,
+    http_request: tiny_http::Request,
+    response: HttpResponse<R>,
+    message: &str,
+) where
+    R: Read,
+{
+    let status = response.status_code();
+    match shared.respond(http_request, response) {
+        Ok(()) => tracing::debug!(
+            "Sent response with status code: {:?} and response message: {}",
+            status,
+            message
+        ),
+        Err(e) => shared.record_error(format!("Error sending response: {}", e)),
+    }
+}
+
+// answers the liveness and readiness endpoints, running the readiness checks
+fn handle_probe(http_request: tiny_http::Request, shared: &Shared) {
+    let config = &shared.config;
+    let (status, body) = if config.health_path.as_deref() == Some(url_path(http_request.url())) {
+        (200, serde_json::json!({ "status": "ok" }))
+    } else {
+        let checks: serde_json::Map<String, Value> = config
+            .readiness_checks
+            .iter()
+            .map(|(name, check)| {
+                let result = check().err().unwrap_or_else(|| "ok".to_string());
+                (name.clone(), Value::String(result))
+            })
+            .collect();
+        let ready = shared.state() == ServerState::Running && checks.values().all(|r| r == "ok");
+        let status = if ready { "ready" } else { "unavailable" };
+        let body = serde_json::json!({ "status": status, "checks": checks });
+        (if ready { 200 } else { 503 }, body)
+    };
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let mut response = HttpResponse::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    for header in shared.headers() {
+        response.add_header(header);
+    }
+    send_http_response(shared, http_request, response, "Probe");
+}
+
+// responds to a http GET request with the registered route handler
+fn handle_get_route(http_request: tiny_http::Request, shared: &Shared) {
+    let Some(handler) = shared.get_route(http_request.url()) else {
+        return;
+    };
+    let context = RequestContext::new(
+        shared.client_addr(&http_request),
+        http_request.url(),
+        http_request.headers().to_vec(),
+    );
+    let mut response = handler(&context);
+    for header in shared.headers() {
+        response.add_header(header);
+    }
+    send_http_response(shared, http_request, response, "GET route");
+}
+
+// responds to OPTIONS requests, normally CORS preflights, advertising the methods allowed on the
+// path, or on the whole server for `OPTIONS *`
+fn handle_options(http_request: tiny_http::Request, shared: &Shared) {
+    let url = http_request.url();
+    let allow = if url == "*" {
+        "GET, HEAD, POST, OPTIONS"
+    } else {
+        match (&shared.config.rpc_path, shared.is_rpc_path(url)) {
+            (None, _) => "GET, HEAD, POST, OPTIONS",
+            (Some(_), true) => "POST, OPTIONS",
+            (Some(_), false) => "GET, HEAD, OPTIONS",
+        }
+    };
+    let allow = if shared.config.disable_get {
+        allow.trim_start_matches("GET, HEAD, ")
+    } else {
+        allow
+    };
+    let mut response = HttpResponse::empty(204);
+    let headers = http_request.headers();
+    if let (Some(cors), Some(origin), Some(_)) = (
+        &shared.config.cors,
+        find_header(headers, "Origin"),
+        find_header(headers, "Access-Control-Request-Method"),
+    ) {
+        if cors.allows(origin) {
+            response.add_header(
+                Header::from_bytes("Access-Control-Allow-Methods", allow).expect("valid header"),
+            );
+            if !cors.allowed_headers.is_empty() {
+                let allowed_headers = cors.allowed_headers.join(", ");
+                response.add_header(
+                    Header::from_bytes("Access-Control-Allow-Headers", allowed_headers)
+                        .expect("valid header"),
+                );
+            }
+            if let Some(max_age) = cors.max_age {
+                let max_age = max_age.as_secs().to_string();
+                response.add_header(
+                    Header::from_bytes("Access-Control-Max-Age", max_age).expect("valid header"),
+                );
+            }
+        }
+    }
+    response.add_header(Header::from_bytes("Allow", allow).expect("valid header"));
+    for header in shared.headers() {
+        response.add_header(header);
+    }
+    let message = "OPTIONS request";
+    send_http_response(shared, http_request, response, message);
+}
+
+// responds 405 to a GET to the rpc_path, a common mistake of clients
+fn reject_get_rpc_path(http_request: tiny_http::Request, shared: &Shared) {
+    let err = InnerError::HttpMethodNotAllowed;
+    let status = err.http_status();
+    let data = serde_json::to_string(&Response::from_error(None, err)).unwrap_or_default();
+    let mut response = HttpResponse::from_string(data)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Allow", "POST, OPTIONS").expect("valid header"))
+        .with_header(Header::from_bytes("Content-Type", "application/json").expect("valid header"));
+    for header in shared.headers() {
+        response.add_header(header);
+    }
+    send_http_response(shared, http_request, response, "GET to the rpc_path");
+}
+
+// returns the path of the request url, without the query string
+fn url_path(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(path, _)| path)
+}
+
+// decodes the percent-encoded bytes of a URL path, `None` if an escape is invalid or the path
+// isn't UTF-8 or contains NUL or backslash characters, which no served file name should have
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let decoded = String::from_utf8(bytes).ok()?;
+    Some(decoded).filter(|d| !d.contains(['\0', '\\']))
+}
+
+// percent-encodes a URL path, leaving the unreserved characters and the slashes
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// answers a server-sent events request with a stream written by a dedicated thread, so that
+// the worker is free for other requests, until the client disconnects or the server stops
+fn handle_events(http_request: tiny_http::Request, shared: &Arc<Shared>) {
+    let Some(permit) = shared.event_streams.try_acquire() else {
+        let message = "503: Too many event streams";
+        let response = error_response(&http_request, &shared.config, 503, message);
+        send_http_response(shared, http_request, response, message);
+        return;
+    };
+    let events = shared.subscribers.subscribe();
+    let mut head = String::from(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+         Connection: close\r\n",
+    );
+    for header in shared.headers() {
+        head.push_str(&format!("{}: {}\r\n", header.field, header.value));
+    }
+    head.push_str("\r\n");
+    shared.count_response(200, None);
+    let shared = shared.clone();
+    let mut writer = http_request.into_writer();
+    thread::spawn(move || {
+        let _permit = permit;
+        let mut result = writer
+            .write_all(head.as_bytes())
+            .and_then(|_| writer.flush());
+        let mut last_write = Instant::now();
+        while result.is_ok() && shared.state() != ServerState::Stopped {
+            let data = match events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => format!("data: {}\n\n", event),
+                Err(RecvTimeoutError::Timeout) if last_write.elapsed() >= EVENTS_KEEP_ALIVE => {
+                    ": keep-alive\n\n".to_string()
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            result = writer
+                .write_all(data.as_bytes())
+                .and_then(|_| writer.flush());
+            last_write = Instant::now();
+        }
+        tracing::debug!("Events stream closed: {:?}", result.err());
+    });
+}
+
+// responds to a http GET request with the requested file from the serve_dir
+fn handle_get(http_request: tiny_http::Request, shared: &Shared) {
+    let config = &shared.config;
+    if config.rpc_path.is_some() && shared.is_rpc_path(http_request.url()) {
+        reject_get_rpc_path(http_request, shared);
+        return;
+    }
+    let embedded = percent_decode(url_path(http_request.url()))
+        .and_then(|decoded| embedded_asset(config, &decoded));
+    if let Some((name, data)) = embedded {
+        let served_path = format!("/{}", name);
+        let validators = vec![asset_etag(data)];
+        let http_request = match send_not_modified(http_request, shared, &served_path, &validators)
+        {
+            Some(http_request) => http_request,
+            None => return,
+        };
+        let file_name = &served_path[1..];
+        let headers = file_headers(config, file_name, &served_path, Path::new(&name));
+        let headers = headers.into_iter().chain(validators).collect();
+        send_file(http_request, shared, data.to_vec(), headers);
+        return;
+    }
+    let Some(mut path) = config.serve_dir.clone() else {
+        if let (Some(index_message), "/") = (&config.index_message, url_path(http_request.url())) {
+            let mut response = HttpResponse::from_string(index_message.as_str());
+            for header in shared.headers() {
+                response.add_header(header);
+            }
+            send_http_response(shared, http_request, response, "Index message");
+            return;
+        }
+        let message = "No serve_dir defined in server config.";
+        let response = error_response(&http_request, config, 500, message);
+        send_http_response(shared, http_request, response, message);
+        return;
+    };
+    // decode and remove the starting slash
+    let decoded = percent_decode(url_path(http_request.url()));
+    let file_name = decoded
+        .as_deref()
+        .map(|decoded| decoded.strip_prefix('/').unwrap_or(decoded));
+    let root = path.clone();
+    let file_name = match file_name {
+        Some(file_name) => {
+            path.push(file_name);
+            Some(file_name)
+                .filter(|_| is_within_root(&root, &path, file_name, config.follow_symlinks))
+        }
+        None => None,
+    };
+    let Some(file_name) = file_name else {
+        let message = "404: File not found";
+        let response = error_response(&http_request, config, 404, message);
+        send_http_response(shared, http_request, response, message);
+        return;
+    };
+    let mut served_path = format!("/{}", file_name);
+    // serve the root index for client side routes
+    if config.spa_fallback && Path::new(&served_path).extension().is_none() && !path.exists() {
+        path = root.join("index.html");
+        served_path = "/index.html".to_string();
+    }
+    // add index.html to directories
+    if path.is_dir() {
+        if config.directory_listing && !path.join("index.html").exists() {
+            send_directory_listing(http_request, shared, &root, &path, &served_path);
+            return;
+        }
+        path.push("index.html");
+        served_path = format!("{}/index.html", served_path.trim_end_matches('/'));
+    }
+    let headers = file_headers(config, file_name, &served_path, &path);
+    match File::open(path) {
+        Ok(mut file) => {
+            // answer conditional requests for an unchanged file without reading it
+            let validators = file
+                .metadata()
+                .ok()
+                .and_then(|m| file_validators(&m))
+                .unwrap_or_default();
+            let http_request =
+                match send_not_modified(http_request, shared, &served_path, &validators) {
+                    Some(http_request) => http_request,
+                    None => return,
+                };
+            let mut buf = Vec::new();
+            match file.read_to_end(&mut buf) {
+                Ok(n) => tracing::trace!("GET: read {} bytes", n),
+                Err(e) => {
+                    let message = "500: Internal error";
+                    let response = error_response(&http_request, config, 500, message);
+                    send_http_response(
+                        shared,
+                        http_request,
+                        response,
+                        format!("{}: {}", message, e).as_str(),
+                    );
+                    return;
+                }
+            }
+            let headers = headers.into_iter().chain(validators).collect();
+            send_file(http_request, shared, buf, headers);
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound) => {
+            // 404
+            let message = "404: File not found";
+            let response = error_response(&http_request, config, 404, message);
+            send_http_response(shared, http_request, response, message);
+        }
+        Err(e) => {
+            // 500
+            let message = "500: Internal error";
+            let response = error_response(&http_request, config, 500, message);
+            send_http_response(
+                shared,
+                http_request,
+                response,
+                format!("{}: {}", message, e).as_str(),
+            );
+        }
+    }
+}
+
+// returns false if the requested path escapes the served directory, either with `..`
+// components or, unless following symlinks, with symlinks resolving outside of it
+fn is_within_root(root: &Path, path: &Path, file_name: &str, follow_symlinks: bool) -> bool {
+    // only plain names, `..` and absolute paths, replacing the root when joined, are rejected
+    if !Path::new(file_name)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return false;
+    }
+    if follow_symlinks {
+        return true;
+    }
+    match (root.canonicalize(), path.canonicalize()) {
+        (Ok(root), Ok(path)) => path.starts_with(root),
+        // missing files are answered with 404 anyway
+        _ => true,
+    }
+}
+
+// returns the `Cache-Control` header of the first pattern matching the served path
+fn cache_control(cache_control: &[(GlobPattern, String)], path: &str) -> Option<Header> {
+    let (_, value) = cache_control
+        .iter()
+        .find(|(pattern, _)| pattern.matches(path))?;
+    Header::from_bytes("Cache-Control", value.as_bytes()).ok()
+}
+
+/// An entry of a directory listing, see [`Config::directory_listing`].
+#[derive(Serialize)]
+struct DirectoryEntry {
+    /// The file name, with a trailing slash for directories.
+    name: String,
+    size: u64,
+    /// The modification time in seconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+// sends the entries of the directory at `path`, sorted by name, skipping the symlinks pointing
+// outside of `root` unless they are followed
+fn send_directory_listing(
+    http_request: tiny_http::Request,
+    shared: &Shared,
+    root: &Path,
+    path: &Path,
+    served_path: &str,
+) {
+    let config = &shared.config;
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let message = "500: Internal error";
+            let response = error_response(&http_request, config, 500, message);
+            send_http_response(
+                shared,
+                http_request,
+                response,
+                &format!("{}: {}", message, e),
+            );
+            return;
+        }
+    };
+    let mut entries: Vec<DirectoryEntry> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            is_within_root(
+                root,
+                &entry.path(),
+                &name.to_string_lossy(),
+                config.follow_symlinks,
+            )
+        })
+        .filter_map(|entry| {
+            let metadata = std::fs::metadata(entry.path()).ok()?;
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if metadata.is_dir() {
+                name.push('/');
+            }
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            Some(DirectoryEntry {
+                name,
+                size: metadata.len(),
+                modified,
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let (data, content_type) = if accepts(&http_request, "application/json") {
+        let data = serde_json::to_vec(&entries).unwrap_or_default();
+        (data, "application/json")
+    } else {
+        let base = served_path.trim_end_matches('/');
+        let mut rows = String::new();
+        for entry in &entries {
+            let modified = entry
+                .modified
+                .map(|secs| {
+                    httpdate::fmt_http_date(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+                })
+                .unwrap_or_default();
+            rows.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                percent_encode(&format!("{}/{}", base, entry.name)),
+                html_escape(&entry.name),
+                entry.size,
+                modified
+            ));
+        }
+        let title = html_escape(&format!("{}/", base));
+        let page = format!(
+            "<!doctype html>\n<html><head><title>{title}</title></head><body><h1>{title}</h1>\n\
+            <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{rows}</table>\
+            </body></html>\n"
+        );
+        (page.into_bytes(), "text/html; charset=utf-8")
+    };
+    let content_type = Header::from_bytes("Content-Type", content_type).expect("valid header");
+    send_file(http_request, shared, data, vec![content_type]);
+}
+
+// sends a served file with the given headers, compressed if enabled and accepted by the client
+fn send_file(
+    http_request: tiny_http::Request,
+    shared: &Shared,
+    data: Vec<u8>,
+    headers: Vec<Header>,
+) {
+    let (data, encoding_headers) = compress_body(&http_request, &shared.config, data);
+    let mut response = HttpResponse::from_data(data);
+    for header in encoding_headers
+        .into_iter()
+        .chain(shared.headers())
+        .chain(headers)
+    {
+        response.add_header(header);
+    }
+    send_http_response(shared, http_request, response, "File for GET request");
+}
+
+// returns the `Content-Type`, `Content-Disposition` and `Cache-Control` headers of a served file
+fn file_headers(config: &Config, file_name: &str, served_path: &str, path: &Path) -> Vec<Header> {
+    [
+        content_type(&config.mime_types, path),
+        content_disposition(&config.download_paths, file_name, path),
+        cache_control(&config.cache_control, served_path),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// answers with HTTP status 304 if the validators show the client copy of the file is fresh,
+// otherwise returns the request back
+fn send_not_modified(
+    http_request: tiny_http::Request,
+    shared: &Shared,
+    served_path: &str,
+    validators: &[Header],
+) -> Option<tiny_http::Request> {
+    if !is_not_modified(&http_request, validators) {
+        return Some(http_request);
+    }
+    let mut response = HttpResponse::empty(304);
+    for header in validators.iter().cloned().chain(shared.headers()) {
+        response.add_header(header);
+    }
+    if let Some(header) = cache_control(&shared.config.cache_control, served_path) {
+        response.add_header(header);
+    }
+    send_http_response(shared, http_request, response, "File not modified");
+    None
+}
+
+// returns the embedded asset served at `url_path` and its name, the `index.html` of directories,
+// or the root one for client side routes if `spa_fallback` is enabled without a `serve_dir`
+fn embedded_asset(config: &Config, url_path: &str) -> Option<(String, &'static [u8])> {
+    let assets = &config.embedded_assets;
+    if assets.is_empty() {
+        return None;
+    }
+    let name = url_path.trim_start_matches('/');
+    let index = format!("{}/index.html", name.trim_end_matches('/'));
+    let mut names = vec![name.to_string(), index.trim_start_matches('/').to_string()];
+    if config.spa_fallback
+        && config.serve_dir.is_none()
+        && Path::new(url_path).extension().is_none()
+    {
+        names.push("index.html".to_string());
+    }
+    names
+        .into_iter()
+        .find_map(|name| assets.get(&name).map(|data| (name, *data)))
+}
+
+// returns the `ETag` header of an embedded asset, derived from its contents
+fn asset_etag(data: &[u8]) -> Header {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    let etag = format!("\"{:x}-{:x}\"", data.len(), hasher.finish());
+    Header::from_bytes("ETag", etag.as_bytes()).expect("valid header")
+}
+
+// returns the `ETag` and `Last-Modified` headers of a served file, the etag is derived from its
+// size and modification time
+fn file_validators(metadata: &std::fs::Metadata) -> Option<Vec<Header>> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    let etag = format!("\"{:x}-{:x}\"", metadata.len(), since_epoch.as_nanos());
+    let etag = Header::from_bytes("ETag", etag.as_bytes()).ok()?;
+    let last_modified = httpdate::fmt_http_date(modified);
+    let last_modified = Header::from_bytes("Last-Modified", last_modified.as_bytes()).ok()?;
+    Some(vec![etag, last_modified])
+}
+
+// returns true if the client cache is fresh, by `If-None-Match` or, if absent, by
+// `If-Modified-Since`
+fn is_not_modified(request: &tiny_http::Request, validators: &[Header]) -> bool {
+    let etag = find_header(validators, "ETag");
+    if let (Some(if_none_match), Some(etag)) = (header_value(request, "If-None-Match"), etag) {
+        return etag_matches(if_none_match, etag);
+    }
+    let since =
+        header_value(request, "If-Modified-Since").and_then(|v| httpdate::parse_http_date(v).ok());
+    // the modification time is truncated to seconds in `Last-Modified`
+    let modified =
+        find_header(validators, "Last-Modified").and_then(|v| httpdate::parse_http_date(v).ok());
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// returns the `Content-Type` header of the file at `path` by its extension, looked up in the
+// configured `mime_types` first
+fn content_type(mime_types: &HashMap<String, String>, path: &Path) -> Option<Header> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let value = match mime_types.get(&extension) {
+        Some(value) => value.as_str(),
+        None => match extension.as_str() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" | "mjs" => "text/javascript; charset=utf-8",
+            "json" => "application/json",
+            "wasm" => "application/wasm",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "txt" => "text/plain; charset=utf-8",
+            _ => return None,
+        },
+    };
+    Header::from_bytes("Content-Type", value.as_bytes()).ok()
+}
+
+// returns a `Content-Disposition: attachment` header if the requested file matches one of the
+// `download_paths`, either by extension or by being under the given subpath.
+fn content_disposition(download_paths: &[String], url_path: &str, path: &Path) -> Option<Header> {
+    let file_name = path.file_name()?.to_string_lossy();
+    let matched = download_paths.iter().any(|download| {
+        if download.starts_with('.') {
+            file_name
+                .to_ascii_lowercase()
+                .ends_with(&download.to_ascii_lowercase())
+        } else {
+            let download = download.trim_matches('/');
+            let url_path = url_path.trim_end_matches('/');
+            url_path == download
+                || url_path
+                    .strip_prefix(download)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }
+    });
+    if !matched {
+        return None;
+    }
+    // keep the quoted file name a valid ASCII header value
+    let file_name: String = file_name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    let value = format!("attachment; filename=\"{}\"", file_name);
+    Header::from_bytes("Content-Disposition", value).ok()
+}
+
+// a plain text error response, or an HTML page if enabled and preferred by the client
+fn error_response(
+    http_request: &tiny_http::Request,
+    config: &Config,
+    status: u16,
+    message: &str,
+) -> HttpResponse<Cursor<Vec<u8>>> {
+    if let Some(page) = error_page(http_request, config, status) {
+        let content_type =
+            Header::from_str("Content-Type: text/html; charset=utf-8").expect("valid");
+        HttpResponse::from_data(page)
+            .with_status_code(status)
+            .with_header(content_type)
+    } else if config.html_errors && prefers_html(http_request) {
+        html_error_response(status, message)
+    } else {
+        HttpResponse::from_string(message).with_status_code(status)
+    }
+}
+
+// returns the custom error page of GET and HEAD requests for `status`, if configured and readable
+fn error_page(http_request: &tiny_http::Request, config: &Config, status: u16) -> Option<Vec<u8>> {
+    if !matches!(
+        http_request.method(),
+        tiny_http::Method::Get | tiny_http::Method::Head
+    ) {
+        return None;
+    }
+    match config.error_pages.get(&status)? {
+        config::ErrorPage::Inline(page) => Some(page.clone().into_bytes()),
+        config::ErrorPage::File(path) => std::fs::read(path)
+            .map_err(|e| tracing::warn!("failed to read error page {:?}: {}", path, e))
+            .ok(),
+    }
+}
+
+fn html_error_response(status: u16, message: &str) -> HttpResponse<Cursor<Vec<u8>>> {
+    let page = format!(
+        "<!doctype html>\n<html><head><title>{status}</title></head>\
+        <body><h1>{status}</h1><p>{}</p></body></html>\n",
+        html_escape(message)
+    );
+    let content_type = Header::from_str("Content-Type: text/html; charset=utf-8").expect("valid");
+    HttpResponse::from_string(page)
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// validates, handles and responds to a jsonrpc POST request
+fn handle_post<D: Dispatch>(
+    mut http_request: tiny_http::Request,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) {
+    // counted before checking the state, so that draining can't complete in between
+    let _in_flight = InFlight::new(shared);
+
+    // the client IP slot is held until the response is sent
+    let ip_permit = shared
+        .ip_limits
+        .as_ref()
+        .zip(shared.client_addr(&http_request))
+        .map(|(limiter, addr)| limiter.try_acquire(addr.ip()));
+
+    let (status, reply) = if shared.state() == ServerState::Draining {
+        let err = InnerError::Draining;
+        (err.http_status(), Response::from_error(None, err).into())
+    } else if let Some(None) = ip_permit {
+        let err = InnerError::TooManyRequests;
+        (err.http_status(), Response::from_error(None, err).into())
+    } else {
+        let client_addr = shared.client_addr(&http_request);
+        tracing::debug!(
+            "received request - client: {:?}, method: {:?}, url: {:?}, headers: {:?}",
+            client_addr,
+            http_request.method(),
+            http_request.url(),
+            RedactedHeaders(http_request.headers())
+        );
+        #[allow(unused_mut)]
+        let mut context = RequestContext::new(
+            client_addr,
+            http_request.url(),
+            http_request.headers().to_vec(),
+        );
+        #[cfg(feature = "tls")]
+        if let Some(addr) = http_request.remote_addr() {
+            context.client_certificate =
+                shared.tls_peers.get(addr).and_then(|peer| peer.certificate);
+        }
+        dispatch(&context, http_request.as_reader(), shared, handler)
+    };
+
+    // send the response
+    if let Err(err) = send_jsonrpc_response(http_request, reply, status, shared) {
+        shared.record_error(format!("send_response error: {}", err));
+    }
+}
+
+// authenticates, validates and handles a jsonrpc request or batch independently of the
+// transport, returning the HTTP status and the reply
+fn dispatch<D: Dispatch + ?Sized>(
+    context: &RequestContext,
+    body: impl Read,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> (u16, Reply) {
+    #[cfg(feature = "tracing-propagation")]
+    return trace::TraceContext::from_headers(&context.headers)
+        .scope(|| dispatch_traced(context, body, shared, handler));
+    #[cfg(not(feature = "tracing-propagation"))]
+    dispatch_traced(context, body, shared, handler)
+}
+
+fn dispatch_traced<D: Dispatch + ?Sized>(
+    context: &RequestContext,
+    body: impl Read,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> (u16, Reply) {
+    let headers = &context.headers[..];
+    // reject calls from our own handlers, which could deadlock waiting for a worker
+    if shared.config.reentrancy_guard && shared.reentrancy.is_reentrant(context.remote_addr) {
+        let err = InnerError::Reentrant;
+        return (err.http_status(), Response::from_error(None, err).into());
+    }
+
+    // authenticate, then validate/parse the jsonrpc POST request
+    let mut body = CountingReader::new(body);
+    let payload = handler
+        .authenticate(headers)
+        .map_err(|err| (401, err))
+        .and_then(|principal| {
+            validate_jsonrpc_request(headers, &mut body, &shared.config)
+                .map(|payload| (principal, payload))
+                .map_err(|err| (err.http_status(), err.into()))
+        });
+    #[cfg(feature = "metrics")]
+    shared.metrics.record_bytes_in(body.count);
+    let (principal, payload) = match payload {
+        Ok(payload) => payload,
+        Err((status, err)) => {
+            // no id since we couldn't validate the request...
+            return (status, Response::from_error(None, err).into());
+        }
+    };
+
+    // replay the reply to a request already handled with the same idempotency key
+    let key = shared
+        .config
+        .idempotency_header
+        .as_deref()
+        .and_then(|header| context.header(header));
+    let Some((cache, key)) = shared.idempotency.as_ref().zip(key) else {
+        return handle_payload(payload, principal, context, shared, handler);
+    };
+    let key = IdempotencyKey {
+        ip: context.remote_addr.map(|addr| addr.ip()),
+        authorization: context.header("Authorization").map(str::to_string),
+        key: key.to_string(),
+    };
+    let fingerprint = payload.fingerprint();
+    let handle = || handle_payload(payload, principal, context, shared, handler);
+    cache
+        .run(key, fingerprint, handle, |(status, reply)| {
+            *status == 200 && !matches!(reply, Reply::Single(r) if r.http.stream.is_some())
+        })
+        .unwrap_or_else(|| {
+            let err = InnerError::IdempotencyConflict;
+            (err.http_status(), Response::from_error(None, err).into())
+        })
+}
+
+fn handle_payload<D: Dispatch + ?Sized>(
+    payload: Payload,
+    principal: D::Principal,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> (u16, Reply) {
+    match payload {
+        Payload::Single(request) => {
+            let notification = request.id.is_none();
+            let (status, response) = handle_request(request, principal, context, shared, handler);
+            if notification {
+                (204, Reply::Empty)
+            } else {
+                (status, response.into())
+            }
+        }
+        Payload::Batch(requests) => {
+            let responses = handle_batch(requests, principal, context, shared, handler);
+            if responses.is_empty() {
+                // a batch of notifications only
+                (204, Reply::Empty)
+            } else {
+                (200, Reply::Batch(responses))
+            }
+        }
+    }
+}
+
+// handles a validated request through the middleware, returning the HTTP status and the response
+fn handle_request<D: Dispatch + ?Sized>(
+    request: Request,
+    principal: D::Principal,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> (u16, Response) {
+    let middleware = &shared.config.middleware;
+    let id = request.id.clone();
+    let jsonrpc_1 = is_jsonrpc_1(&request, &shared.config);
+    let mut entered = 0;
+    let result = middleware
+        .iter()
+        .try_fold(request, |request, middleware| {
+            let request = middleware.before(request)?;
+            entered += 1;
+            Ok(request)
+        })
+        .and_then(|request| handle_jsonrpc_request(request, principal, context, shared, handler));
+    let (status, mut response) = match result {
+        Ok(response) => (200, response),
+        Err(Error::Stop) => {
+            shared.set_state(ServerState::Stopped);
+            (200, Response::from_error(id, Error::Stop))
+        }
+        Err(err) => (err.http_status(), Response::from_error(id, err)),
+    };
+    if jsonrpc_1 {
+        response.jsonrpc = "1.0".into();
+        response.http.jsonrpc_1 = true;
+    }
+    let response = middleware[..entered]
+        .iter()
+        .rev()
+        .fold(response, |response, middleware| middleware.after(response));
+    (status, response)
+}
+
+// handles the requests of a batch on up to `num_threads` threads, the worker's included,
+// returning the responses in the order of the requests, notifications have none
+fn handle_batch<D: Dispatch + ?Sized>(
+    requests: Vec<Result<Request, InnerError>>,
+    principal: D::Principal,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> Vec<Response> {
+    let handle = |request: Result<Request, InnerError>, principal: D::Principal| match request {
+        Ok(request) => {
+            let notification = request.id.is_none();
+            let (_, response) = handle_request(request, principal, context, shared, handler);
+            Some(response).filter(|_| !notification)
+        }
+        Err(err) => Some(Response::from_error(None, err)),
+    };
+    let threads = usize::from(shared.config.num_threads.get()).min(requests.len());
+    if threads <= 1 {
+        return requests
+            .into_iter()
+            .filter_map(|request| handle(request, principal.clone()))
+            .collect();
+    }
+
+    // each thread takes the next unhandled request and stores its response at the same index, a
+    // panicking handler is propagated by the scope so every request is handled afterwards
+    let len = requests.len();
+    let requests: Vec<_> = requests.into_iter().map(|r| Mutex::new(Some(r))).collect();
+    let responses: Vec<_> = (0..len).map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    let work = |principal: D::Principal| loop {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        let Some(request) = requests.get(i).and_then(|r| lock_state(r).take()) else {
+            break;
+        };
+        *lock_state(&responses[i]) = handle(request, principal.clone());
+    };
+    thread::scope(|scope| {
+        for _ in 1..threads {
+            let principal = principal.clone();
+            scope.spawn(with_current_context(|| work(principal)));
+        }
+        work(principal);
+    });
+    responses
+        .into_iter()
+        .filter_map(|response| response.into_inner().unwrap_or_else(|p| p.into_inner()))
+        .collect()
+}
+
+// wraps `f` to run it on another thread in the trace context of the calling thread
+#[cfg(feature = "tracing-propagation")]
+fn with_current_context<R>(f: impl FnOnce() -> R) -> impl FnOnce() -> R {
+    let context = trace::TraceContext::current();
+    move || match context {
+        Some(context) => context.scope(f),
+        None => f(),
+    }
+}
+
+#[cfg(not(feature = "tracing-propagation"))]
+fn with_current_context<R>(f: impl FnOnce() -> R) -> impl FnOnce() -> R {
+    f
+}
+
+/// A validated POST body, a single request or a batch of requests, the invalid ones replaced
+/// with their error.
+enum Payload {
+    Single(Request),
+    Batch(Vec<Result<Request, InnerError>>),
+}
+
+impl Payload {
+    // identifies the requests by their methods and params, a key can't be reused for others
+    fn fingerprint(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let mut hash = |request: &Result<&Request, &InnerError>| match request {
+            Ok(request) => {
+                request.method.hash(&mut hasher);
+                serde_json::to_string(&request.params)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+            }
+            Err(err) => err.to_string().hash(&mut hasher),
+        };
+        match self {
+            Payload::Single(request) => hash(&Ok(request)),
+            Payload::Batch(requests) => requests.iter().for_each(|r| hash(&r.as_ref())),
+        }
+        hasher.finish()
+    }
+}
+
+/// Scopes an idempotency key to the client sending it, identified by its IP address and its
+/// credentials.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct IdempotencyKey {
+    ip: Option<IpAddr>,
+    authorization: Option<String>,
+    key: String,
+}
+
+/// The reply to a POST body, the responses to a batch are sent as an array. Notifications, and
+/// batches of notifications only, are answered without a body.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Reply {
+    Single(Response),
+    Batch(Vec<Response>),
+    Empty,
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        Reply::Single(response)
+    }
+}
+
+/// Counts the bytes read, as received before any decompression.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+fn validate_jsonrpc_request(
+    headers: &[Header],
+    reader: impl Read,
+    config: &Config,
+) -> Result<Payload, InnerError> {
+    if config.require_json_content_type {
+        // check content-type header exists
+        let content_header = headers
+            .iter()
+            .find(|h| h.field.as_str().as_str().to_ascii_lowercase() == "content-type")
+            .ok_or(InnerError::NoContentType)?;
+
+        // check the media type is application/json, ignoring parameters such as the charset
+        let value = content_header.value.as_str();
+        let media_type = value
+            .split_once(';')
+            .map_or(value, |(media_type, _)| media_type);
+        let media_type = media_type.trim().to_ascii_lowercase();
+        let accepted = media_type == "application/json"
+            || (config.accept_json_rpc_content_type && media_type == "application/json-rpc");
+        if !accepted {
+            return Err(InnerError::WrongContentType);
+        }
+    }
+
+    // check the declared body length before reading it
+    if let Some(value) = find_header(headers, "Content-Length") {
+        let length = parse_content_length(value)?;
+        if let Some(max) = config.max_body_bytes {
+            if length > max {
+                return Err(InnerError::BodyTooLarge(max));
+            }
+        }
+    }
+
+    // read the body, decompressing it if needed
+    let encoding = find_header(headers, "Content-Encoding").map(str::to_ascii_lowercase);
+    let body = match encoding.as_deref().map(str::trim) {
+        None | Some("identity") => read_limited(reader, config)?,
+        #[cfg(feature = "compression")]
+        Some("gzip") => read_limited(flate2::read::GzDecoder::new(reader), config)?,
+        #[cfg(feature = "compression")]
+        Some("deflate") => read_limited(flate2::read::ZlibDecoder::new(reader), config)?,
+        Some(_) => return Err(InnerError::UnsupportedEncoding),
+    };
+
+    // parse json into request
+    let s = String::from_utf8(body).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    if config.log_bodies {
+        tracing::trace!("request body: {}", truncate_for_log(&s));
+    }
+
+    if !s.trim_start().starts_with('[') {
+        let value: Value = serde_json::from_str(&s)?;
+        return Ok(Payload::Single(parse_request(value, config)?));
+    }
+
+    // reject oversized batches before parsing any of their elements
+    if let Some(max) = config.max_batch_size {
+        let len = serde_json::from_str::<Vec<serde::de::IgnoredAny>>(&s)?.len();
+        if len > max {
+            return Err(InnerError::BatchTooLarge(max));
+        }
+    }
+
+    // each request of the batch is validated on its own
+    let batch: Vec<Value> = serde_json::from_str(&s)?;
+    if batch.is_empty() {
+        return Err(InnerError::EmptyBatch);
+    }
+    let requests = batch
+        .into_iter()
+        .map(|value| parse_request(value, config))
+        .collect();
+    Ok(Payload::Batch(requests))
+}
+
+fn is_jsonrpc_1(request: &Request, config: &Config) -> bool {
+    config.jsonrpc_1_compat && request.jsonrpc == "1.0"
+}
+
+/// The members of a request object.
+const REQUEST_FIELDS: [&str; 4] = ["jsonrpc", "id", "method", "params"];
+
+// valid JSON not representing a request is an invalid request rather than a parse error, as are
+// requests failing the strict checks of the config
+fn parse_request(mut value: Value, config: &Config) -> Result<Request, InnerError> {
+    if config.jsonrpc_1_compat {
+        if let Some(object) = value.as_object_mut() {
+            // 1.0 requests have no version, and a null id marks notifications
+            let version = object.entry("jsonrpc").or_insert_with(|| "1.0".into());
+            if version == "1.0" && object.get("id") == Some(&Value::Null) {
+                object.remove("id");
+            }
+        }
+    }
+    if config.deny_unknown_fields {
+        let unknown = value.as_object().and_then(|object| {
+            object
+                .keys()
+                .find(|k| !REQUEST_FIELDS.contains(&k.as_str()))
+        });
+        if let Some(field) = unknown {
+            return Err(InnerError::UnknownField(field.clone()));
+        }
+    }
+    let request: Request = serde_json::from_value(value).map_err(InnerError::InvalidRequest)?;
+    if config.require_id && request.id.is_none() {
+        return Err(InnerError::MissingId);
+    }
+    Ok(request)
+}
+
+// reads a body up to `max_body_bytes`, also bounding chunked bodies without a `Content-Length`,
+// and the decompressed size to defuse compression bombs
+fn read_limited(reader: impl Read, config: &Config) -> Result<Vec<u8>, InnerError> {
+    let max = config.max_body_bytes.unwrap_or(MAX_UNREAD_BODY);
+    let mut body = Vec::new();
+    reader.take(max.saturating_add(1)).read_to_end(&mut body)?;
+    if body.len() as u64 > max {
+        return Err(InnerError::BodyTooLarge(max));
+    }
+    Ok(body)
+}
+
+// parses a Content-Length header value, values overflowing u64 are reported as too large since
+// they exceed any limit
+fn parse_content_length(value: &str) -> Result<u64, InnerError> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(InnerError::InvalidContentLength);
+    }
+    value
+        .parse()
+        .map_err(|_| InnerError::BodyTooLarge(u64::MAX))
+}
+
+// calls the method handler, on a separate thread for long poll methods and with a request timeout
+// so that the worker can answer once the timeout expires, leaving the handler running in the
+// background
+fn call_handler<D: Dispatch + ?Sized>(
+    request: Request,
+    principal: D::Principal,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+    permit: Option<SemaphorePermit>,
+) -> Result<Response, Error> {
+    let long_poll = shared.config.long_poll.get(&request.method).copied();
+    let Some(timeout) = long_poll.or(shared.config.request_timeout) else {
+        return shared
+            .reentrancy
+            .scope(|| handler.call(request, principal, context));
+    };
+    let (id, method) = (request.id.clone(), request.method.clone());
+    let (tx, rx) = mpsc::sync_channel(1);
+    let cancellation = context.cancellation.clone();
+    let ip = context.remote_addr.map(|addr| addr.ip());
+    // filled when the request times out, emptied by the handler thread once the handler returns
+    let overrun = Arc::new(Mutex::new(None));
+    let (handler, reentrancy, context) =
+        (handler.clone(), shared.reentrancy.clone(), context.clone());
+    let call = with_current_context(move || {
+        reentrancy.scope(|| handler.call(request, principal, &context))
+    });
+    {
+        let overrun = overrun.clone();
+        thread::spawn(move || {
+            let _ = tx.send(call());
+            lock_state(&overrun).take();
+        });
+    }
+    let result = rx.recv_timeout(timeout);
+    if let Err(RecvTimeoutError::Timeout) = result {
+        *lock_state(&overrun) = Some(Overrun::new(shared, ip, permit));
+    }
+    match result {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) if long_poll.is_some() => {
+            tracing::debug!("Long poll '{}' timed out", method);
+            Ok(Response::long_poll_timeout(id, timeout))
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            cancellation.cancel();
+            Err(Error::Inner(InnerError::RequestTimeout { method, timeout }))
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(format!("Handler of '{}' panicked", method).into())
+        }
+    }
+}
+
+fn handle_jsonrpc_request<D: Dispatch + ?Sized>(
+    request: Request,
+    principal: D::Principal,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> Result<Response, Error> {
+    // check jsonrpc version
+    if request.jsonrpc.as_str() != "2.0" && !is_jsonrpc_1(&request, &shared.config) {
+        return Err(error::Error::Inner(InnerError::InvalidVersion));
+    }
+
+    // answer the built-in methods without calling the handler
+    if shared.config.builtin_methods {
+        if let Some(result) = builtin_method(&request, context, shared)? {
+            return Ok(Response::result(request.id, result));
+        }
+    }
+
+    // check method is not reserved (ie: starts with "rpc."), except for the OpenRPC discovery
+    // answered by a router
+    #[cfg(feature = "openrpc")]
+    let discover = handler.answers_discover() && request.method == openrpc::DISCOVER_METHOD;
+    #[cfg(not(feature = "openrpc"))]
+    let discover = false;
+    if request.method.starts_with("rpc.") && !discover {
+        return Err(error::Error::Inner(InnerError::ReservedMethodPrefix));
+    }
+
+    // check params is a structured value, if requested
+    if shared.config.strict_params
+        && request
+            .params
+            .as_ref()
+            .is_some_and(|params| !params.is_array() && !params.is_object())
+    {
+        return Err(error::Error::Inner(InnerError::ScalarParams));
+    }
+
+    // check the method concurrency limit, the permit is held until the handler returns
+    let permit = match shared.method_limits.get(&request.method) {
+        Some(semaphore) => Some(
+            semaphore
+                .try_acquire()
+                .ok_or_else(|| InnerError::MethodBusy(request.method.clone()))?,
+        ),
+        None => None,
+    };
+
+    // register the request so that its client can cancel it by id
+    let client = context.remote_addr.map(|addr| addr.ip());
+    let registration = request
+        .id
+        .as_ref()
+        .map(|id| shared.cancellations.register(client, id));
+    let context = match &registration {
+        Some(registration) => Cow::Owned(RequestContext {
+            cancellation: registration.token(),
+            ..context.clone()
+        }),
+        None => Cow::Borrowed(context),
+    };
+
+    // call the method handler
+    let id = request.id.clone();
+    #[cfg(feature = "metrics")]
+    let method = request.method.clone();
+    let start = Instant::now();
+    let result = call_handler(request, principal, &context, shared, handler, permit);
+    drop(registration);
+    let elapsed = start.elapsed();
+    #[cfg(feature = "metrics")]
+    shared.metrics.record(&method, elapsed);
+    let mut response = match result {
+        Ok(response) => response,
+        Err(Error::Stop) => return Err(Error::Stop),
+        Err(Error::Inner(err)) => {
+            shared.record_error(format!("Error processing request: {}", err));
+            Response::from_error(id, err)
+        }
+        Err(Error::Implementation(err)) => Response::from_error(id, err),
+    };
+    if shared.config.response_timing {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        response.meta = Some(serde_json::json!({ "elapsed_ms": elapsed_ms }));
+    }
+
+    Ok(response)
+}
+
+// returns the result of a built-in maintenance method, `None` for other methods
+fn builtin_method(
+    request: &Request,
+    context: &RequestContext,
+    shared: &Shared,
+) -> Result<Option<Value>, Error> {
+    let result = match request.method.as_str() {
+        "rpc.ping" => Value::from("pong"),
+        "rpc.version" => Value::from(env!("CARGO_PKG_VERSION")),
+        "rpc.uptime" => Value::from(shared.started.elapsed().as_secs()),
+        "rpc.threads" => serde_json::json!({
+            "num_threads": shared.config.num_threads.get(),
+            "busy": shared.busy_workers.load(Ordering::SeqCst),
+        }),
+        "rpc.cancel" => {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Params {
+                Named { id: Id },
+                Positional((Id,)),
+            }
+            let id = match request.params_as()? {
+                Params::Named { id } | Params::Positional((id,)) => id,
+            };
+            let client = context.remote_addr.map(|addr| addr.ip());
+            Value::from(shared.cancellations.cancel(client, &id))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(result))
+}
+
+fn send_jsonrpc_response(
+    request: tiny_http::Request,
+    reply: Reply,
+    status: u16,
+    shared: &Shared,
+) -> Result<(), InnerError> {
+    let response = match reply {
+        Reply::Single(response) => response,
+        // the HTTP extras of the responses don't apply to a batch
+        batch @ Reply::Batch(_) => {
+            let data = response_body(&batch, shared);
+            if shared.config.log_bodies {
+                tracing::trace!("response body: {}", truncate_for_log(&data));
+            }
+            let mut http_response =
+                string_response(&request, shared, data).with_status_code(status);
+            for header in shared.headers() {
+                http_response.add_header(header);
+            }
+            return Ok(shared.respond(request, http_response)?);
+        }
+        Reply::Empty => {
+            let mut http_response = HttpResponse::empty(status);
+            for header in shared.headers() {
+                http_response.add_header(header);
+            }
+            return Ok(shared.respond(request, http_response)?);
+        }
+    };
+    if let Some(redirect) = &response.http.redirect {
+        let location = Header::from_bytes(&b"Location"[..], redirect.location.as_bytes())
+            .map_err(|_| InnerError::InvalidHeader)?;
+        let status = if redirect.permanent { 301 } else { 302 };
+        let mut http_response = HttpResponse::empty(status).with_header(location);
+        for header in shared.headers() {
+            http_response.add_header(header);
+        }
+        return Ok(shared.respond(request, http_response)?);
+    }
+    let etag = match &response.http.etag {
+        Some(etag) if response.is_result() => Some(
+            Header::from_bytes(&b"ETag"[..], format!("\"{}\"", etag).as_bytes())
+                .map_err(|_| InnerError::InvalidHeader)?,
+        ),
+        _ => None,
+    };
+    if let (Some(etag), Some(if_none_match)) = (&etag, header_value(&request, "If-None-Match")) {
+        if etag_matches(if_none_match, etag.value.as_str()) {
+            let mut http_response = HttpResponse::empty(304).with_header(etag.clone());
+            for header in shared.headers() {
+                http_response.add_header(header);
+            }
+            return Ok(shared.respond(request, http_response)?);
+        }
+    }
+    let mut http_response = match response.http.raw {
+        _ if response.is_error() && shared.config.html_errors && prefers_html(&request) => {
+            let message = response
+                .error
+                .as_ref()
+                .map(|e| format!("JSON RPC error {}: {}", e.code, e.message))
+                .unwrap_or_default();
+            html_error_response(status, &message)
+        }
+        Some(raw) if accepts(&request, &raw.content_type) => {
+            let content_type =
+                Header::from_bytes(&b"Content-Type"[..], raw.content_type.as_bytes())
+                    .map_err(|_| InnerError::InvalidHeader)?;
+            let (data, headers) = compress_body(&request, &shared.config, raw.data);
+            let mut http_response = HttpResponse::from_data(data).with_header(content_type);
+            for header in headers {
+                http_response.add_header(header);
+            }
+            http_response
+        }
+        _ if response.is_result() && response.http.stream.is_some() => {
+            let mut http_response = stream_response(response).with_status_code(status);
+            if let Some(etag) = etag {
+                http_response.add_header(etag);
+            }
+            for header in shared.headers() {
+                http_response.add_header(header);
+            }
+            return Ok(shared.respond(request, http_response)?);
+        }
+        _ => {
+            let data = response_body(&Reply::Single(response), shared);
+            if shared.config.log_bodies {
+                tracing::trace!("response body: {}", truncate_for_log(&data));
+            }
+            string_response(&request, shared, data)
+        }
+    }
+    .with_status_code(status);
+    if let Some(etag) = etag {
+        http_response.add_header(etag);
+    }
+    for header in shared.headers() {
+        http_response.add_header(header);
+    }
+    Ok(shared.respond(request, http_response)?)
+}
+
+// builds the response of a JSON RPC body like `HttpResponse::from_string`, compressed if
+// enabled and accepted by the client
+fn string_response(
+    request: &tiny_http::Request,
+    shared: &Shared,
+    data: String,
+) -> HttpResponse<Cursor<Vec<u8>>> {
+    let (data, headers) = compress_body(request, &shared.config, data.into_bytes());
+    let content_type =
+        Header::from_bytes("Content-Type", "text/plain; charset=UTF-8").expect("valid header");
+    let mut http_response = HttpResponse::from_data(data).with_header(content_type);
+    for header in headers {
+        http_response.add_header(header);
+    }
+    http_response
+}
+
+// compresses a body of at least `compress_min_bytes` with the encoding preferred by the client,
+// returning it with the `Content-Encoding` and `Vary` headers to add
+#[cfg(feature = "compression")]
+fn compress_body(
+    request: &tiny_http::Request,
+    config: &Config,
+    data: Vec<u8>,
+) -> (Vec<u8>, Vec<Header>) {
+    use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+
+    if config
+        .compress_min_bytes
+        .map_or(true, |min| data.len() < min)
+    {
+        return (data, vec![]);
+    }
+    let vary = Header::from_bytes("Vary", "Accept-Encoding").expect("valid header");
+    let encoding = header_value(request, "Accept-Encoding").and_then(preferred_encoding);
+    let compressed = match encoding {
+        Some("gzip") => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data).and_then(|_| encoder.finish())
+        }
+        Some(_) => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data).and_then(|_| encoder.finish())
+        }
+        None => return (data, vec![vary]),
+    };
+    match (encoding, compressed) {
+        (Some(encoding), Ok(compressed)) => {
+            let header = Header::from_bytes("Content-Encoding", encoding).expect("valid header");
+            (compressed, vec![header, vary])
+        }
+        _ => (data, vec![vary]),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_body(_: &tiny_http::Request, _: &Config, data: Vec<u8>) -> (Vec<u8>, Vec<Header>) {
+    (data, vec![])
+}
+
+// returns the supported encoding with the highest quality in an `Accept-Encoding` header value,
+// gzip on ties. `*` stands for the encodings not listed.
+#[cfg(feature = "compression")]
+fn preferred_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let qualities: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name, quality)
+        })
+        .collect();
+    let quality = |name: &str| {
+        let find = |name: &str| qualities.iter().find(|(n, _)| n == name).map(|(_, q)| *q);
+        find(name).or_else(|| find("*")).unwrap_or(0.0)
+    };
+    let (gzip, deflate) = (quality("gzip"), quality("deflate"));
+    match () {
+        _ if gzip > 0.0 && gzip >= deflate => Some("gzip"),
+        _ if deflate > 0.0 => Some("deflate"),
+        _ => None,
+    }
+}
+
+// builds the HTTP response of a streamed result, of unknown length, sent with chunked transfer
+// encoding
+fn stream_response(response: Response) -> HttpResponse<StreamBody> {
+    let items = response.http.stream.as_ref().and_then(ResultStream::take);
+    // with the items taken the result serializes as an empty array, the first one in the JSON
+    // text since only `jsonrpc` precedes it
+    let data = serde_json::to_vec(&response).unwrap_or_default();
+    let split = data
+        .windows(RESULT_KEY.len())
+        .position(|window| window == RESULT_KEY)
+        .map_or(0, |pos| pos + RESULT_KEY.len() - 1);
+    let (prefix, suffix) = data.split_at(split);
+    let body = StreamBody {
+        items: items.unwrap_or_else(|| Box::new(std::iter::empty())),
+        buf: prefix.to_vec(),
+        pos: 0,
+        first: true,
+        suffix: Some(suffix.to_vec()),
+    };
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    HttpResponse::new(200.into(), vec![content_type], body, None, None)
+}
+
+/// The serialized result of a streamed response before the items are added.
+const RESULT_KEY: &[u8] = br#""result":[]"#;
+
+// returns true if the `If-None-Match` header value lists the quoted etag, compared weakly
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || tag.strip_prefix("W/").unwrap_or(tag) == etag.strip_prefix("W/").unwrap_or(etag)
+    })
+}
+
+// serializes the reply, replacing it with an internal error if it fails to serialize or
+// exceeds `max_response_bytes`
+fn response_body(reply: &Reply, shared: &Shared) -> String {
+    let id = match reply {
+        Reply::Single(response) => response.id.clone(),
+        Reply::Batch(_) | Reply::Empty => None,
+    };
+    let data = serde_json::to_string(reply).unwrap_or_else(|err| {
+        shared.record_error(format!("Error serializing response: {}", err));
+        serialization_error_body(&id)
+    });
+    match shared.config.max_response_bytes {
+        Some(max) if data.len() > max => {
+            shared.record_error(format!(
+                "Response of {} bytes exceeds max_response_bytes",
+                data.len()
+            ));
+            let err = InnerError::ResponseTooLarge(max);
+            let response = Response::from_error(id, err);
+            serde_json::to_string(&response)
+                .unwrap_or_else(|_| serialization_error_body(&response.id))
+        }
+        _ => data,
+    }
+}
+
+// a hand built internal error response, so that the client gets a well-formed response with the
+// original id even if the response fails to serialize
+fn serialization_error_body(id: &Option<Id>) -> String {
+    let id = serde_json::to_string(id).unwrap_or_else(|_| "null".to_string());
+    format!(
+        r#"{{"jsonrpc":"2.0","error":{{"code":{},"message":"Internal error: the response could not be serialized"}},"id":{}}}"#,
+        INTERNAL_ERROR, id
+    )
+}
+
+/// Bodies longer than this are truncated when logged.
+const MAX_LOGGED_BODY: usize = 4096;
+
+fn truncate_for_log(body: &str) -> Cow<'_, str> {
+    if body.len() <= MAX_LOGGED_BODY {
+        return Cow::Borrowed(body);
+    }
+    let mut end = MAX_LOGGED_BODY;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!(
+        "{}... ({} bytes truncated)",
+        &body[..end],
+        body.len() - end
+    ))
+}
+
+/// Debug formats headers hiding the values of the ones carrying credentials.
+struct RedactedHeaders<'a>(&'a [Header]);
+
+impl std::fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|h| {
+                if h.field.equiv("Authorization")
+                    || h.field.equiv("Proxy-Authorization")
+                    || h.field.equiv("Cookie")
+                {
+                    format!("{}: <redacted>", h.field)
+                } else {
+                    h.to_string()
+                }
+            }))
+            .finish()
+    }
+}
+
+// returns the value of the first header with the given field, if any
+fn header_value<'a>(request: &'a tiny_http::Request, field: &'static str) -> Option<&'a str> {
+    find_header(request.headers(), field)
+}
+
+fn find_header<'a>(headers: &'a [Header], field: &'static str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.field.equiv(field))
+        .map(|h| h.value.as_str())
+}
+
+// returns the client IP given by the forwarded headers of a request from a trusted proxy: the last
+// address in `X-Forwarded-For` not of a trusted proxy, the first one if all are, otherwise the
+// `X-Real-IP`. Header lines are combined in order, `None` if an address is invalid.
+fn forwarded_ip(headers: &[Header], proxies: &[IpAddr]) -> Option<IpAddr> {
+    let forwarded: Vec<&str> = headers
+        .iter()
+        .filter(|h| h.field.equiv("X-Forwarded-For"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .map(str::trim)
+        .collect();
+    if forwarded.is_empty() {
+        return find_header(headers, "X-Real-IP")?.trim().parse().ok();
+    }
+    let mut client = None;
+    for value in forwarded.iter().rev() {
+        let ip: IpAddr = value.parse().ok()?;
+        client = Some(ip);
+        if !proxies.contains(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+// returns the quality value the `Accept` header gives to the media type, using the most specific
+// matching range, no `Accept` header accepts everything
+fn accept_quality(request: &tiny_http::Request, media_type: &str) -> f32 {
+    let Some(accept) = header_value(request, "Accept") else {
+        return 1.0;
+    };
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let range = parts.next().unwrap_or_default().trim();
+        let specificity = if range.eq_ignore_ascii_case(media_type) {
+            2
+        } else if range.split_once('/') == Some((kind, "*")) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if best.map_or(true, |(s, _)| specificity > s) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+// returns true if the client prefers an HTML response over a JSON one
+fn prefers_html(request: &tiny_http::Request) -> bool {
+    accept_quality(request, "text/html") > accept_quality(request, "application/json")
+}
+
+// returns true if the `Accept` header explicitly lists the given media type, wildcards are
+// ignored so that generic clients keep receiving JSON
+fn accepts(request: &tiny_http::Request, media_type: &str) -> bool {
+    header_value(request, "Accept").is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let range = range.split(';').next().unwrap_or_default().trim();
+            range.eq_ignore_ascii_case(media_type)
+        })
+    })
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// The request id, `None` if the key is absent, making the request a notification, and
+    /// `Some(Id::Null)` if explicitly `null`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Id>,
+    pub method: String,
+    /// The request params, `None` if the key is absent and `Some(Value::Null)` if explicitly
+    /// `null`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub params: Option<Value>,
+}
+
+// deserializes a present value, including null, as `Some`, absent values default to `None`
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+impl Request {
+    /// Deserializes the params into `T`, absent params deserialize as `null`.
+    ///
+    /// Fails with an invalid params error, which handlers can return as is.
+    pub fn params_as<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        let params = self.params.clone().unwrap_or(Value::Null);
+        serde_json::from_value(params).map_err(|e| InnerError::InvalidParams(e.to_string()).into())
+    }
+
+    /// Deserializes positional params, an array, into `T`, such as a tuple or a `Vec`. Absent
+    /// params deserialize as an empty array.
+    ///
+    /// Fails with an invalid params error if the params are not an array or don't match `T`.
+    pub fn positional_params<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        match &self.params {
+            None => serde_json::from_value(Value::Array(vec![]))
+                .map_err(|e| InnerError::InvalidParams(e.to_string()).into()),
+            Some(Value::Array(_)) => self.params_as(),
+            Some(_) => Err(InnerError::InvalidParams("expected positional params".into()).into()),
+        }
+    }
+
+    /// Deserializes named params, an object, into `T`, such as a struct or a map. Absent params
+    /// deserialize as an empty object.
+    ///
+    /// Fails with an invalid params error if the params are not an object or don't match `T`.
+    pub fn named_params<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        match &self.params {
+            None => serde_json::from_value(Value::Object(Default::default()))
+                .map_err(|e| InnerError::InvalidParams(e.to_string()).into()),
+            Some(Value::Object(_)) => self.params_as(),
+            Some(_) => Err(InnerError::InvalidParams("expected named params".into()).into()),
+        }
+    }
+}
+
+/// A JSON RPC response, serialized with the `jsonrpc`, `result` or `error`, and `id` keys in this
+/// order. Keys of objects within `result` are sorted unless the `preserve_order` feature is
+/// enabled, in which case they keep their insertion order.
+///
+/// Responses to JSON RPC 1.0 requests, see [`Config::jsonrpc_1_compat`], are serialized without
+/// `jsonrpc` and with both `result` and `error`, one of them `null`.
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct Response {
+    #[serde(default = "jsonrpc_1")]
+    pub jsonrpc: String,
+    pub result: Option<Value>,
+    pub error: Option<RpcError>,
+    pub id: Option<Id>,
+    #[serde(rename = "_meta", default)]
+    meta: Option<Value>,
+    #[serde(skip)]
+    pub(crate) http: HttpExtras,
+}
+
+// responses to JSON RPC 1.0 requests have no version
+fn jsonrpc_1() -> String {
+    "1.0".to_string()
+}
+
+impl serde::Serialize for Response {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        if self.http.jsonrpc_1 {
+            match &self.http.stream {
+                Some(stream) => map.serialize_entry("result", stream)?,
+                None => map.serialize_entry("result", &self.result)?,
+            }
+            map.serialize_entry("error", &self.error)?;
+        } else {
+            map.serialize_entry("jsonrpc", &self.jsonrpc)?;
+            match (&self.http.stream, &self.result) {
+                (Some(stream), Some(_)) => map.serialize_entry("result", stream)?,
+                (_, Some(result)) => map.serialize_entry("result", result)?,
+                (_, None) => {}
+            }
+            if let Some(error) = &self.error {
+                map.serialize_entry("error", error)?;
+            }
+        }
+        map.serialize_entry("id", &self.id)?;
+        if let Some(meta) = &self.meta {
+            map.serialize_entry("_meta", meta)?;
+        }
+        map.end()
+    }
+}
+
+/// HTTP level details of a [`Response`], they are not part of the JSON RPC object.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HttpExtras {
+    raw: Option<RawBody>,
+    redirect: Option<Redirect>,
+    etag: Option<String>,
+    stream: Option<ResultStream>,
+    /// Answers a JSON RPC 1.0 request.
+    jsonrpc_1: bool,
+}
+
+/// The items of a streamed result array, taken by the first serialization of the response.
+#[derive(Clone)]
+struct ResultStream(Arc<Mutex<Option<ResultItems>>>);
+
+type ResultItems = Box<dyn Iterator<Item = Value> + Send>;
+
+impl ResultStream {
+    fn take(&self) -> Option<ResultItems> {
+        lock_state(&self.0).take()
+    }
+}
+
+impl std::fmt::Debug for ResultStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResultStream")
+    }
+}
+
+impl serde::Serialize for ResultStream {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.take().into_iter().flatten())
+    }
+}
+
+/// The HTTP body of a response with a streamed result, serializing the items of the result array
+/// as they are read.
+struct StreamBody {
+    items: ResultItems,
+    buf: Vec<u8>,
+    pos: usize,
+    first: bool,
+    suffix: Option<Vec<u8>>,
+}
+
+impl Read for StreamBody {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            match self.items.next() {
+                Some(item) => {
+                    if !self.first {
+                        self.buf.push(b',');
+                    }
+                    self.first = false;
+                    serde_json::to_writer(&mut self.buf, &item)?;
+                }
+                None => match self.suffix.take() {
+                    Some(suffix) => self.buf = suffix,
+                    None => return Ok(0),
+                },
+            }
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Redirect {
+    location: String,
+    permanent: bool,
+}
+
+#[derive(Clone, Debug)]
+struct RawBody {
+    content_type: String,
+    data: Vec<u8>,
+}
+
+impl Response {
+    /// Creates a response from its members, in place of a struct literal since the response
+    /// also carries HTTP details, such as [`Response::with_raw`].
+    pub fn new(
+        jsonrpc: String,
+        id: Option<Id>,
+        result: Option<Value>,
+        error: Option<RpcError>,
+    ) -> Self {
+        Self {
+            jsonrpc,
+            id,
+            result,
+            error,
+            meta: None,
+            http: HttpExtras::default(),
+        }
+    }
+
+    pub fn result(id: Option<Id>, value: Value) -> Self {
+        Self::new("2.0".into(), id, Some(value), None)
+    }
+
+    /// Attaches an extension object with details about the handling of the request, serialized
+    /// as `_meta`. It is replaced by the timing of [`Config::response_timing`] if enabled.
+    pub fn with_meta(mut self, meta: Value) -> Self {
+        self.meta = Some(meta);
+        self
+    }
+
+    /// The `_meta` extension object, such as the `elapsed_ms` of [`Config::response_timing`].
+    pub fn meta(&self) -> Option<&Value> {
+        self.meta.as_ref()
+    }
+
+    /// Creates a result response serializing `value`, failing if it can't be represented as JSON.
+    pub fn ok<S: serde::Serialize>(id: Option<Id>, value: S) -> Result<Self, Error> {
+        let value = serde_json::to_value(value).map_err(InnerError::ResultSerialization)?;
+        Ok(Self::result(id, value))
+    }
+
+    /// Attaches an alternative, non JSON, representation of the result.
+    ///
+    /// The `data` is sent as the HTTP body, with the given `content_type` and without the JSON RPC
+    /// envelope, only to clients explicitly listing `content_type` in their `Accept` header.
+    /// Other clients receive the JSON RPC response as usual.
+    pub fn with_raw(mut self, content_type: &str, data: Vec<u8>) -> Self {
+        self.http.raw = Some(RawBody {
+            content_type: content_type.to_string(),
+            data,
+        });
+        self
+    }
+
+    /// Creates a result response whose result is the array of `items`, serialized as the HTTP
+    /// body is sent with chunked transfer encoding, so that large results, such as thousands of
+    /// transactions, don't need to be held in memory at once.
+    ///
+    /// [`Config::max_response_bytes`] and [`Config::log_bodies`] don't apply to streamed
+    /// results, nor is [`Config::idempotency_header`]. Responses in a batch or sent by other
+    /// transports are serialized as a whole. The items are produced once: the result of a clone
+    /// of the response serialized after the original is empty.
+    pub fn stream<I>(id: Option<Id>, items: I) -> Self
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: Send + 'static,
+    {
+        let mut response = Self::result(id, Value::Array(vec![]));
+        let items: ResultItems = Box::new(items.into_iter());
+        response.http.stream = Some(ResultStream(Arc::new(Mutex::new(Some(items)))));
+        response
+    }
+
+    /// Attaches a version of the result, sent as the `ETag` header. Clients sending it back in
+    /// an `If-None-Match` header receive an empty HTTP status 304 response instead of the
+    /// result, meaning the result they cached for the same call is still current.
+    ///
+    /// The etag must identify the result, for example a hash or a version number, it is quoted
+    /// by the server. Note the cached response carries the id of the earlier request.
+    pub fn with_etag(mut self, etag: &str) -> Self {
+        self.http.etag = Some(etag.to_string());
+        self
+    }
+
+    /// Creates a response redirecting the client to `location`, sent as HTTP status 301 if
+    /// `permanent`, 302 otherwise, with a `Location` header and no JSON body.
+    ///
+    /// Note the client follows the redirect with its own HTTP method, JSON RPC clients may not
+    /// follow it at all.
+    pub fn redirect(location: &str, permanent: bool) -> Self {
+        let mut response = Self::result(None, Value::Null);
+        response.http.redirect = Some(Redirect {
+            location: location.to_string(),
+            permanent,
+        });
+        response
+    }
+
+    pub fn error(id: Option<Id>, code: i64, message: String, data: Option<Value>) -> Self {
+        let err = RpcError {
+            code,
+            message,
+            data,
+        };
+        Self::new("2.0".into(), id, None, Some(err))
+    }
+
+    pub(crate) fn from_error<E: AsRpcError>(id: Option<Id>, error: E) -> Self {
+        Self::new("2.0".into(), id, None, Some(error.as_rpc_error()))
+    }
+
+    /// The response of a long poll method returning without new data because its timeout
+    /// expired, clients are expected to poll again.
+    ///
+    /// The error `data` carries the timeout in milliseconds as `timeout_ms`, so clients can
+    /// adjust their own timeouts. See [`Config::long_poll`].
+    pub fn long_poll_timeout(id: Option<Id>, timeout: Duration) -> Self {
+        Self::from_error(id, InnerError::LongPollTimeout(timeout))
+    }
+
+    pub fn unimplemented(id: Option<Id>, message: String) -> Self {
+        Self::error(id, METHOD_NOT_FOUND, message, None)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    pub fn is_result(&self) -> bool {
+        self.result.is_some()
+    }
+}
+
+/// A JSON RPC error object, handlers can return it as an [`Error`] with any code, such as
+/// `RpcError::new(-1).with_message("Insufficient funds").into()`. Server error codes outside of
+/// [`error::USER_CODES`] are used by this library and become [`error::GENERIC`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    /// Creates an error with `code`, and the message of the pre-defined errors such as
+    /// "Method not found" for [`error::METHOD_NOT_FOUND`], "Server error" otherwise.
+    pub fn new(code: i64) -> Self {
+        let message = match code {
+            error::PARSE_ERROR => "Parse error",
+            error::INVALID_REQUEST => "Invalid Request",
+            error::METHOD_NOT_FOUND => "Method not found",
+            error::INVALID_PARAMS => "Invalid params",
+            error::INTERNAL_ERROR => "Internal error",
+            _ => "Server error",
+        };
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn data(&self) -> Option<&Value> {
+        self.data.as_ref()
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A request id, a string, a number, including fractional ones, or null.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Id {
+    Null,
+    Number(serde_json::Number),
+    String(String),
+}
+
+// deserialized through a `Value` rather than untagged, which doesn't support the numbers of the
+// `arbitrary_precision` feature
+impl<'de> serde::Deserialize<'de> for Id {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::Null => Ok(Id::Null),
+            Value::Number(n) => Ok(Id::Number(n)),
+            Value::String(s) => Ok(Id::String(s)),
+            other => Err(serde::de::Error::invalid_type(
+                unexpected(&other),
+                &"a string, a number or null",
+            )),
+        }
+    }
+}
+
+fn unexpected(value: &Value) -> serde::de::Unexpected<'_> {
+    use serde::de::Unexpected;
+    match value {
+        Value::Bool(b) => Unexpected::Bool(*b),
+        Value::Array(_) => Unexpected::Seq,
+        Value::Object(_) => Unexpected::Map,
+        _ => Unexpected::Other("value"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, io::Write, path::PathBuf, sync::mpsc};
+
+    use super::*;
+    use jsonrpc::Client;
+    use serde_json::{json, value::to_raw_value};
+    use tiny_http::Server;
+
+    fn process(request: Request, _state: Arc<Mutex<()>>) -> Result<Response, Error> {
+        let response = match request.method.as_str() {
+            "echo" => Response::new(request.jsonrpc, request.id, request.params, None),
+            _ => unimplemented!(),
+        };
+        Ok(response)
+    }
+
+    #[test]
+    fn echo() {
+        let addr = "127.0.0.1:0";
+        let server = Server::http(addr).unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let mut rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        let url = format!("127.0.0.1:{}", port);
+
+        let client = Client::simple_http(&url, None, None).unwrap();
+        let val = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+        let params = to_raw_value(val).unwrap();
+        let request = client.build_request("echo", Some(&params));
+        let req = request.clone();
+
+        let response = client.send_request(request).unwrap();
+
+        assert_eq!(response.id, req.id);
+        assert_eq!(response.jsonrpc.unwrap().as_str(), req.jsonrpc.unwrap());
+        let result = response.result.unwrap();
+        let expected = serde_json::to_string(&json!(params)).unwrap();
+        assert_eq!(result.get(), expected.as_str());
+
+        rpc.stop();
+        rpc.join_threads();
+    }
+
+    #[test]
+    fn rpc_dot_reserved() {
+        let addr = "127.0.0.1:0";
+        let server = Server::http(addr).unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        let url = format!("127.0.0.1:{}", port);
+
+        let client = Client::simple_http(&url, None, None).unwrap();
+        let request = client.build_request("rpc.reserved", None);
+
+        let response = client.send_request(request).unwrap();
+        assert!(response.error.is_some());
+    }
+
+    #[test]
+    fn response_serialization() {
+        // result response must not include error key
+        let response = Response::new(
+            "2.0".into(),
+            Some(Id::Number(123.into())),
+            Some(Value::Bool(true)),
+            None,
+        );
+        let actual = serde_json::to_value(response).unwrap();
+        let expected = json!({
+            "jsonrpc": "2.0",
+            "result": true,
+            "id": 123,
+        });
+        assert_eq!(actual, expected);
+        assert!(actual.get("error").is_none());
+
+        // error response must not include result key
+        let error = RpcError {
+            code: -32_000,
+            message: "Sunlifter".into(),
+            data: None,
+        };
+        let response = Response::new(
+            "2.0".into(),
+            Some(Id::Number(123.into())),
+            None,
+            Some(error),
+        );
+        let actual = serde_json::to_value(response).unwrap();
+        let expected = json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -32000,
+                "message": "Sunlifter",
+                "data": null,
+            },
+            "id": 123,
+        });
+        assert_eq!(actual, expected);
+        assert!(actual.get("result").is_none());
+    }
+
+    #[test]
+    fn response_ok() {
+        #[derive(Serialize)]
+        struct Balance {
+            asset: String,
+            amount: u64,
+        }
+        let balance = Balance {
+            asset: "L-BTC".into(),
+            amount: 21,
+        };
+        let response = Response::ok(Some(Id::Number(1.into())), balance).unwrap();
+        assert_eq!(response.jsonrpc, "2.0");
+        assert_eq!(
+            response.result.unwrap(),
+            json!({"asset": "L-BTC", "amount": 21})
+        );
+
+        // maps with non string keys can't be serialized
+        let map = HashMap::from([((1, 2), 3)]);
+        assert!(Response::ok(None, map).is_err());
+    }
+
+    #[test]
+    fn result_serialization_error() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        // maps with non string keys can't be serialized
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            Response::ok(request.id, HashMap::from([((1, 2), 3)]))
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc": "2.0", "id": 7, "method": "m"}),
+        );
+        let response: Response = response.json().unwrap();
+        assert_eq!(json!(response.id), json!(7));
+        assert_eq!(response.error.unwrap().code, -32_603);
+
+        // the fallback body is a valid response
+        let id = Some(Id::String("a\"b".to_string()));
+        let response: Response = serde_json::from_str(&serialization_error_body(&id)).unwrap();
+        assert_eq!(json!(response.id), json!("a\"b"));
+        assert_eq!(response.error.unwrap().code, -32_603);
+    }
+
+    #[test]
+    fn reentrancy_guard() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let state = Arc::new(Mutex::new(()));
+        // calls back into the server, nested calls would eventually hold all the workers
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            let response = reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", port))
+                .json(&json!({"jsonrpc": "2.0", "id": 2, "method": "inner"}))
+                .timeout(Duration::from_secs(5))
+                .send()
+                .unwrap();
+            Response::ok(request.id, response.status().as_u16())
+        };
+        let config = Config::builder()
+            .with_num_threads(std::num::NonZeroU8::new(2).unwrap())
+            .with_reentrancy_guard(true)
+            .build();
+        let _rpc = JsonRpcServer::new(server, config, state, process);
+        let response = post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "outer"}));
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.result.unwrap(), json!(508));
+    }
+
+    #[test]
+    fn etag() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(21u64));
+        let process = |request: Request, state: Arc<Mutex<u64>>| {
+            let balance = *lock_state(&state);
+            Ok(Response::result(request.id, json!(balance)).with_etag(&balance.to_string()))
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state.clone(), process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "balance"});
+        let client = reqwest::blocking::Client::new();
+        let balance = |etag: Option<&str>| {
+            let mut request = client.post(&url).json(&body);
+            if let Some(etag) = etag {
+                request = request.header("If-None-Match", etag);
+            }
+            request.send().unwrap()
+        };
+
+        let response = balance(None);
+        assert_eq!(response.status(), 200);
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        assert_eq!(etag, "\"21\"");
+        assert_eq!(
+            response.json::<Response>().unwrap().result.unwrap(),
+            json!(21)
+        );
+
+        // unchanged
+        let response = balance(Some(&etag));
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()["ETag"], etag.as_str());
+        assert!(response.bytes().unwrap().is_empty());
+
+        // changed
+        *lock_state(&state) = 42;
+        let response = balance(Some(&etag));
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["ETag"], "\"42\"");
+        assert_eq!(
+            response.json::<Response>().unwrap().result.unwrap(),
+            json!(42)
+        );
+
+        assert!(etag_matches("\"1\", W/\"42\"", "\"42\""));
+        assert!(etag_matches("*", "\"42\""));
+        assert!(!etag_matches("\"4\"", "\"42\""));
+    }
+
+    #[test]
+    fn redirect() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            Ok(Response::redirect("/new", request.method == "moved"))
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        for (method, status) in [("moved", 301), ("found", 302)] {
+            let response = client
+                .post(format!("http://127.0.0.1:{}", rpc.port().unwrap()))
+                .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method}))
+                .send()
+                .unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers()["Location"], "/new");
+            assert!(response.bytes().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn lenient_content_type() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[1]}"#;
+        let post_text = |port: u16| {
+            reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", port))
+                .header("Content-Type", "text/plain")
+                .body(body)
+                .send()
+                .unwrap()
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let response: Response = post_text(rpc.port().unwrap()).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_002);
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_require_json_content_type(false)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let response: Response = post_text(rpc.port().unwrap()).json().unwrap();
+        assert!(response.error.is_none());
+        assert_eq!(response.result.unwrap(), json!([1]));
+    }
+
+    #[test]
+    fn content_type_parameters() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[1]}"#;
+        let post_with = |port: u16, content_type: &str| -> Response {
+            reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", port))
+                .header("Content-Type", content_type)
+                .body(body)
+                .send()
+                .unwrap()
+                .json()
+                .unwrap()
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        for accepted in [
+            "application/json; charset=utf-8",
+            "Application/JSON ;charset=\"UTF-8\"",
+        ] {
+            assert!(post_with(port, accepted).error.is_none(), "{}", accepted);
+        }
+        for rejected in [
+            "application/json-rpc",
+            "application/jsonx",
+            "text/plain; note=application/json",
+        ] {
+            let response = post_with(port, rejected);
+            assert_eq!(response.error.unwrap().code, -32_002, "{}", rejected);
+        }
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_accept_json_rpc_content_type(true)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let response = post_with(rpc.port().unwrap(), "application/json-rpc; charset=utf-8");
+        assert_eq!(response.result.unwrap(), json!([1]));
+    }
+
+    #[test]
+    fn invalid_request_or_json() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let code = |body: &str| {
+            let response: Response = reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", rpc.port().unwrap()))
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .send()
+                .unwrap()
+                .json()
+                .unwrap();
+            response.error.unwrap().code
+        };
+        assert_eq!(code("42"), -32_600);
+        assert_eq!(code(r#""request""#), -32_600);
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1}"#), -32_600);
+        assert_eq!(code("{bad}"), -32_700);
+        assert_eq!(code(r#"{"jsonrpc":"2.0","#), -32_700);
+    }
+
+    #[test]
+    fn batch() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["a"]},
+            1,
+            {"jsonrpc": "1.0", "id": 2, "method": "echo"},
+            {"jsonrpc": "2.0", "id": "3", "method": "echo", "params": {"b": 2}},
+        ]);
+        let response = post(port, &batch);
+        assert_eq!(response.status(), 200);
+        let responses: Vec<Response> = response.json().unwrap();
+        assert_eq!(responses.len(), 4);
+        assert_eq!(json!(responses[0].id), json!(1));
+        assert_eq!(responses[0].result.as_ref().unwrap(), &json!(["a"]));
+        assert_eq!(json!(responses[1].id), json!(null));
+        assert_eq!(responses[1].error.as_ref().unwrap().code, -32_600);
+        assert_eq!(json!(responses[2].id), json!(2));
+        assert_eq!(responses[2].error.as_ref().unwrap().code, -32_004);
+        assert_eq!(json!(responses[3].id), json!("3"));
+        assert_eq!(responses[3].result.as_ref().unwrap(), &json!({"b": 2}));
+
+        // an empty batch or invalid JSON get a single error
+        let response: Response = post(port, &json!([])).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_600);
+        let response = reqwest::blocking::Client::new()
+            .post(format!("http://127.0.0.1:{}", port))
+            .header("Content-Type", "application/json")
+            .body("[{\"jsonrpc\": \"2.0\", \"method\": \"echo\"},")
+            .send()
+            .unwrap();
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_700);
+    }
+
+    #[test]
+    fn notification() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(0));
+        let process = |request: Request, state: Arc<Mutex<u64>>| {
+            *state.lock().unwrap() += 1;
+            Ok(Response::result(request.id, json!(null)))
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state.clone(), process);
+        let port = rpc.port().unwrap();
+
+        // the handler is called but no body is returned
+        let response = post(port, &json!({"jsonrpc": "2.0", "method": "inc"}));
+        assert_eq!(response.status(), 204);
+        assert!(response.text().unwrap().is_empty());
+        assert_eq!(*state.lock().unwrap(), 1);
+
+        // notifications are omitted from batch responses
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "inc"},
+            {"jsonrpc": "2.0", "id": 1, "method": "inc"},
+        ]);
+        let responses: Vec<Response> = post(port, &batch).json().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(json!(responses[0].id), json!(1));
+        assert_eq!(*state.lock().unwrap(), 3);
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "inc"},
+            {"jsonrpc": "2.0", "method": "inc"},
+        ]);
+        let response = post(port, &batch);
+        assert_eq!(response.status(), 204);
+        assert!(response.text().unwrap().is_empty());
+        assert_eq!(*state.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn router() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let mut router = Router::new();
+        router.register("concat", |params: Vec<String>, _state| Ok(params.concat()));
+        let rpc = JsonRpcServer::new_with_router(server, Config::default(), state, router);
+        let port = rpc.port().unwrap();
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "concat", "params": ["a", "b"]});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(response.result.unwrap(), json!("ab"));
+
+        let request = json!({"jsonrpc": "2.0", "id": 2, "method": "split", "params": []});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_601);
+
+        // only a router with the openrpc feature answers the discovery
+        let request = json!({"jsonrpc": "2.0", "id": 3, "method": "rpc.discover"});
+        let response: Response = post(port, &request).json().unwrap();
+        if cfg!(feature = "openrpc") {
+            assert!(response.result.unwrap()["methods"].is_array());
+        } else {
+            assert_eq!(response.error.unwrap().code, -32_003);
+        }
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let response: Response = post(rpc.port().unwrap(), &request).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_003);
+    }
+
+    #[test]
+    fn middleware() {
+        use crate::middleware::Middleware;
+
+        struct Alias;
+        impl Middleware for Alias {
+            fn before(&self, mut request: Request) -> Result<Request, Error> {
+                if request.method == "say" {
+                    request.method = "echo".into();
+                }
+                Ok(request)
+            }
+        }
+        struct Deny(Arc<Mutex<Vec<String>>>);
+        impl Middleware for Deny {
+            fn before(&self, request: Request) -> Result<Request, Error> {
+                lock_state(&self.0).push(format!("before {}", request.method));
+                match request.method.as_str() {
+                    "echo" => Ok(request),
+                    method => Err(format!("{} denied", method).into()),
+                }
+            }
+            fn after(&self, mut response: Response) -> Response {
+                lock_state(&self.0).push("after".into());
+                if let Some(result) = response.result.take() {
+                    response.result = Some(json!({"wrapped": result}));
+                }
+                response
+            }
+        }
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let log = Arc::new(Mutex::new(vec![]));
+        let config = Config::builder()
+            .with_middleware(Alias)
+            .with_middleware(Deny(log.clone()))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, Arc::new(Mutex::new(())), process);
+        let port = rpc.port().unwrap();
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "say", "params": [1]});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(response.result.unwrap(), json!({"wrapped": [1]}));
+
+        // a rejected request reaches neither the handler nor the after hooks of the rejecting
+        // middleware
+        let request = json!({"jsonrpc": "2.0", "id": 2, "method": "drop", "params": [1]});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(json!(response.id), json!(2));
+        assert!(response.error.unwrap().message.contains("drop denied"));
+        assert_eq!(
+            *lock_state(&log),
+            vec!["before echo", "after", "before drop"]
+        );
+    }
+
+    #[test]
+    fn request_context() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, context: &RequestContext, _state| {
+            let result = json!({
+                "ip": context.remote_addr.map(|addr| addr.ip().to_string()),
+                "url": context.url,
+                "agent": context.header("x-agent"),
+                "received": context.received_at <= std::time::SystemTime::now(),
+            });
+            Ok(Response::result(request.id, result))
+        };
+        let rpc = JsonRpcServer::new_with_context(server, Config::default(), state, process);
+        let response = reqwest::blocking::Client::new()
+            .post(format!("http://127.0.0.1:{}/rpc?x=1", rpc.port().unwrap()))
+            .header("Content-Type", "application/json")
+            .header("X-Agent", "test")
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "context"}))
+            .send()
+            .unwrap();
+        let response: Response = response.json().unwrap();
+        assert_eq!(
+            response.result.unwrap(),
+            json!({"ip": "127.0.0.1", "url": "/rpc?x=1", "agent": "test", "received": true})
+        );
+    }
+
+    #[test]
+    fn ids() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        // null and fractional ids are answered with the same id
+        for id in [json!(null), json!(1.5), json!(-3), json!("a")] {
+            let request = json!({"jsonrpc": "2.0", "id": id, "method": "echo", "params": []});
+            let response = post(port, &request);
+            assert_eq!(response.status(), 200);
+            let response: Value = response.json().unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], json!([]));
+        }
+
+        let request: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":null,"method":"m"}"#).unwrap();
+        assert_eq!(request.id, Some(Id::Null));
+        let request: Request = serde_json::from_str(r#"{"jsonrpc":"2.0","method":"m"}"#).unwrap();
+        assert_eq!(request.id, None);
+
+        let request = json!({"jsonrpc": "2.0", "id": [1], "method": "echo"});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_600);
+    }
+
+    #[test]
+    fn builtin_methods() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state.clone(), process);
+        let port = rpc.port().unwrap();
+        let call = |port, method| -> Response {
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": method});
+            post(port, &request).json().unwrap()
+        };
+
+        assert_eq!(call(port, "rpc.ping").result.unwrap(), json!("pong"));
+        let version = call(port, "rpc.version").result.unwrap();
+        assert_eq!(version, json!(env!("CARGO_PKG_VERSION")));
+        assert!(call(port, "rpc.uptime").result.unwrap().is_u64());
+        // the worker answering is busy
+        let threads = call(port, "rpc.threads").result.unwrap();
+        assert_eq!(threads, json!({"num_threads": 4, "busy": 1}));
+        assert_eq!(call(port, "rpc.other").error.unwrap().code, -32_003);
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let config = Config::builder().with_builtin_methods(false).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let response = call(rpc.port().unwrap(), "rpc.ping");
+        assert_eq!(response.error.unwrap().code, -32_003);
+    }
+
+    #[test]
+    fn cancel() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, context: &RequestContext, _state| {
+            while !context.cancellation.is_cancelled() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(Response::result(request.id, json!("cancelled")))
+        };
+        // the proxy lets the test act as another client
+        let config = Config::builder()
+            .with_trusted_proxy("127.0.0.1".parse().unwrap())
+            .build();
+        let rpc = JsonRpcServer::new_with_context(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let scan = thread::spawn(move || {
+            let request = json!({"jsonrpc": "2.0", "id": "scan", "method": "scan"});
+            post(port, &request).json::<Response>().unwrap()
+        });
+
+        let cancel = |params| -> Response {
+            let request =
+                json!({"jsonrpc": "2.0", "id": 1, "method": "rpc.cancel", "params": params});
+            post(port, &request).json().unwrap()
+        };
+        assert_eq!(cancel(json!(["other"])).result.unwrap(), json!(false));
+        assert_eq!(cancel(json!("scan")).error.unwrap().code, -32_602);
+
+        // other clients can't cancel the request, even knowing its id
+        let request =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "rpc.cancel", "params": ["scan"]});
+        for _ in 0..5 {
+            let response: Response = reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", port))
+                .header("X-Forwarded-For", "6.6.6.6")
+                .json(&request)
+                .send()
+                .unwrap()
+                .json()
+                .unwrap();
+            assert_eq!(response.result.unwrap(), json!(false));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!scan.is_finished());
+
+        while cancel(json!({"id": "scan"})).result.unwrap() == json!(false) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(scan.join().unwrap().result.unwrap(), json!("cancelled"));
+        assert_eq!(cancel(json!(["scan"])).result.unwrap(), json!(false));
+    }
+
+    #[test]
+    fn response_timing() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state| {
+            thread::sleep(Duration::from_millis(50));
+            Ok(Response::result(request.id, json!(null)))
+        };
+        let config = Config::builder().with_response_timing(true).build();
+        let rpc = JsonRpcServer::new(server, config, state.clone(), process);
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "sleep"});
+        let response: Response = post(rpc.port().unwrap(), &request).json().unwrap();
+        let elapsed_ms = response.meta().unwrap()["elapsed_ms"].as_u64().unwrap();
+        assert!((50..1000).contains(&elapsed_ms), "{}", elapsed_ms);
+        let actual = serde_json::to_value(response.with_meta(json!({"cached": true}))).unwrap();
+        assert_eq!(actual["_meta"], json!({"cached": true}));
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let response: Value = post(rpc.port().unwrap(), &request).json().unwrap();
+        assert!(response.get("_meta").is_none());
+    }
+
+    #[test]
+    fn strict_validation() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_deny_unknown_fields(true)
+            .with_require_id(true)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state.clone(), process);
+        let port = rpc.port().unwrap();
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "param": [1]});
+        let response: Response = post(port, &request).json().unwrap();
+        let error = response.error.unwrap();
+        assert_eq!(error.code, -32_600);
+        assert!(error.message.contains("'param'"), "{}", error.message);
+
+        let request = json!({"jsonrpc": "2.0", "method": "echo", "params": [1]});
+        let response = post(port, &request);
+        assert_eq!(response.status(), 200);
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_600);
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1]});
+        let response: Response = post(port, &request).json().unwrap();
+        assert_eq!(response.result.unwrap(), json!([1]));
+
+        // lenient by default
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "param": [1]});
+        let response: Response = post(rpc.port().unwrap(), &request).json().unwrap();
+        assert!(response.error.is_none());
+    }
+
+    #[test]
+    fn jsonrpc_1_compat() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_jsonrpc_1_compat(true).build();
+        let rpc = JsonRpcServer::new(server, config, state.clone(), process);
+        let port = rpc.port().unwrap();
+
+        for request in [
+            json!({"id": 1, "method": "echo", "params": [1]}),
+            json!({"jsonrpc": "1.0", "id": 1, "method": "echo", "params": [1]}),
+        ] {
+            let response: Value = post(port, &request).json().unwrap();
+            assert_eq!(response, json!({"result": [1], "error": null, "id": 1}));
+        }
+        let request = json!({"id": 2, "method": "rpc.other", "params": []});
+        let response: Value = post(port, &request).json().unwrap();
+        assert_eq!(response["result"], json!(null));
+        assert_eq!(response["error"]["code"], json!(-32_003));
+        assert_eq!(response["id"], json!(2));
+        assert!(response.get("jsonrpc").is_none());
+
+        // 1.0 notifications have a null id
+        let request = json!({"id": null, "method": "echo", "params": []});
+        assert_eq!(post(port, &request).status(), 204);
+
+        // 2.0 requests are unchanged
+        let request = json!({"jsonrpc": "2.0", "id": null, "method": "echo", "params": [1]});
+        let response: Value = post(port, &request).json().unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "result": [1], "id": null})
+        );
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let request = json!({"jsonrpc": "1.0", "id": 1, "method": "echo", "params": [1]});
+        let response: Response = post(rpc.port().unwrap(), &request).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_004);
+    }
+
+    #[test]
+    fn idempotency_key() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(0));
+        let process = |request: Request, state: Arc<Mutex<u64>>| {
+            let mut sent = state.lock().unwrap();
+            *sent += 1;
+            Ok(Response::result(request.id, json!(*sent)))
+        };
+        let config = Config::builder()
+            .with_idempotency("Idempotency-Key", Duration::from_secs(60))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state.clone(), process);
+        let send = |key: Option<&str>| -> Response {
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": "send"});
+            let mut builder = reqwest::blocking::Client::new()
+                .post(format!("http://127.0.0.1:{}", rpc.port().unwrap()))
+                .json(&request);
+            if let Some(key) = key {
+                builder = builder.header("Idempotency-Key", key);
+            }
+            builder.send().unwrap().json().unwrap()
+        };
+
+        assert_eq!(send(Some("a")).result.unwrap(), json!(1));
+        assert_eq!(send(Some("a")).result.unwrap(), json!(1));
+        assert_eq!(send(Some("b")).result.unwrap(), json!(2));
+        assert_eq!(send(None).result.unwrap(), json!(3));
+        assert_eq!(send(None).result.unwrap(), json!(4));
+        assert_eq!(*state.lock().unwrap(), 4);
+
+        // keys are scoped to the credentials of the client
+        let response: Response = reqwest::blocking::Client::new()
+            .post(format!("http://127.0.0.1:{}", rpc.port().unwrap()))
+            .header("Idempotency-Key", "a")
+            .header("Authorization", "Bearer other")
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "send"}))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(response.result.unwrap(), json!(5));
+
+        // reusing a key for other params is a conflict
+        let response = reqwest::blocking::Client::new()
+            .post(format!("http://127.0.0.1:{}", rpc.port().unwrap()))
+            .header("Idempotency-Key", "a")
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "send", "params": [1]}))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 409);
+        assert_eq!(*state.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn batch_order() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        // earlier requests take longer, completing in reverse order
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            let delay = request.params_as::<u64>()?;
+            thread::sleep(Duration::from_millis(delay));
+            Ok(Response::result(request.id, json!(delay)))
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let delays = [200, 150, 100, 50, 0];
+        let batch: Vec<_> = delays
+            .iter()
+            .enumerate()
+            .map(
+                |(i, delay)| json!({"jsonrpc": "2.0", "id": i, "method": "sleep", "params": delay}),
+            )
+            .collect();
+        let start = Instant::now();
+        let responses: Vec<Response> = post(rpc.port().unwrap(), &json!(batch)).json().unwrap();
+        // handled in parallel
+        assert!(start.elapsed() < Duration::from_millis(450));
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(json!(response.id), json!(i));
+            assert_eq!(response.result.as_ref().unwrap(), &json!(delays[i]));
+        }
+    }
+
+    #[test]
+    fn max_batch_size() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_max_batch_size(Some(2)).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo"});
+        let batch = json!([request, request, request]);
+        let response = post(rpc.port().unwrap(), &batch);
+        assert_eq!(response.status(), 413);
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_600);
+    }
+
+    #[test]
+    fn max_response_bytes() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            let len = request.params_as::<usize>()?;
+            Ok(Response::result(request.id, json!(vec![0; len])))
+        };
+        let config = Config::builder()
+            .with_max_response_bytes(Some(1024))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+
+        let response = post(
+            port,
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "m", "params": 10}),
+        );
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.result.unwrap(), json!(vec![0; 10]));
+
+        let response = post(
+            port,
+            &json!({"jsonrpc": "2.0", "id": 2, "method": "m", "params": 100_000}),
+        );
+        let response: Response = response.json().unwrap();
+        assert_eq!(json!(response.id), json!(2));
+        assert_eq!(response.error.unwrap().code, -32_603);
+        assert!(rpc.recent_errors()[0].ends_with("bytes exceeds max_response_bytes"));
+    }
+
+    #[test]
+    fn params_null() {
+        let absent: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"method":"m"}"#).unwrap();
+        let null: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"method":"m","params":null}"#).unwrap();
+        assert_eq!(absent.params, None);
+        assert_eq!(null.params, Some(Value::Null));
+        assert_eq!(absent.params_as::<Option<u32>>().unwrap(), None);
+        assert_eq!(null.params_as::<Option<u32>>().unwrap(), None);
+
+        // absent params stay absent when serialized
+        let json = serde_json::to_value(&absent).unwrap();
+        assert!(json.get("params").is_none());
+        let json = serde_json::to_value(&null).unwrap();
+        assert_eq!(json.get("params"), Some(&Value::Null));
+
+        let request: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"method":"m","params":["a"]}"#)
+                .unwrap();
+        assert_eq!(request.params_as::<Vec<String>>().unwrap(), vec!["a"]);
+        let err = request.params_as::<Vec<u32>>().unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+    }
+
+    #[test]
+    fn positional_and_named_params() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Named {
+            a: u32,
+            #[serde(default)]
+            b: Option<String>,
+        }
+        let request = |params: &str| -> Request {
+            let json = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"m"{}}}"#, params);
+            serde_json::from_str(&json).unwrap()
+        };
+
+        let positional = request(r#","params":[1,"x"]"#);
+        let (a, b): (u32, String) = positional.positional_params().unwrap();
+        assert_eq!((a, b.as_str()), (1, "x"));
+        let err = positional.named_params::<Named>().unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+        let err = positional.positional_params::<(String,)>().unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+
+        let named = request(r#","params":{"a":1}"#);
+        assert_eq!(
+            named.named_params::<Named>().unwrap(),
+            Named { a: 1, b: None }
+        );
+        let err = named.positional_params::<Vec<u32>>().unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+
+        let absent = request("");
+        assert!(absent.positional_params::<Vec<u32>>().unwrap().is_empty());
+        assert!(absent
+            .named_params::<HashMap<String, u32>>()
+            .unwrap()
+            .is_empty());
+        let err = absent.named_params::<Named>().unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+    }
+
+    #[test]
+    fn rpc_error() {
+        use crate::error::{is_reserved_code, INVALID_PARAMS, METHOD_NOT_FOUND};
+
+        let err = RpcError::new(METHOD_NOT_FOUND);
+        assert_eq!(err.message(), "Method not found");
+        assert_eq!(err.data(), None);
+        assert!(is_reserved_code(err.code()));
+
+        let err = RpcError::new(-1)
+            .with_message("Insufficient funds")
+            .with_data(json!({"missing": 42}));
+        assert!(!is_reserved_code(err.code()));
+        let response = Response::from_error(Some(Id::Number(1.into())), Error::from(err));
+        let actual = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            actual["error"],
+            json!({"code": -1, "message": "Insufficient funds", "data": {"missing": 42}})
+        );
+
+        assert_eq!(RpcError::new(INVALID_PARAMS).message(), "Invalid params");
+        assert_eq!(RpcError::new(-32_050).message(), "Server error");
+    }
+
+    #[test]
+    fn response_key_order() {
+        let response = Response::result(Some(Id::Number(1.into())), json!(true));
+        let actual = serde_json::to_string(&response).unwrap();
+        assert_eq!(actual, r#"{"jsonrpc":"2.0","result":true,"id":1}"#);
+
+        let response = Response::error(
+            Some(Id::Number(1.into())),
+            -32_000,
+            "Sunlifter".into(),
+            None,
+        );
+        let actual = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            actual,
+            r#"{"jsonrpc":"2.0","error":{"code":-32000,"message":"Sunlifter","data":null},"id":1}"#
+        );
+
+        let result: Value = serde_json::from_str(r#"{"b":1,"a":2}"#).unwrap();
+        let response = Response::result(Some(Id::Number(1.into())), result);
+        let actual = serde_json::to_string(&response).unwrap();
+        let expected = if cfg!(feature = "preserve_order") {
+            r#"{"jsonrpc":"2.0","result":{"b":1,"a":2},"id":1}"#
+        } else {
+            r#"{"jsonrpc":"2.0","result":{"a":2,"b":1},"id":1}"#
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn http_options() {
+        let addr = "127.0.0.1:0";
+        let server = Server::http(addr).unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config {
+            headers: vec![
+                Header::from_str("Access-Control-Allow-Origin: http://127.0.0.1:8000").unwrap(),
+                Header::from_str("Access-Control-Allow-Headers: content-type").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let url = format!("http://127.0.0.1:{}", port);
+
+        let client = reqwest::blocking::Client::builder().build().unwrap();
+
+        let resp = client
+            .request(reqwest::Method::OPTIONS, url)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 204);
+        assert_eq!(
+            resp.headers().get("allow").unwrap(),
+            "GET, HEAD, POST, OPTIONS"
+        );
+        assert_eq!(
+            resp.headers().get("access-control-allow-origin").unwrap(),
+            "http://127.0.0.1:8000"
+        );
+        assert_eq!(
+            resp.headers().get("access-control-allow-headers").unwrap(),
+            "content-type"
+        );
+        assert!(resp.bytes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cors() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let cors = config::CorsPolicy::origins(["https://wallet.example/"])
+            .with_allowed_header("Authorization")
+            .with_max_age(Duration::from_secs(600));
+        let config = Config::builder().with_cors(cors).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let client = reqwest::blocking::Client::new();
+
+        let preflight = |origin: &str| {
+            client
+                .request(reqwest::Method::OPTIONS, &url)
+                .header("Origin", origin)
+                .header("Access-Control-Request-Method", "POST")
+                .header("Access-Control-Request-Headers", "content-type")
+                .send()
+                .unwrap()
+        };
+        let resp = preflight("https://wallet.example");
+        assert_eq!(resp.status(), 204);
+        let headers = resp.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://wallet.example"
+        );
+        assert_eq!(headers["vary"], "Origin");
+        assert_eq!(
+            headers["access-control-allow-methods"],
+            "GET, HEAD, POST, OPTIONS"
+        );
+        assert_eq!(
+            headers["access-control-allow-headers"],
+            "Content-Type, Authorization"
+        );
+        assert_eq!(headers["access-control-max-age"], "600");
+
+        let resp = preflight("https://evil.example");
+        assert_eq!(resp.status(), 204);
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+        assert!(resp.headers().get("access-control-allow-methods").is_none());
+
+        let post = |origin: &str| {
+            client
+                .post(&url)
+                .header("Origin", origin)
+                .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1]}))
+                .send()
+                .unwrap()
+        };
+        let resp = post("HTTPS://WALLET.EXAMPLE");
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "HTTPS://WALLET.EXAMPLE"
+        );
+        let resp = post("https://wallet.example/");
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://wallet.example/"
+        );
+        let cors = config::CorsPolicy::origins(["https://wallet.example"]);
+        assert!(cors.allows("https://wallet.example/"));
+        assert!(!cors.allows("https://wallet.example.evil"));
+        let resp = post("https://evil.example");
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.result.unwrap(), json!([1]));
+
+        // any origin is reflected, not answered with `*`
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_cors(config::CorsPolicy::any_origin())
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let resp = reqwest::blocking::Client::new()
+            .get(format!("http://127.0.0.1:{}/", rpc.port().unwrap()))
+            .header("Origin", "http://localhost:3000")
+            .send()
+            .unwrap();
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "http://localhost:3000"
+        );
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let headers = |lines: &[&str]| -> Vec<Header> {
+            lines.iter().map(|l| Header::from_str(l).unwrap()).collect()
+        };
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        let proxies = [proxy];
+        let forwarded = |lines: &[&str]| forwarded_ip(&headers(lines), &proxies);
+        assert_eq!(forwarded(&["X-Forwarded-For: 1.2.3.4"]), ip("1.2.3.4"));
+        // the client can prepend anything, only the address added by the proxy is trusted
+        assert_eq!(
+            forwarded(&["X-Forwarded-For: 6.6.6.6, 1.2.3.4, 10.0.0.1"]),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            forwarded(&["X-Forwarded-For: 6.6.6.6", "X-Forwarded-For: 2001:db8::1"]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(forwarded(&["X-Forwarded-For: 10.0.0.1"]), ip("10.0.0.1"));
+        assert_eq!(forwarded(&["X-Real-IP: 1.2.3.4"]), ip("1.2.3.4"));
+        assert_eq!(forwarded(&["X-Forwarded-For: 1.2.3.4, unknown"]), None);
+        assert_eq!(forwarded(&[]), None);
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_trusted_proxy("127.0.0.1".parse().unwrap())
+            .with_accept_filter(|ip| ip != "6.6.6.6".parse::<IpAddr>().unwrap())
+            .build();
+        let rpc = JsonRpcServer::new_with_context(server, config, state, |request, context, _| {
+            let ip = context.remote_addr.map(|addr| addr.ip().to_string());
+            Ok(Response::result(request.id, json!(ip)))
+        });
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let client = reqwest::blocking::Client::new();
+        let post = |header: Option<(&str, &str)>| {
+            let mut request = client
+                .post(&url)
+                .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "ip"}));
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.send().unwrap()
+        };
+        let ip_of = |header| {
+            let response: Response = post(header).json().unwrap();
+            response.result.unwrap()
+        };
+        assert_eq!(ip_of(None), json!("127.0.0.1"));
+        assert_eq!(
+            ip_of(Some(("X-Forwarded-For", "1.2.3.4"))),
+            json!("1.2.3.4")
+        );
+        assert_eq!(ip_of(Some(("X-Real-IP", "5.6.7.8"))), json!("5.6.7.8"));
+        let resp = post(Some(("X-Forwarded-For", "6.6.6.6")));
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[test]
+    fn http_options_paths() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_rpc_path(Some("/rpc")).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+
+        let allow = |target: &str| {
+            let response = raw_http(
+                port,
+                &format!(
+                    "OPTIONS {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    target
+                ),
+            );
+            assert!(response.starts_with("HTTP/1.1 204"));
+            response
+                .lines()
+                .find_map(|l| l.strip_prefix("Allow: "))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(allow("/rpc"), "POST, OPTIONS");
+        assert_eq!(allow("/rpc?x=1"), "POST, OPTIONS");
+        assert_eq!(allow("/index.html"), "GET, HEAD, OPTIONS");
+        assert_eq!(allow("*"), "GET, HEAD, POST, OPTIONS");
+
+        // GET to the rpc path is not allowed
+        let resp = reqwest::blocking::get(format!("http://127.0.0.1:{}/rpc", port)).unwrap();
+        assert_eq!(resp.status(), 405);
+        assert_eq!(resp.headers().get("allow").unwrap(), "POST, OPTIONS");
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_600);
+
+        // POST to other paths is not found
+        let request = json!({"jsonrpc":"2.0","id":1,"method":"echo"});
+        let client = reqwest::blocking::Client::new();
+        let url = format!("http://127.0.0.1:{}/other", port);
+        let resp = client.post(url).json(&request).send().unwrap();
+        assert_eq!(resp.status(), 404);
+
+        // and rejected without waiting for the body
+        let response = raw_http(
+            port,
+            "POST /other HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+            Content-Length: 1000000\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let url = format!("http://127.0.0.1:{}/rpc", port);
+        let resp = client.post(url).json(&request).send().unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[cfg(feature = "tracing-propagation")]
+    #[test]
+    fn traceparent() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            let context = trace::TraceContext::current().expect("handling a request");
+            Response::ok(request.id, context.traceparent())
+        };
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "trace"});
+        let traceparent = |incoming: &str| {
+            let response: Response = reqwest::blocking::Client::new()
+                .post(&url)
+                .header("traceparent", incoming)
+                .json(&body)
+                .send()
+                .unwrap()
+                .json()
+                .unwrap();
+            response.result.unwrap().as_str().unwrap().to_string()
+        };
+
+        // the trace continues with a new span
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let outgoing = traceparent(incoming);
+        assert!(outgoing.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert_ne!(outgoing, incoming);
+
+        // a fresh trace is started
+        let outgoing = traceparent("00-malformed");
+        assert_eq!(outgoing.len(), 55);
+        assert!(!outgoing.contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+
+    #[test]
+    fn cache_control() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(
+            dir.path().to_path_buf(),
+            "index.html".to_string(),
+            b"<html></html>",
+        );
+        make_file(
+            dir.path().to_path_buf(),
+            "app.3f2a.js".to_string(),
+            b"console.log(1)",
+        );
+        make_file(dir.path().to_path_buf(), "notes.txt".to_string(), b"notes");
+        std::fs::create_dir(dir.path().join("css")).unwrap();
+        make_file(dir.path().join("css"), "app.css".to_string(), b"body {}");
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_cache_control("index.html", "no-cache")
+            .with_cache_control("**/*.js", "max-age=31536000, immutable")
+            .with_cache_control(".css", "max-age=3600")
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let cache_control = |path: &str| {
+            let response = reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+            assert_eq!(response.status(), 200);
+            response
+                .headers()
+                .get("Cache-Control")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(cache_control("/").unwrap(), "no-cache");
+        assert_eq!(cache_control("/index.html").unwrap(), "no-cache");
+        assert_eq!(
+            cache_control("/app.3f2a.js").unwrap(),
+            "max-age=31536000, immutable"
+        );
+        assert_eq!(cache_control("/css/app.css").unwrap(), "max-age=3600");
+        assert_eq!(cache_control("/notes.txt"), None);
+    }
+
+    #[test]
+    fn conditional_get() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "app.js".to_string(), b"app");
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_cache_control("*.js", "no-cache")
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}/app.js", rpc.port().unwrap());
+        let get = |header: Option<(&str, &str)>| {
+            let mut request = reqwest::blocking::Client::new().get(&url);
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.send().unwrap()
+        };
+
+        let response = get(None);
+        assert_eq!(response.status(), 200);
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        let last_modified = response.headers()["Last-Modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(response.text().unwrap(), "app");
+
+        let response = get(Some(("If-None-Match", &etag)));
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()["ETag"], etag.as_str());
+        assert_eq!(response.headers()["Cache-Control"], "no-cache");
+        assert_eq!(get(Some(("If-None-Match", "\"other\""))).status(), 200);
+
+        let response = get(Some(("If-Modified-Since", &last_modified)));
+        assert_eq!(response.status(), 304);
+        let earlier = "Thu, 01 Jan 1970 00:00:00 GMT";
+        assert_eq!(get(Some(("If-Modified-Since", earlier))).status(), 200);
+
+        // the etag changes with the file
+        make_file(dir.path().to_path_buf(), "app.js".to_string(), b"app v2");
+        let response = get(Some(("If-None-Match", &etag)));
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().unwrap(), "app v2");
+    }
+
+    #[test]
+    fn embedded_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "app.js".to_string(), b"file");
+        make_file(dir.path().to_path_buf(), "notes.txt".to_string(), b"notes");
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_embedded_asset("index.html", b"<html></html>")
+            .with_embedded_asset("/js/app.js", b"embedded")
+            .with_embedded_asset("app.js", b"embedded")
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let get = |path: &str| reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+
+        let response = get("/");
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.text().unwrap(), "<html></html>");
+        let response = get("/js/app.js");
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        assert_eq!(response.text().unwrap(), "embedded");
+
+        // embedded assets take precedence over the files
+        assert_eq!(get("/app.js").text().unwrap(), "embedded");
+        assert_eq!(get("/notes.txt").text().unwrap(), "notes");
+        assert_eq!(get("/js/missing.js").status(), 404);
+
+        let response = reqwest::blocking::Client::new()
+            .get(format!("{}/js/app.js", url))
+            .header("If-None-Match", &etag)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 304);
+    }
+
+    #[test]
+    fn directory_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("psets")).unwrap();
+        std::fs::create_dir(dir.path().join("psets/old")).unwrap();
+        make_file(dir.path().join("psets"), "b.pset".to_string(), b"pset");
+        make_file(dir.path().join("psets"), "<a>.pset".to_string(), b"");
+        for directory_listing in [false, true] {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let state = Arc::new(Mutex::new(()));
+            let config = Config::builder()
+                .with_serve_dir(Some(dir.path().to_path_buf()))
+                .with_directory_listing(directory_listing)
+                .build();
+            let rpc = JsonRpcServer::new(server, config, state, process);
+            let url = format!("http://127.0.0.1:{}/psets", rpc.port().unwrap());
+
+            let response = reqwest::blocking::get(&url).unwrap();
+            if !directory_listing {
+                assert_eq!(response.status(), 404);
+                continue;
+            }
+            assert_eq!(response.status(), 200);
+            let page = response.text().unwrap();
+            assert!(page.contains(r#"<a href="/psets/b.pset">b.pset</a></td><td>4</td>"#));
+            assert!(page.contains("&lt;a&gt;.pset"), "{}", page);
+
+            let response = reqwest::blocking::Client::new()
+                .get(&url)
+                .header("Accept", "application/json")
+                .send()
+                .unwrap();
+            let entries: Value = response.json().unwrap();
+            let names: Vec<_> = entries
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| e["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(names, ["<a>.pset", "b.pset", "old/"]);
+            assert_eq!(entries[1]["size"], 4);
+            assert!(entries[1]["modified"].is_u64());
+        }
+    }
+
+    #[test]
+    fn error_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(
+            dir.path().to_path_buf(),
+            "404.html".to_string(),
+            b"<h1>Lost</h1>",
+        );
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_rpc_path(Some("/rpc"))
+            .with_error_page(404, config::ErrorPage::File(dir.path().join("404.html")))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+
+        let response = reqwest::blocking::get(format!("{}/missing", url)).unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.text().unwrap(), "<h1>Lost</h1>");
+
+        // only GET responses use the pages
+        let response = reqwest::blocking::Client::new()
+            .post(format!("{}/other", url))
+            .json(&json!({"jsonrpc":"2.0","id":1,"method":"echo"}))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.text().unwrap(), "404: Not found");
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_index_message(None)
+            .with_error_page(500, config::ErrorPage::Inline("<h1>Oops</h1>".into()))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}/", rpc.port().unwrap());
+        let response = reqwest::blocking::get(url).unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.text().unwrap(), "<h1>Oops</h1>");
+    }
+
+    #[test]
+    fn health() {
+        let reachable = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let backend = reachable.clone();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_health_path(Some("/health"))
+            .with_ready_path(Some("/ready"))
+            .with_readiness_check("electrum", move || {
+                if backend.load(Ordering::SeqCst) {
+                    Ok(())
+                } else {
+                    Err("unreachable".to_string())
+                }
+            })
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let get = |path: &str| reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+
+        let response = get("/health");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.json::<Value>().unwrap(), json!({"status": "ok"}));
+        let response = get("/ready");
+        assert_eq!(response.status(), 200);
+        let expected = json!({"status": "ready", "checks": {"electrum": "ok"}});
+        assert_eq!(response.json::<Value>().unwrap(), expected);
+
+        reachable.store(false, Ordering::SeqCst);
+        let response = get("/ready");
+        assert_eq!(response.status(), 503);
+        let expected = json!({"status": "unavailable", "checks": {"electrum": "unreachable"}});
+        assert_eq!(response.json::<Value>().unwrap(), expected);
+        assert_eq!(get("/health").status(), 200);
+
+        let response = reqwest::blocking::Client::new()
+            .head(format!("{}/health", url))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn get_routes() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_disable_get(true)
+            .with_get_route("pset/*.base64", |context: &RequestContext| {
+                let txid = url_path(&context.url)
+                    .trim_start_matches("/pset/")
+                    .trim_end_matches(".base64");
+                HttpResponse::from_string(format!("pset of {}", txid)).boxed()
+            })
+            .with_get_route("metrics", |_: &RequestContext| {
+                HttpResponse::from_string("requests 1")
+                    .with_status_code(202)
+                    .boxed()
+            })
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let get = |path: &str| reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+
+        let response = get("/pset/abc.base64?x=1");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().unwrap(), "pset of abc");
+        let response = get("/metrics");
+        assert_eq!(response.status(), 202);
+        assert_eq!(response.text().unwrap(), "requests 1");
+        // other paths are not served with `disable_get`
+        assert_eq!(get("/pset/abc.hex").status(), 404);
+    }
+
+    #[test]
+    fn spa_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "index.html".to_string(), b"index");
+        make_file(dir.path().to_path_buf(), "app.js".to_string(), b"app");
+        for spa_fallback in [false, true] {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let state = Arc::new(Mutex::new(()));
+            let config = Config::builder()
+                .with_serve_dir(Some(dir.path().to_path_buf()))
+                .with_spa_fallback(spa_fallback)
+                .build();
+            let rpc = JsonRpcServer::new(server, config, state, process);
+            let port = rpc.port().unwrap();
+            let get = |path: &str| {
+                reqwest::blocking::get(format!("http://127.0.0.1:{}{}", port, path)).unwrap()
+            };
+
+            let response = get("/wallet/settings?tab=1");
+            if spa_fallback {
+                assert_eq!(response.status(), 200);
+                assert_eq!(response.text().unwrap(), "index");
+            } else {
+                assert_eq!(response.status(), 404);
+            }
+            assert_eq!(get("/app.js").text().unwrap(), "app");
+            assert_eq!(get("/missing.js").status(), 404);
+        }
+    }
+
+    #[test]
+    fn mime_types() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "index.html",
+            "app.JS",
+            "module.wasm",
+            "data.bin",
+            "notes.md",
+        ] {
+            make_file(dir.path().to_path_buf(), name.to_string(), b"data");
+        }
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_mime_type(".md", "text/markdown")
+            .with_mime_type("wasm", "application/octet-stream")
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let content_type = |path: &str| {
+            let response = reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+            assert_eq!(response.status(), 200);
+            response
+                .headers()
+                .get("Content-Type")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(content_type("/").unwrap(), "text/html; charset=utf-8");
+        assert_eq!(
+            content_type("/app.JS").unwrap(),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            content_type("/module.wasm").unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(content_type("/notes.md").unwrap(), "text/markdown");
+        assert_eq!(content_type("/data.bin"), None);
+    }
+
+    #[test]
+    fn worker_stats() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            entered_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            Ok(Response::result(request.id, Value::Null))
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        let idle = WorkerStats {
+            busy: 0,
+            idle: 4,
+            total: 4,
+        };
+        assert_eq!(rpc.worker_stats(), idle);
+
+        let call = thread::spawn(move || {
+            post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "block"}))
+        });
+        entered_rx.recv().unwrap();
+        let busy = WorkerStats {
+            busy: 1,
+            idle: 3,
+            total: 4,
+        };
+        assert_eq!(rpc.worker_stats(), busy);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(call.join().unwrap().status(), 200);
+        // the worker is released right after sending the response
+        for _ in 0..100 {
+            if rpc.worker_stats() == idle {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(rpc.worker_stats(), idle);
+    }
+
+    #[test]
+    fn new_ready() {
+        let server = Server::http("0.0.0.0:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let timeout = Duration::from_secs(5);
+        let rpc =
+            JsonRpcServer::new_ready(server, Config::default(), state, process, timeout).unwrap();
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "echo"}),
+        );
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn disable_get() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "index.html".to_string(), b"index");
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_disable_get(true)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        for path in ["/", "/index.html", "/missing"] {
+            let response = reqwest::blocking::get(format!("{}{}", url, path)).unwrap();
+            assert_eq!(response.status(), 404);
+            assert_eq!(response.text().unwrap(), "404: Not Found");
+        }
+        let response = reqwest::blocking::Client::new()
+            .request(reqwest::Method::OPTIONS, &url)
+            .send()
+            .unwrap();
+        assert_eq!(response.headers()["Allow"], "POST, OPTIONS");
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "echo"}),
+        );
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn stop_with_request_in_flight() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let process = move |request: Request, _state: Arc<Mutex<()>>| match request.method.as_str()
+        {
+            "stop" => Err(Error::Stop),
+            _ => {
+                entered_tx.send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+                Ok(Response::result(request.id, json!("done")))
+            }
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let mut rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        let slow = thread::spawn(move || {
+            post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "slow"}))
+        });
+        entered_rx.recv().unwrap();
+        let response = post(port, &json!({"jsonrpc": "2.0", "id": 2, "method": "stop"}));
+        let response: Response = response.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_099);
+        assert!(!rpc.is_running());
+
+        // the in flight request still gets its response
+        release_tx.send(()).unwrap();
+        let response: Response = slow.join().unwrap().json().unwrap();
+        assert_eq!(response.result.unwrap(), json!("done"));
+
+        let start = Instant::now();
+        rpc.join_threads();
+        assert!(start.elapsed() < Duration::from_millis(90));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix() {
+        use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rpc.sock");
+        // a stale socket, left without a listener
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let new = || {
+            let state = Arc::new(Mutex::new(()));
+            JsonRpcServer::bind_unix(&path, 0o600, Config::default(), state, process)
+        };
+        let rpc = new().unwrap();
+        assert_eq!(rpc.port(), None);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(new().is_err(), "the socket is in use");
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[1]}"#;
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(
+            response.ends_with(r#""result":[1],"id":1}"#),
+            "{}",
+            response
+        );
+
+        rpc.stop();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn path_traversal() {
+        let outside = tempfile::tempdir().unwrap();
+        make_file(
+            outside.path().to_path_buf(),
+            "secret".to_string(),
+            b"secret",
+        );
+        let dir = outside.path().join("www");
+        std::fs::create_dir(&dir).unwrap();
+        make_file(dir.clone(), "file name.txt".to_string(), b"spaced");
+        make_file(dir.clone(), "app.js".to_string(), b"app");
+        let secret = outside.path().join("secret");
+        let secret = secret.to_str().unwrap();
+
+        for follow_symlinks in [false, true] {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let state = Arc::new(Mutex::new(()));
+            let config = Config::builder()
+                .with_serve_dir(Some(dir.clone()))
+                .with_follow_symlinks(follow_symlinks)
+                .build();
+            let rpc = JsonRpcServer::new(server, config, state, process);
+            let port = rpc.port().unwrap();
+            let get = |path: &str| {
+                let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+                raw_http(port, &request)
+            };
+
+            for path in [
+                "/../secret".to_string(),
+                "/..%2fsecret".to_string(),
+                "/%2e%2e/secret".to_string(),
+                "/%2E%2E%2Fsecret".to_string(),
+                format!("/{}", secret),
+                format!("/%2f{}", &secret[1..]),
+                "/..%5csecret".to_string(),
+                "/%zz".to_string(),
+            ] {
+                let response = get(&path);
+                assert!(
+                    response.starts_with("HTTP/1.1 404"),
+                    "{}: {}",
+                    path,
+                    response
+                );
+                assert!(!response.ends_with("secret"), "{}: {}", path, response);
+            }
+            assert!(get("/file%20name.txt").ends_with("spaced"));
+            assert!(get("/app.js?v=1").ends_with("app"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        make_file(
+            outside.path().to_path_buf(),
+            "secret".to_string(),
+            b"secret",
+        );
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "file".to_string(), b"file");
+        std::os::unix::fs::symlink(dir.path().join("file"), dir.path().join("safe")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("escape"))
+            .unwrap();
+
+        for follow_symlinks in [false, true] {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let state = Arc::new(Mutex::new(()));
+            let config = Config::builder()
+                .with_serve_dir(Some(dir.path().to_path_buf()))
+                .with_follow_symlinks(follow_symlinks)
+                .build();
+            let rpc = JsonRpcServer::new(server, config, state, process);
+            let port = rpc.port().unwrap();
+            let get = |path: &str| {
+                reqwest::blocking::get(format!("http://127.0.0.1:{}{}", port, path)).unwrap()
+            };
+
+            let response = get("/safe");
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.text().unwrap(), "file");
+
+            let response = get("/escape");
+            if follow_symlinks {
+                assert_eq!(response.text().unwrap(), "secret");
+            } else {
+                assert_eq!(response.status(), 404);
+            }
+
+            // `..` is always rejected, clients normalize it so the request is written by hand
+            let secret = outside.path().file_name().unwrap().to_str().unwrap();
+            let request = format!(
+                "GET /../{}/secret HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                secret
+            );
+            let response = raw_http(port, &request);
+            assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn on_tick() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let config = {
+            let ticks = ticks.clone();
+            Config::builder()
+                .with_on_tick(move || {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                })
+                .build()
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        thread::sleep(Duration::from_millis(550));
+        drop(rpc);
+        // about every 100ms by a single worker, not one tick per worker
+        let ticks = ticks.load(Ordering::SeqCst);
+        assert!((3..=6).contains(&ticks), "{}", ticks);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn bytes_metrics() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[1,2,3]}"#;
+        let response = reqwest::blocking::Client::new()
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .unwrap();
+        let sent = response.bytes().unwrap().len()
+            + reqwest::blocking::get(&url).unwrap().bytes().unwrap().len();
+
+        let metrics = rpc.metrics();
+        assert_eq!(metrics.bytes_in, body.len() as u64);
+        assert_eq!(metrics.bytes_out, sent as u64);
+    }
+
+    #[test]
+    fn status_counts() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_rpc_path(Some("/rpc")).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let client = reqwest::blocking::Client::new();
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo"});
+        for path in ["/rpc", "/rpc", "/other"] {
+            client
+                .post(format!("{}{}", url, path))
+                .json(&body)
+                .send()
+                .unwrap();
+        }
+        for path in ["/", "/rpc"] {
+            client.get(format!("{}{}", url, path)).send().unwrap();
+        }
+        let expected = HashMap::from([(200, 3), (404, 1), (405, 1)]);
+        assert_eq!(rpc.status_counts(), expected);
+    }
+
+    #[test]
+    fn recent_errors() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_max_recent_errors(2).build();
+        let rpc = JsonRpcServer::new(server, config, state, |request: Request, _| {
+            let err = std::io::Error::new(ErrorKind::Other, format!("boom {}", request.method));
+            Err(Error::Inner(InnerError::Io(err)))
+        });
+        let port = rpc.port().unwrap();
+        assert!(rpc.recent_errors().is_empty());
+
+        for method in ["a", "b", "c"] {
+            post(port, &json!({"jsonrpc":"2.0","id":1,"method":method}));
+        }
+        assert_eq!(
+            rpc.recent_errors(),
+            vec![
+                "Error processing request: IO Error: boom b",
+                "Error processing request: IO Error: boom c",
+            ]
+        );
+    }
+
+    #[test]
+    fn pipelined_order() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, |request: Request, _| {
+            if request.method == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            Ok(Response::result(request.id, Value::String(request.method)))
+        });
+        let port = rpc.port().unwrap();
+
+        let request = |method: &str, close: bool| {
+            let body = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{}"}}"#, method);
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                {}Content-Length: {}\r\n\r\n{}",
+                if close { "Connection: close\r\n" } else { "" },
+                body.len(),
+                body
+            )
+        };
+        let pipelined = request("slow", false) + &request("fast", true);
+        let response = raw_http(port, &pipelined);
+        let slow = response.find(r#""result":"slow""#).unwrap();
+        let fast = response.find(r#""result":"fast""#).unwrap();
+        assert!(slow < fast);
+    }
+
+    #[test]
+    fn multiple_servers() {
+        let servers = vec![
+            Server::http("127.0.0.1:0").unwrap(),
+            Server::http("127.0.0.1:0").unwrap(),
+        ];
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new_with_servers(servers, Config::default(), state, process);
+        let addrs = rpc.addrs();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(
+            addrs[0].clone().to_ip().unwrap().port(),
+            rpc.port().unwrap()
+        );
+
+        for addr in addrs {
+            let port = addr.to_ip().unwrap().port();
+            let response = post(port, &json!({"jsonrpc":"2.0","id":1,"method":"echo"}));
+            assert_eq!(response.status(), 200);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rpc.sock");
+        let unix = Listener::Unix {
+            path: path.clone(),
+            mode: 0o600,
+        };
+        let tcp = Listener::Tcp("127.0.0.1:0".parse().unwrap());
+
+        // the socket created is removed when a following listener fails
+        let taken = Server::http("127.0.0.1:0").unwrap();
+        let taken = Listener::Tcp(taken.server_addr().to_ip().unwrap());
+        let state = Arc::new(Mutex::new(()));
+        let listeners = vec![unix.clone(), taken];
+        assert!(JsonRpcServer::bind(listeners, Config::default(), state, process).is_err());
+        assert!(!path.exists());
+
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::bind(vec![tcp, unix], Config::default(), state, process).unwrap();
+        let addrs = rpc.addrs();
+        assert_eq!(addrs.len(), 2);
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc":"2.0","id":1,"method":"echo"}),
+        );
+        assert_eq!(response.status(), 200);
+        assert!(path.exists());
+
+        rpc.stop();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn index_message() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        let resp = reqwest::blocking::get(format!("http://127.0.0.1:{}/", port)).unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.text().unwrap(),
+            "This is a JSON-RPC endpoint; POST application/json"
+        );
+        let resp = reqwest::blocking::get(format!("http://127.0.0.1:{}/file", port)).unwrap();
+        assert_eq!(resp.status(), 500);
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_index_message(None).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let resp = reqwest::blocking::get(format!("http://127.0.0.1:{}/", port)).unwrap();
+        assert_eq!(resp.status(), 500);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_response() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+
+        let dir = tempfile::tempdir().unwrap();
+        make_file(
+            dir.path().to_path_buf(),
+            "app.js".to_string(),
+            &[b'a'; 2048],
+        );
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .with_compress_min_bytes(Some(1024))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let client = reqwest::blocking::Client::new();
+        let call = |params: Value, accept_encoding: &str| {
+            let request = json!({"jsonrpc":"2.0","id":1,"method":"echo","params":params});
+            client
+                .post(&url)
+                .json(&request)
+                .header("Accept-Encoding", accept_encoding)
+                .send()
+                .unwrap()
+        };
+        let encoding = |response: &reqwest::blocking::Response| {
+            response
+                .headers()
+                .get("Content-Encoding")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+
+        let large = json!(["x".repeat(2048)]);
+        let response = call(large.clone(), "br, gzip");
+        assert_eq!(encoding(&response).unwrap(), "gzip");
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        let response: Response = serde_json::from_reader(GzDecoder::new(response)).unwrap();
+        assert_eq!(response.result.unwrap(), large);
+
+        let response = call(large.clone(), "gzip;q=0.5, deflate");
+        assert_eq!(encoding(&response).unwrap(), "deflate");
+        let response: Response = serde_json::from_reader(ZlibDecoder::new(response)).unwrap();
+        assert_eq!(response.result.unwrap(), large);
+
+        // below the threshold, or not accepted by the client
+        assert_eq!(encoding(&call(json!(["small"]), "gzip")), None);
+        assert_eq!(encoding(&call(large, "identity")), None);
+
+        let response = client
+            .get(format!("{}/app.js", url))
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .unwrap();
+        assert_eq!(encoding(&response).unwrap(), "gzip");
+        let mut file = Vec::new();
+        GzDecoder::new(response).read_to_end(&mut file).unwrap();
+        assert_eq!(file, [b'a'; 2048]);
+
+        assert_eq!(preferred_encoding("gzip;q=0, *"), Some("deflate"));
+        assert_eq!(preferred_encoding("gzip;q=0, identity"), None);
+        assert_eq!(preferred_encoding("deflate, *;q=0.1"), Some("deflate"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_request() {
+        use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_max_body_bytes(Some(1024)).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let client = reqwest::blocking::Client::new();
+
+        let send = |encoding: &str, body: Vec<u8>| {
+            client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Content-Encoding", encoding)
+                .body(body)
+                .send()
+                .unwrap()
+        };
+        let request = json!({"jsonrpc":"2.0","id":1,"method":"echo","params":["ok"]});
+        let json = serde_json::to_vec(&request).unwrap();
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&json).unwrap();
+        let resp = send("gzip", gzip.finish().unwrap());
+        assert_eq!(resp.status(), 200);
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.result.unwrap(), json!(["ok"]));
+
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(&json).unwrap();
+        let resp = send("deflate", deflate.finish().unwrap());
+        assert_eq!(resp.status(), 200);
+
+        // the decompressed size counts against max_body_bytes
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&[b' '; 4096]).unwrap();
+        let resp = send("gzip", gzip.finish().unwrap());
+        assert_eq!(resp.status(), 413);
+
+        let resp = send("br", json);
+        assert_eq!(resp.status(), 415);
+    }
+
+    #[test]
+    fn large_numbers() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        let echo = |params: &str| {
+            let body = format!(
+                r#"{{"jsonrpc":"2.0","id":1,"method":"echo","params":{}}}"#,
+                params
+            );
+            let url = format!("http://127.0.0.1:{}", port);
+            reqwest::blocking::Client::new()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+                .unwrap()
+                .text()
+                .unwrap()
+        };
+        let params = format!("[{}]", u64::MAX - 1);
+        assert!(echo(&params).contains(&params));
+
+        if cfg!(feature = "arbitrary_precision") {
+            let params = "[340282366920938463463374607431768211456,0.10000000000000000001]";
+            assert!(echo(params).contains(params));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "no worker thread started")]
+    fn no_workers() {
+        let handler = JsonRpcServer::handler(Arc::new(Mutex::new(())), process);
+        JsonRpcServer::run(Vec::new(), Config::default(), handler);
+    }
+
+    #[test]
+    fn thread_stack_size() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_thread_stack_size(Some(256 * 1024))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc":"2.0","id":1,"method":"echo"}),
+        );
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn auth_principal() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let auth = |headers: &[Header]| {
+            let token = headers
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str());
+            match token {
+                Some("Bearer alice-token") => Ok("alice".to_string()),
+                Some("Bearer bob-token") => Ok("bob".to_string()),
+                _ => Err(Error::from("invalid token".to_string())),
+            }
+        };
+        let func = |request: Request, user: String, _state| {
+            Ok(Response::result(request.id, Value::String(user)))
+        };
+        let rpc = JsonRpcServer::new_with_auth(server, Config::default(), state, auth, func);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+
+        let send = |token: &str| {
+            reqwest::blocking::Client::new()
+                .post(&url)
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&json!({"jsonrpc":"2.0","id":1,"method":"whoami"}))
+                .send()
+                .unwrap()
+        };
+        for user in ["alice", "bob"] {
+            let resp = send(&format!("{}-token", user));
+            assert_eq!(resp.status(), 200);
+            let response: Response = resp.json().unwrap();
+            assert_eq!(response.result.unwrap(), json!(user));
+        }
+        let resp = send("mallory-token");
+        assert_eq!(resp.status(), 401);
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.error.unwrap().message, "invalid token");
+    }
+
+    #[test]
+    fn accept_filter() {
+        let localhost = std::net::IpAddr::from([127, 0, 0, 1]);
+        for (allowed, status) in [(true, 200), (false, 403)] {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let state = Arc::new(Mutex::new(()));
+            let config = Config::builder()
+                .with_accept_filter(move |ip| (ip == localhost) == allowed)
+                .build();
+            let rpc = JsonRpcServer::new(server, config, state, process);
+            let port = rpc.port().unwrap();
+            let response = post(port, &json!({"jsonrpc":"2.0","id":1,"method":"echo"}));
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[test]
+    fn on_send_error() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let (error_tx, error_rx) = mpsc::channel();
+        let error_tx = Mutex::new(error_tx);
+        // responses closing the connection are written without tiny_http, which would ignore
+        // errors caused by the client disconnecting
+        let config = Config::builder()
+            .with_max_requests_per_connection(Some(1))
+            .with_on_send_error(move |e| {
+                error_tx.lock().unwrap().send(e.kind()).unwrap();
+            })
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, |request: Request, _| {
+            thread::sleep(Duration::from_millis(200));
+            Response::ok(request.id, vec![0u8; 1 << 20])
+        });
+        let port = rpc.port().unwrap();
+
+        // the client disconnects before the large response is sent
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"big"}"#;
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        drop(stream);
+
+        error_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn secure_defaults() {
+        let addr = "127.0.0.1:0";
+        let server = Server::http(addr).unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_header(Header::from_str("X-Frame-Options: SAMEORIGIN").unwrap())
+            .secure_defaults()
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let url = format!("http://127.0.0.1:{}", port);
+
+        let client = reqwest::blocking::Client::builder().build().unwrap();
+        let resp = client
+            .post(url)
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": []}))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let headers = resp.headers();
+        assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+        assert_eq!(headers.get("x-frame-options").unwrap(), "SAMEORIGIN");
+        assert_eq!(
+            headers.get("content-security-policy").unwrap(),
+            "default-src 'self'"
+        );
+    }
+
+    fn post(port: u16, body: &Value) -> reqwest::blocking::Response {
+        let url = format!("http://127.0.0.1:{}", port);
+        reqwest::blocking::Client::new()
+            .post(url)
+            .json(body)
+            .send()
+            .unwrap()
+    }
+
+    #[test]
+    fn method_concurrency() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            entered_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            Ok(Response::result(request.id, Value::Bool(true)))
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_method_concurrency("rescan", 1)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "rescan"});
+
+        let first = {
+            let body = body.clone();
+            thread::spawn(move || post(port, &body))
+        };
+        entered_rx.recv().unwrap();
+
+        // the only permit is held by the first request
+        let resp = post(port, &body);
+        assert_eq!(resp.status(), 503);
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_000);
+
+        release_tx.send(()).unwrap();
+        let resp = first.join().unwrap();
+        assert_eq!(resp.status(), 200);
+        let response: Response = resp.json().unwrap();
+        assert!(response.is_result());
+    }
+
+    #[test]
+    fn max_concurrent_per_ip() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            if request.method == "scan" {
+                entered_tx.send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+            }
+            Ok(Response::result(request.id, Value::Bool(true)))
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_max_concurrent_per_ip(Some(1))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+
+        let first = thread::spawn(move || {
+            post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "scan"}))
+        });
+        entered_rx.recv().unwrap();
+
+        // the only slot of the address is held by the first request, whatever the method
+        let resp = post(port, &json!({"jsonrpc": "2.0", "id": 2, "method": "echo"}));
+        assert_eq!(resp.status(), 429);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(first.join().unwrap().status(), 200);
+        let resp = post(port, &json!({"jsonrpc": "2.0", "id": 3, "method": "echo"}));
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[test]
+    fn events() {
+        use std::io::{BufRead, BufReader};
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_events_path(Some("/events")).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}/events", rpc.port().unwrap());
+        let sender = rpc.event_sender();
+        assert_eq!(sender.send(json!("nobody")), 0);
+
+        let response = reqwest::blocking::get(&url).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut lines = BufReader::new(response).lines();
+        assert_eq!(sender.send(json!({"tx": "abc"})), 1);
+        assert_eq!(rpc.broadcast("block", json!([1])), 1);
+        assert_eq!(lines.next().unwrap().unwrap(), r#"data: {"tx":"abc"}"#);
+        assert_eq!(lines.next().unwrap().unwrap(), "");
+        let notification = r#"data: {"jsonrpc":"2.0","method":"block","params":[1]}"#;
+        assert_eq!(lines.next().unwrap().unwrap(), notification);
+
+        // disconnected clients are pruned
+        drop(lines);
+        while sender.send(json!(null)) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // other paths serve files as usual
+        let response = reqwest::blocking::get(format!("{}/other", url)).unwrap();
+        assert_eq!(response.status(), 500);
+    }
+
+    #[test]
+    fn events_limits() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_events_path(Some("/events"))
+            .with_max_event_streams(1)
+            .with_disable_get(true)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}/events", rpc.port().unwrap());
+
+        // the endpoint is set explicitly, it is answered even if GET is disabled
+        let first = reqwest::blocking::get(&url).unwrap();
+        assert_eq!(first.status(), 200);
+        let response = reqwest::blocking::get(format!("{}/other", url)).unwrap();
+        assert_eq!(response.status(), 404);
+
+        let response = reqwest::blocking::get(&url).unwrap();
+        assert_eq!(response.status(), 503);
+
+        // the stream is released once its client disconnects
+        drop(first);
+        let sender = rpc.event_sender();
+        while sender.send(json!(null)) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reqwest::blocking::get(&url).unwrap().status(), 200);
+    }
+
+    #[test]
+    fn stream() {
+        let process = |request: Request, _state: Arc<Mutex<()>>| {
+            let count = request.params_as::<(u64,)>()?.0;
+            Ok(Response::stream(
+                request.id,
+                (0..count).map(|i| json!({"txid": i})),
+            ))
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "txs", "params": [10000]});
+        let response = post(port, &request);
+        assert_eq!(response.headers()["transfer-encoding"], "chunked");
+        let response: Response = response.json().unwrap();
+        let txs = response.result.unwrap();
+        assert_eq!(txs.as_array().unwrap().len(), 10000);
+        assert_eq!(txs[9999], json!({"txid": 9999}));
+        assert_eq!(response.id, Some(Id::Number(1.into())));
+
+        let request = json!({"jsonrpc": "2.0", "id": "a", "method": "txs", "params": [0]});
+        let response: Value = post(port, &request).json().unwrap();
+        assert_eq!(response, json!({"jsonrpc": "2.0", "result": [], "id": "a"}));
+
+        // serialized as a whole in a batch
+        let request = json!([{"jsonrpc": "2.0", "id": 2, "method": "txs", "params": [2]}]);
+        let response: Value = post(port, &request).json().unwrap();
+        assert_eq!(response[0]["result"], json!([{"txid": 0}, {"txid": 1}]));
+    }
+
+    #[test]
+    fn long_poll() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (update_tx, update_rx) = mpsc::channel::<u64>();
+        let update_rx = Arc::new(Mutex::new(update_rx));
+        let timeout = Duration::from_millis(300);
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            entered_tx.send(()).unwrap();
+            // the handler waits less than the configured timeout
+            match update_rx.lock().unwrap().recv_timeout(timeout / 2) {
+                Ok(height) => Response::ok(request.id, height),
+                Err(_) => Ok(Response::long_poll_timeout(request.id, timeout)),
+            }
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_long_poll("wait", timeout).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "wait"});
+
+        // returns on new data
+        let poll = {
+            let body = body.clone();
+            thread::spawn(move || post(port, &body))
+        };
+        entered_rx.recv().unwrap();
+        update_tx.send(42).unwrap();
+        let response: Response = poll.join().unwrap().json().unwrap();
+        assert_eq!(response.result.unwrap(), json!(42));
+
+        // or once the timeout expires
+        let response: Response = post(port, &body).json().unwrap();
+        let error = response.error.unwrap();
+        assert_eq!(error.code, -32_090);
+        assert_eq!(error.data.unwrap(), json!({"timeout_ms": 300}));
+    }
+
+    #[test]
+    fn request_timeout() {
+        let (cancelled_tx, cancelled_rx) = mpsc::channel();
+        let cancelled_tx = Mutex::new(cancelled_tx);
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_num_threads(std::num::NonZeroU8::new(1).unwrap())
+            .with_request_timeout(Some(Duration::from_millis(200)))
+            .build();
+        let rpc =
+            JsonRpcServer::new_with_context(server, config, state, move |request, context, _| {
+                if request.method == "hang" {
+                    while !context.cancellation.is_cancelled() {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    cancelled_tx.lock().unwrap().send(()).unwrap();
+                }
+                Ok(Response::result(request.id, json!(request.method)))
+            });
+        let port = rpc.port().unwrap();
+
+        let response: Response = post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "hang"}))
+            .json()
+            .unwrap();
+        let error = response.error.unwrap();
+        assert_eq!(error.code, -32_092);
+        assert!(error.message.contains("'hang'"), "{}", error.message);
+        assert_eq!(error.data.unwrap(), json!({"timeout_ms": 200}));
+        cancelled_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // the only worker is free again
+        let response: Response = post(port, &json!({"jsonrpc": "2.0", "id": 2, "method": "fast"}))
+            .json()
+            .unwrap();
+        assert_eq!(response.result.unwrap(), json!("fast"));
+    }
+
+    #[test]
+    fn request_timeout_holds_limits() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_request_timeout(Some(Duration::from_millis(100)))
+            .with_method_concurrency("slow", 1)
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, |request, _| {
+            // ignores the cancellation
+            thread::sleep(Duration::from_millis(500));
+            Ok(Response::result(request.id, json!(request.method)))
+        });
+        let port = rpc.port().unwrap();
+        let slow = json!({"jsonrpc": "2.0", "id": 1, "method": "slow"});
+
+        let response: Response = post(port, &slow).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_092);
+
+        // the handler of the timed out request still runs
+        let response: Response = post(port, &slow).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_000);
+        rpc.drain();
+        assert_eq!(rpc.state(), ServerState::Draining);
+
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(rpc.state(), ServerState::Stopped);
+    }
+
+    #[test]
+    fn raw_result() {
+        let psbt = b"psbt\xff".to_vec();
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            let response = Response::result(request.id, Value::String("cHNidP8=".into()));
+            Ok(response.with_raw("application/octet-stream", psbt.clone()))
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        let url = format!("http://127.0.0.1:{}", port);
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "psbt"});
+
+        // standard clients receive the JSON RPC response
+        let resp = reqwest::blocking::Client::new()
+            .post(&url)
+            .header("Accept", "application/json, */*")
+            .json(&body)
+            .send()
+            .unwrap();
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.result.unwrap(), "cHNidP8=");
+
+        // clients asking for the raw content type receive the bytes
+        let resp = reqwest::blocking::Client::new()
+            .post(&url)
+            .header("Accept", "application/octet-stream")
+            .json(&body)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(&resp.bytes().unwrap()[..], b"psbt\xff");
+    }
+
+    #[test]
+    fn drain() {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let process = move |request: Request, _state: Arc<Mutex<()>>| {
+            entered_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            Ok(Response::result(request.id, Value::Bool(true)))
+        };
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let mut rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "scan"});
+        assert_eq!(rpc.state(), ServerState::Running);
 
-    // check content-type header exists
-    let content_header = http_request
-        .headers()
-        .iter()
-        .find(|h| h.field.as_str().as_str().to_ascii_lowercase() == "content-type")
-        .ok_or(InnerError::NoContentType)?;
-
-    // check content-type is application/json
-    if !content_header
-        .value
-        .as_str()
-        .trim()
-        .to_ascii_lowercase()
-        .contains("application/json")
-    {
-        return Err(InnerError::WrongContentType);
-    }
+        let in_flight = {
+            let body = body.clone();
+            thread::spawn(move || post(port, &body))
+        };
+        entered_rx.recv().unwrap();
 
-    // parse json into request
-    let mut s = String::new(); // todo: performance
-    http_request.as_reader().read_to_string(&mut s)?;
+        rpc.drain();
+        assert_eq!(rpc.state(), ServerState::Draining);
+        assert!(rpc.is_running());
 
-    let request: Request = serde_json::from_str(&s)?;
+        // new requests are rejected
+        let resp = post(port, &body);
+        assert_eq!(resp.status(), 503);
+        let response: Response = resp.json().unwrap();
+        assert!(response.is_error());
 
-    Ok(request)
-}
+        // the request in flight completes
+        release_tx.send(()).unwrap();
+        let resp = in_flight.join().unwrap();
+        assert_eq!(resp.status(), 200);
+        let response: Response = resp.json().unwrap();
+        assert!(response.is_result());
 
-fn handle_jsonrpc_request<F, T>(
-    request: Request,
-    state: Arc<Mutex<T>>,
-    process: F,
-) -> Result<Response, Error>
-where
-    F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Clone + Send + Sync + 'static,
-    T: Send + 'static,
-{
-    // check jsonrpc version
-    if request.jsonrpc.as_str() != "2.0" {
-        return Err(error::Error::Inner(InnerError::InvalidVersion));
+        rpc.join_threads();
+        assert_eq!(rpc.state(), ServerState::Stopped);
     }
 
-    // check method is not reserved (ie: starts with "rpc.")
-    if request.method.starts_with("rpc.") {
-        return Err(error::Error::Inner(InnerError::ReservedMethodPrefix));
+    // sends a raw HTTP request and returns the raw response
+    fn raw_http(port: u16, request: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
     }
 
-    // call the method handler
-    let id = request.id.clone();
-    let response = match process(request, state) {
-        Ok(response) => response,
-        Err(Error::Stop) => return Err(Error::Stop),
-        Err(Error::Inner(err)) => {
-            tracing::error!("Error processing request: {}", err);
-            Response::from_error(id, err)
-        }
-        Err(Error::Implementation(err)) => Response::from_error(id, err),
-    };
+    #[test]
+    fn head() {
+        let dir = tempfile::tempdir().unwrap();
+        make_file(dir.path().to_path_buf(), "app.js".to_string(), &[b'a'; 100]);
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_serve_dir(Some(dir.path().to_path_buf()))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
 
-    Ok(response)
-}
+        let response = raw_http(port, "HEAD /app.js HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Content-Length: 100\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
 
-fn send_jsonrpc_response(
-    request: tiny_http::Request,
-    response: Response,
-    headers: &[Header],
-) -> Result<(), InnerError> {
-    let data = serde_json::to_string(&response)?;
-    let mut response = HttpResponse::from_string(data);
-    for header in headers.iter() {
-        response.add_header(header.clone());
+        let response = raw_http(port, "HEAD /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
     }
-    Ok(request.respond(response)?)
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Request {
-    pub jsonrpc: String,
-    pub id: Option<Id>,
-    pub method: String,
-    pub params: Option<Value>,
-}
+    #[test]
+    fn content_length() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_max_body_bytes(Some(1024)).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Response {
-    pub jsonrpc: String,
-    pub id: Option<Id>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub result: Option<Value>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<RpcError>,
-}
+        let request = |content_length: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                content_length
+            )
+        };
+        // chunked bodies have no declared length, they are limited while reading
+        let chunk = "x".repeat(1000);
+        let chunked = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+            Content-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n\
+            {len:x}\r\n{chunk}\r\n{len:x}\r\n{chunk}\r\n0\r\n\r\n",
+            len = chunk.len(),
+            chunk = chunk
+        );
+        let response = raw_http(port, &chunked);
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+        assert!(response.contains("-32600"), "{}", response);
 
-impl Response {
-    pub fn result(id: Option<Id>, value: Value) -> Self {
-        Self {
-            jsonrpc: "2.0".into(),
-            id,
-            result: Some(value),
-            error: None,
+        for (content_length, status) in [
+            ("99999999999999999", "413"),
+            ("99999999999999999999999999", "413"),
+            ("-1", "400"),
+            ("ten", "400"),
+        ] {
+            let response = raw_http(port, &request(content_length));
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}: {}",
+                content_length,
+                response
+            );
         }
     }
 
-    pub fn error(id: Option<Id>, code: i64, message: String, data: Option<Value>) -> Self {
-        let err = RpcError {
-            code,
-            message,
-            data,
-        };
-        Self {
-            jsonrpc: "2.0".into(),
-            id,
-            result: None,
-            error: Some(err),
-        }
-    }
+    #[test]
+    fn oversized_bodies() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
 
-    pub(crate) fn from_error<E: AsRpcError>(id: Option<Id>, error: E) -> Self {
-        Self {
-            jsonrpc: "2.0".into(),
-            id,
-            result: None,
-            error: Some(error.as_rpc_error()),
+        // the client keeps the connection alive, the server closes it
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+            Content-Length: 99999999999999999\r\n\r\n";
+        for _ in 0..200 {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            let response = String::from_utf8_lossy(&response);
+            assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
         }
-    }
 
-    pub fn unimplemented(id: Option<Id>, message: String) -> Self {
-        Self::error(id, METHOD_NOT_FOUND, message, None)
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [42]});
+        let response: Response = post(port, &body).json().unwrap();
+        assert_eq!(response.result.unwrap(), json!([42]));
     }
 
-    pub fn is_error(&self) -> bool {
-        self.error.is_some()
-    }
+    #[test]
+    fn strict_params() {
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": 42});
 
-    pub fn is_result(&self) -> bool {
-        self.result.is_some()
-    }
-}
+        // lenient by default
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let response: Response = post(rpc.port().unwrap(), &body).json().unwrap();
+        assert_eq!(response.result.unwrap(), 42);
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct RpcError {
-    code: i64,
-    message: String,
-    data: Option<Value>,
-}
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_strict_params(true).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let response: Response = post(rpc.port().unwrap(), &body).json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_602);
 
-impl Display for RpcError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [42]});
+        let response: Response = post(rpc.port().unwrap(), &body).json().unwrap();
+        assert_eq!(response.result.unwrap(), json!([42]));
     }
-}
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum Id {
-    Number(u64),
-    String(String),
-}
 
-#[cfg(test)]
-mod test {
-    use std::{fs::File, io::Write, path::PathBuf};
+    #[test]
+    fn poisoned_state() {
+        let process = |request: Request, state: Arc<Mutex<u32>>| {
+            let mut counter = lock_state(&state);
+            *counter += 1;
+            if request.method == "panic" {
+                panic!("handler panic while holding the state lock");
+            }
+            Ok(Response::result(request.id, json!(*counter)))
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(0));
+        let rpc = JsonRpcServer::new(server, Config::default(), state.clone(), process);
+        let port = rpc.port().unwrap();
 
-    use super::*;
-    use jsonrpc::Client;
-    use serde_json::{json, value::to_raw_value};
-    use tiny_http::Server;
+        let resp = post(port, &json!({"jsonrpc": "2.0", "id": 1, "method": "panic"}));
+        assert_eq!(resp.status(), 500);
+        assert!(state.is_poisoned());
 
-    fn process(request: Request, _state: Arc<Mutex<()>>) -> Result<Response, Error> {
-        let response = match request.method.as_str() {
-            "echo" => Response {
-                jsonrpc: request.jsonrpc,
-                id: request.id,
-                result: request.params,
-                error: None,
-            },
-            _ => unimplemented!(),
-        };
-        Ok(response)
+        let body = json!({"jsonrpc": "2.0", "id": 2, "method": "increment"});
+        let response: Response = post(port, &body).json().unwrap();
+        assert_eq!(response.result.unwrap(), 2);
     }
 
     #[test]
-    fn echo() {
-        let addr = "127.0.0.1:0";
-        let server = Server::http(addr).unwrap();
+    fn set_headers() {
+        let server = Server::http("127.0.0.1:0").unwrap();
         let state = Arc::new(Mutex::new(()));
-        let mut rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let config = Config::builder()
+            .with_header(Header::from_str("Access-Control-Allow-Origin: http://a.example").unwrap())
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
         let port = rpc.port().unwrap();
-        let url = format!("127.0.0.1:{}", port);
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": []});
 
-        let client = Client::simple_http(&url, None, None).unwrap();
-        let val = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
-        let params = to_raw_value(val).unwrap();
-        let request = client.build_request("echo", Some(&params));
-        let req = request.clone();
+        let resp = post(port, &body);
+        let origin = resp.headers().get("access-control-allow-origin").unwrap();
+        assert_eq!(origin, "http://a.example");
 
-        let response = client.send_request(request).unwrap();
+        rpc.set_headers(vec![Header::from_str(
+            "Access-Control-Allow-Origin: http://b.example",
+        )
+        .unwrap()]);
+        for _ in 0..rpc.config().num_threads.get() {
+            let resp = post(port, &body);
+            let origin = resp.headers().get("access-control-allow-origin").unwrap();
+            assert_eq!(origin, "http://b.example");
+        }
+    }
 
-        assert_eq!(response.id, req.id);
-        assert_eq!(response.jsonrpc.unwrap().as_str(), req.jsonrpc.unwrap());
-        let result = response.result.unwrap();
-        let expected = serde_json::to_string(&json!(params)).unwrap();
-        assert_eq!(result.get(), expected.as_str());
+    #[test]
+    fn log_helpers() {
+        let body = "a".repeat(MAX_LOGGED_BODY);
+        assert_eq!(truncate_for_log(&body), body);
+        let body = "€".repeat(MAX_LOGGED_BODY);
+        let truncated = truncate_for_log(&body);
+        assert!(truncated.len() < body.len());
+        assert!(truncated.ends_with("bytes truncated)"));
 
-        rpc.stop();
-        rpc.join_threads();
+        let headers = [
+            Header::from_str("Content-Type: application/json").unwrap(),
+            Header::from_str("Authorization: Basic dXNlcjpwYXNz").unwrap(),
+        ];
+        let formatted = format!("{:?}", RedactedHeaders(&headers));
+        assert!(formatted.contains("Content-Type: application/json"));
+        assert!(formatted.contains("Authorization: <redacted>"));
+        assert!(!formatted.contains("dXNlcjpwYXNz"));
     }
 
     #[test]
-    fn rpc_dot_reserved() {
-        let addr = "127.0.0.1:0";
-        let server = Server::http(addr).unwrap();
+    fn html_errors() {
+        let server = Server::http("127.0.0.1:0").unwrap();
         let state = Arc::new(Mutex::new(()));
-        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
-        let port = rpc.port().unwrap();
-        let url = format!("127.0.0.1:{}", port);
-
-        let client = Client::simple_http(&url, None, None).unwrap();
-        let request = client.build_request("rpc.reserved", None);
+        let config = Config::builder().with_html_errors(true).build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let url = format!("http://127.0.0.1:{}", rpc.port().unwrap());
+        let browser_accept = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        let client = reqwest::blocking::Client::new();
 
-        let response = client.send_request(request).unwrap();
-        assert!(response.error.is_some());
-    }
+        // browsers GETting a file
+        let resp = client
+            .get(format!("{}/wallet", url))
+            .header("Accept", browser_accept)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), 500);
+        let content_type = resp.headers().get("content-type").unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(resp.text().unwrap().contains("No serve_dir defined"));
 
-    #[test]
-    fn response_serialization() {
-        // result response must not include error key
-        let response = Response {
-            jsonrpc: "2.0".into(),
-            id: Some(Id::Number(123)),
-            result: Some(Value::Bool(true)),
-            error: None,
-        };
-        let actual = serde_json::to_value(response).unwrap();
-        let expected = json!({
-            "jsonrpc": "2.0",
-            "result": true,
-            "id": 123,
-        });
-        assert_eq!(actual, expected);
-        assert!(actual.get("error").is_none());
+        // browsers POSTing a malformed request
+        let resp = client
+            .post(&url)
+            .header("Accept", browser_accept)
+            .header("Content-Type", "application/json")
+            .body("{<bad>")
+            .send()
+            .unwrap();
+        let content_type = resp.headers().get("content-type").unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        let text = resp.text().unwrap();
+        assert!(text.contains("JSON RPC error -32700"));
+        assert!(!text.contains("<bad>"));
 
-        // error response must not include result key
-        let response = Response {
-            jsonrpc: "2.0".into(),
-            id: Some(Id::Number(123)),
-            result: None,
-            error: Some(RpcError {
-                code: -32_000,
-                message: "Sunlifter".into(),
-                data: None,
-            }),
-        };
-        let actual = serde_json::to_value(response).unwrap();
-        let expected = json!({
-            "jsonrpc": "2.0",
-            "error": {
-                "code": -32000,
-                "message": "Sunlifter",
-                "data": null,
-            },
-            "id": 123,
-        });
-        assert_eq!(actual, expected);
-        assert!(actual.get("result").is_none());
+        // JSON clients still get the JSON RPC error object
+        let resp = client
+            .post(&url)
+            .header("Accept", "*/*")
+            .header("Content-Type", "application/json")
+            .body("{<bad>")
+            .send()
+            .unwrap();
+        let response: Response = resp.json().unwrap();
+        assert_eq!(response.error.unwrap().code, -32_700);
     }
 
     #[test]
-    fn http_options() {
-        let addr = "127.0.0.1:0";
-        let server = Server::http(addr).unwrap();
+    fn max_requests_per_connection() {
+        let server = Server::http("127.0.0.1:0").unwrap();
         let state = Arc::new(Mutex::new(()));
-        let config = Config {
-            headers: vec![
-                Header::from_str("Access-Control-Allow-Origin: http://127.0.0.1:8000").unwrap(),
-                Header::from_str("Access-Control-Allow-Headers: content-type").unwrap(),
-            ],
-            ..Default::default()
-        };
+        let config = Config::builder()
+            .with_max_requests_per_connection(Some(2))
+            .build();
         let rpc = JsonRpcServer::new(server, config, state, process);
         let port = rpc.port().unwrap();
-        let url = format!("http://127.0.0.1:{}", port);
 
-        let client = reqwest::blocking::Client::builder().build().unwrap();
-
-        let resp = client
-            .request(reqwest::Method::OPTIONS, url)
-            .send()
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
             .unwrap();
-        assert_eq!(resp.status(), 204);
-        assert_eq!(resp.headers().get("allow").unwrap(), "GET, POST, OPTIONS");
-        assert_eq!(
-            resp.headers().get("access-control-allow-origin").unwrap(),
-            "http://127.0.0.1:8000"
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[]}"#;
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
         );
-        assert_eq!(
-            resp.headers().get("access-control-allow-headers").unwrap(),
-            "content-type"
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut read_response = || {
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let len: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            head
+        };
+
+        stream.write_all(request.as_bytes()).unwrap();
+        let first = read_response();
+        assert!(first.starts_with("HTTP/1.1 200"));
+        assert!(!first.contains("Connection: close"));
+
+        stream.write_all(request.as_bytes()).unwrap();
+        let second = read_response();
+        assert!(second.starts_with("HTTP/1.1 200"));
+        assert!(second.contains("Connection: close"));
+    }
+
+    #[test]
+    fn self_test() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_self_test("echo", Some(json!(["ping"])))
+            .build();
+        let rpc = JsonRpcServer::try_new(server, config, state, process).unwrap();
+        let response = post(
+            rpc.port().unwrap(),
+            &json!({"jsonrpc":"2.0","id":1,"method":"echo"}),
         );
-        assert!(resp.bytes().unwrap().is_empty());
+        assert_eq!(response.status(), 200);
+        rpc.stop();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder()
+            .with_self_test("echo", None)
+            .with_self_test("rpc.echo", None)
+            .build();
+        let err = JsonRpcServer::try_new(server, config, state, process)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            Error::Inner(InnerError::SelfTest { ref method, .. }) if method == "rpc.echo"
+        ));
+    }
+
+    #[test]
+    fn drop_releases_port() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let rpc = JsonRpcServer::new(server, Config::default(), state, process);
+        let port = rpc.port().unwrap();
+        drop(rpc);
+
+        let start = std::time::Instant::now();
+        while Server::http(("127.0.0.1", port)).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "port not released"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
     }
 
     fn make_file(dir_path: PathBuf, file_name: String, data: &[u8]) -> File {
@@ -615,4 +6573,40 @@ mod test {
         assert_eq!(resp.status(), 404);
         assert_eq!(resp.text().unwrap(), "404: File not found");
     }
+
+    #[test]
+    fn download_paths() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let dir_path = tempfile::tempdir().unwrap().into_path();
+        std::fs::create_dir(dir_path.join("export")).unwrap();
+        make_file(dir_path.clone(), "export/wallet.json".to_string(), b"{}");
+        make_file(dir_path.clone(), "backup.DAT".to_string(), b"data");
+        make_file(dir_path.clone(), "exported.json".to_string(), b"{}");
+
+        let config = Config::builder()
+            .with_serve_dir(Some(dir_path))
+            .with_download_paths(vec!["/export".to_string(), ".dat".to_string()])
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let port = rpc.port().unwrap();
+
+        let disposition = |path: &str| {
+            let url = format!("http://127.0.0.1:{}/{}", port, path);
+            let resp = reqwest::blocking::get(url).unwrap();
+            assert_eq!(resp.status(), 200);
+            resp.headers()
+                .get("Content-Disposition")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            disposition("export/wallet.json").unwrap(),
+            "attachment; filename=\"wallet.json\""
+        );
+        assert_eq!(
+            disposition("backup.DAT").unwrap(),
+            "attachment; filename=\"backup.DAT\""
+        );
+        assert_eq!(disposition("exported.json"), None);
+    }
 }
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A non-blocking counting semaphore, callers are expected to reject work when no permit is
+/// available instead of waiting for one.
+pub(crate) struct Semaphore {
+    permits: usize,
+    acquired: AtomicUsize,
+}
+
+/// Releases the acquired permit when dropped.
+pub(crate) struct SemaphorePermit(Arc<Semaphore>);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            acquired: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a permit, or `None` if all the permits are currently acquired.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<SemaphorePermit> {
+        self.acquired
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.permits).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| SemaphorePermit(self.clone()))
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        self.0.acquired.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts the requests received on each connection, identified by the client address.
+pub(crate) struct ConnectionLimiter {
+    max: usize,
+    requests: Mutex<HashMap<SocketAddr, (usize, Instant)>>,
+}
+
+/// Tracked connections are pruned when exceeding this number.
+const MAX_TRACKED_CONNECTIONS: usize = 1024;
+
+/// Connections idle for longer than this are considered closed when pruning.
+const CONNECTION_IDLE: Duration = Duration::from_secs(60);
+
+impl ConnectionLimiter {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `addr`, returns true if the connection reached the limit and should
+    /// be closed.
+    pub(crate) fn count(&self, addr: SocketAddr) -> bool {
+        let mut requests = self
+            .requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        if requests.len() >= MAX_TRACKED_CONNECTIONS {
+            requests.retain(|_, (_, last)| now.duration_since(*last) < CONNECTION_IDLE);
+        }
+        let entry = requests.entry(addr).or_insert((0, now));
+        entry.0 += 1;
+        entry.1 = now;
+        if entry.0 >= self.max {
+            requests.remove(&addr);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits the requests handled concurrently for each client IP address.
+pub(crate) struct IpLimiter {
+    max: usize,
+    // addresses without requests in flight are removed
+    in_flight: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Releases the slot of the IP address when dropped.
+pub(crate) struct IpPermit<'a> {
+    limiter: &'a IpLimiter,
+    ip: IpAddr,
+}
+
+impl IpLimiter {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a permit, or `None` if `ip` already has the maximum number of requests in flight.
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit<'_>> {
+        let mut in_flight = self.lock();
+        let count = in_flight.entry(ip).or_insert(0);
+        if *count >= self.max {
+            if *count == 0 {
+                in_flight.remove(&ip);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit { limiter: self, ip })
+    }
+
+    /// Takes a slot of `ip` regardless of the limit, for a request already holding one. The slot
+    /// is given back with [`IpLimiter::release`].
+    pub(crate) fn hold(&self, ip: IpAddr) {
+        *self.lock().entry(ip).or_insert(0) += 1;
+    }
+
+    /// Gives back a slot of `ip`.
+    pub(crate) fn release(&self, ip: IpAddr) {
+        let mut in_flight = self.lock();
+        if let Some(count) = in_flight.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&ip);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for IpPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn semaphore() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let first = semaphore.try_acquire().unwrap();
+        let _second = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        drop(first);
+        assert!(semaphore.try_acquire().is_some());
+
+        let semaphore = Arc::new(Semaphore::new(0));
+        assert!(semaphore.try_acquire().is_none());
+    }
+
+    #[test]
+    fn connection_limiter() {
+        let limiter = ConnectionLimiter::new(2);
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        assert!(!limiter.count(a));
+        assert!(!limiter.count(b));
+        assert!(limiter.count(a));
+        // counting restarts for a new connection reusing the address
+        assert!(!limiter.count(a));
+        assert!(limiter.count(b));
+    }
+
+    #[test]
+    fn ip_limiter() {
+        let limiter = IpLimiter::new(1);
+        let a = IpAddr::from([127, 0, 0, 1]);
+        let b = IpAddr::from([127, 0, 0, 2]);
+        let first = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        let _other = limiter.try_acquire(b).unwrap();
+        drop(first);
+        assert!(limiter.try_acquire(a).is_some());
+        assert_eq!(limiter.lock().len(), 1);
+    }
+}
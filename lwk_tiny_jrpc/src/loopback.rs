@@ -0,0 +1,135 @@
+//! An in-memory transport calling the JSON RPC handler without sockets, for tests.
+//!
+//! The requests go through the same validation and dispatch as the ones received by a
+//! [`JsonRpcServer`], such as the version check and the reserved method prefix, so the
+//! responses are the ones a client would receive over HTTP.
+
+use std::sync::{Arc, Mutex};
+
+use tiny_http::Header;
+
+use crate::{
+    dispatch, handler::Dispatch, response_body, Config, Error, JsonRpcServer, Reply, Request,
+    RequestContext, Response, ServerState, Shared,
+};
+
+/// Calls the JSON RPC handler in the current thread, without a [`JsonRpcServer`] listening.
+///
+/// Features depending on the connection, such as the per IP limits, and static file serving are
+/// not available.
+#[derive(Clone)]
+pub struct LoopbackClient {
+    shared: Arc<Shared>,
+    handler: Arc<dyn Dispatch<Principal = ()>>,
+    headers: Vec<Header>,
+}
+
+impl LoopbackClient {
+    pub fn new<F, T>(config: Config, state: Arc<Mutex<T>>, func: F) -> Self
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let content_type = "Content-Type: application/json"
+            .parse()
+            .expect("valid header");
+        Self {
+            shared: Arc::new(Shared::new(config)),
+            handler: JsonRpcServer::handler(state, func),
+            headers: vec![content_type],
+        }
+    }
+
+    /// Adds a header to the requests, as seen by the handler in the [`RequestContext`] and by
+    /// the authentication.
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// Sends the request and returns its response, `None` for notifications.
+    pub fn call(&self, request: &Request) -> Option<Response> {
+        let body = serde_json::to_vec(request).expect("requests serialize");
+        let (_, body) = self.send(&body);
+        (!body.is_empty()).then(|| serde_json::from_str(&body).expect("responses deserialize"))
+    }
+
+    /// Sends a raw request body, such as a batch or invalid JSON, returning the HTTP status and
+    /// the body of the response, which is empty for notifications.
+    pub fn send(&self, body: &[u8]) -> (u16, String) {
+        let context = RequestContext::new(None, "/", self.headers.clone());
+        let (status, reply) = dispatch(&context, body, &self.shared, &self.handler);
+        match reply {
+            Reply::Empty => (status, String::new()),
+            reply => (status, response_body(&reply, &self.shared)),
+        }
+    }
+
+    // true once a handler returned `Error::Stop`
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.shared.state() == ServerState::Stopped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn loopback() {
+        let state = Arc::new(Mutex::new(()));
+        let client = LoopbackClient::new(Config::default(), state, |request: Request, _| {
+            Ok(Response::result(
+                request.id,
+                request.params.unwrap_or_default(),
+            ))
+        });
+
+        let request: Request = serde_json::from_value(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["hi"]}),
+        )
+        .unwrap();
+        let response = client.call(&request).unwrap();
+        assert_eq!(response.result, Some(json!(["hi"])));
+
+        let reserved = Request {
+            method: "rpc.echo".to_string(),
+            ..request.clone()
+        };
+        assert_eq!(client.call(&reserved).unwrap().error.unwrap().code, -32_003);
+
+        let notification = Request {
+            id: None,
+            ..request
+        };
+        assert!(client.call(&notification).is_none());
+
+        let (status, body) = client.send(b"{");
+        assert_eq!(status, 200);
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["error"]["code"], -32_700);
+    }
+
+    #[test]
+    fn loopback_jsonrpc_1() {
+        let state = Arc::new(Mutex::new(()));
+        let config = Config::builder().with_jsonrpc_1_compat(true).build();
+        let client = LoopbackClient::new(config, state, |request: Request, _| {
+            Ok(Response::result(
+                request.id,
+                request.params.unwrap_or_default(),
+            ))
+        });
+
+        let request: Request = serde_json::from_value(
+            json!({"jsonrpc": "1.0", "id": 1, "method": "echo", "params": ["hi"]}),
+        )
+        .unwrap();
+        let response = client.call(&request).unwrap();
+        assert_eq!(response.jsonrpc, "1.0");
+        assert_eq!(response.result, Some(json!(["hi"])));
+        assert!(response.error.is_none());
+    }
+}
@@ -0,0 +1,303 @@
+//! Request metrics, available with the `metrics` feature.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+/// Upper bounds (exclusive) of the latency histogram buckets, the last bucket counts the
+/// requests taking at least one second.
+pub const LATENCY_BUCKETS: [Duration; 4] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Methods are tracked individually up to this number, to bound the memory used when clients
+/// call arbitrary method names. Calls to further methods are counted only in the total.
+const MAX_TRACKED_METHODS: usize = 256;
+
+/// A point in time copy of the server metrics, see [`crate::JsonRpcServer::metrics`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// The number of JSON RPC requests passed to the method handler.
+    pub requests: u64,
+    /// The number of JSON RPC request body bytes received, before decompression.
+    pub bytes_in: u64,
+    /// The number of response body bytes sent, across JSON RPC, file and OPTIONS requests.
+    pub bytes_out: u64,
+    /// Metrics by method name.
+    pub methods: HashMap<String, MethodMetrics>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MethodMetrics {
+    /// The number of calls to the method.
+    pub count: u64,
+    /// The number of calls by latency, bucketed according to [`LATENCY_BUCKETS`].
+    pub latency: [u64; LATENCY_BUCKETS.len() + 1],
+    /// The total latency of the calls, with microsecond precision.
+    pub latency_sum: Duration,
+}
+
+#[derive(Default)]
+struct MethodCounters {
+    count: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    methods: RwLock<HashMap<String, Arc<MethodCounters>>>,
+}
+
+impl Metrics {
+    /// Records a call to `method` that took `elapsed`.
+    pub(crate) fn record(&self, method: &str, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let Some(counters) = self.method_counters(method) else {
+            return;
+        };
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed < *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        counters
+            .latency_sum_micros
+            .fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_out(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // the write lock is taken only the first time a method is seen
+    fn method_counters(&self, method: &str) -> Option<Arc<MethodCounters>> {
+        let methods = self
+            .methods
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(counters) = methods.get(method) {
+            return Some(counters.clone());
+        }
+        drop(methods);
+        let mut methods = self
+            .methods
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !methods.contains_key(method) && methods.len() >= MAX_TRACKED_METHODS {
+            return None;
+        }
+        Some(methods.entry(method.to_string()).or_default().clone())
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        let methods = self
+            .methods
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        MetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            methods: methods
+                .iter()
+                .map(|(method, counters)| {
+                    let metrics = MethodMetrics {
+                        count: counters.count.load(Ordering::Relaxed),
+                        latency: std::array::from_fn(|i| {
+                            counters.latency[i].load(Ordering::Relaxed)
+                        }),
+                        latency_sum: Duration::from_micros(
+                            counters.latency_sum_micros.load(Ordering::Relaxed),
+                        ),
+                    };
+                    (method.clone(), metrics)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics and the HTTP `status_counts` in the Prometheus text exposition
+    /// format, to be served from a `/metrics` endpoint with content type
+    /// `text/plain; version=0.0.4`.
+    pub fn to_prometheus(&self, status_counts: &HashMap<u16, u64>) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "jsonrpc_requests_total",
+                "JSON RPC requests passed to the method handler.",
+                self.requests,
+            ),
+            (
+                "jsonrpc_received_bytes_total",
+                "JSON RPC request body bytes received.",
+                self.bytes_in,
+            ),
+            (
+                "jsonrpc_sent_bytes_total",
+                "Response body bytes sent.",
+                self.bytes_out,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let mut methods: Vec<_> = self.methods.iter().collect();
+        methods.sort_by(|a, b| a.0.cmp(b.0));
+        let name = "jsonrpc_method_requests_total";
+        header(&mut out, name, "JSON RPC requests by method.", "counter");
+        for (method, metrics) in &methods {
+            let method = escape_label(method);
+            let _ = writeln!(out, "{}{{method=\"{}\"}} {}", name, method, metrics.count);
+        }
+        let name = "jsonrpc_method_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "JSON RPC handler latency by method.",
+            "histogram",
+        );
+        for (method, metrics) in &methods {
+            let method = escape_label(method);
+            // prometheus buckets are cumulative
+            let mut cumulative = 0;
+            for (i, count) in metrics.latency.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    name, method, le, cumulative
+                );
+            }
+            let sum = metrics.latency_sum.as_secs_f64();
+            let _ = writeln!(out, "{}_sum{{method=\"{}\"}} {}", name, method, sum);
+            let _ = writeln!(
+                out,
+                "{}_count{{method=\"{}\"}} {}",
+                name, method, cumulative
+            );
+        }
+
+        let mut statuses: Vec<_> = status_counts.iter().collect();
+        statuses.sort();
+        let name = "jsonrpc_http_responses_total";
+        header(&mut out, name, "HTTP responses by status code.", "counter");
+        for (status, count) in statuses {
+            let _ = writeln!(out, "{}{{status=\"{}\"}} {}", name, status, count);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// escapes a label value, method names are chosen by clients
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_buckets() {
+        let metrics = Metrics::default();
+        metrics.record("a", Duration::from_micros(10));
+        metrics.record("a", Duration::from_millis(1));
+        metrics.record("a", Duration::from_millis(500));
+        metrics.record("b", Duration::from_secs(3));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests, 4);
+        assert_eq!(snapshot.methods["a"].count, 3);
+        assert_eq!(snapshot.methods["a"].latency, [1, 1, 0, 1, 0]);
+        assert_eq!(snapshot.methods["b"].latency, [0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn prometheus() {
+        let metrics = Metrics::default();
+        metrics.record("a", Duration::from_micros(10));
+        metrics.record("a", Duration::from_millis(500));
+        metrics.record("we\"ird\n", Duration::from_secs(3));
+        metrics.record_bytes_in(100);
+        let status_counts = HashMap::from([(200, 2), (404, 1)]);
+        let text = metrics.snapshot().to_prometheus(&status_counts);
+
+        assert!(text.contains("jsonrpc_requests_total 3\n"));
+        assert!(text.contains("jsonrpc_received_bytes_total 100\n"));
+        assert!(
+            text.contains("jsonrpc_method_duration_seconds_bucket{method=\"a\",le=\"0.001\"} 1\n")
+        );
+        assert!(
+            text.contains("jsonrpc_method_duration_seconds_bucket{method=\"a\",le=\"+Inf\"} 2\n")
+        );
+        assert!(text.contains("jsonrpc_method_duration_seconds_sum{method=\"a\"} 0.50001\n"));
+        assert!(text.contains("jsonrpc_method_requests_total{method=\"we\\\"ird\\n\"} 1\n"));
+        assert!(text.contains("jsonrpc_http_responses_total{status=\"404\"} 1\n"));
+
+        // every line is a comment or a sample: a metric name, optional labels and a number
+        for line in text.lines() {
+            if line.starts_with("# HELP ") || line.starts_with("# TYPE ") {
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+            let name = series.split('{').next().unwrap();
+            assert!(!name.is_empty(), "{}", line);
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{}",
+                line
+            );
+            if let Some(labels) = series.strip_prefix(name).filter(|l| !l.is_empty()) {
+                let labels = labels.strip_prefix('{').unwrap().strip_suffix('}').unwrap();
+                assert!(labels.contains("=\""), "{}", line);
+                assert!(!labels.contains('\n'), "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn max_tracked_methods() {
+        let metrics = Metrics::default();
+        for i in 0..MAX_TRACKED_METHODS + 10 {
+            metrics.record(&i.to_string(), Duration::ZERO);
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests, MAX_TRACKED_METHODS as u64 + 10);
+        assert_eq!(snapshot.methods.len(), MAX_TRACKED_METHODS);
+    }
+}
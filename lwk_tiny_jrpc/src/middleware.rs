@@ -0,0 +1,26 @@
+//! Hooks run around the method handler of every request.
+
+use crate::{Error, Request, Response};
+
+/// Intercepts requests before they reach the method handler and their responses, to implement
+/// cross-cutting concerns such as authorization, logging or request rewriting.
+///
+/// Middleware is configured with [`crate::config::ConfigBuilder::with_middleware`] and stacked in
+/// the order it's added: the `before` hooks run in that order, the `after` hooks in reverse order.
+/// Each request of a batch goes through the whole stack.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called with a validated request before the method handler, returning the request to
+    /// pass on, possibly rewritten.
+    ///
+    /// An error answers the request with it, neither the following middleware nor the handler
+    /// are called.
+    fn before(&self, request: Request) -> Result<Request, Error> {
+        Ok(request)
+    }
+
+    /// Called with the response, of the method handler or of an error, if the `before` hook of
+    /// this middleware succeeded.
+    fn after(&self, response: Response) -> Response {
+        response
+    }
+}
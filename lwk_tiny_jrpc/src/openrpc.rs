@@ -0,0 +1,159 @@
+//! OpenRPC documents describing the methods of a [`crate::Router`], available with the `openrpc`
+//! feature.
+//!
+//! The document is served by the router at the `rpc.discover` method, the schemas of the params
+//! and results are derived with [`schemars`] from the types of the handlers registered with
+//! [`crate::Router::register_with_schema`].
+//!
+//! See <https://spec.open-rpc.org/>.
+
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{Schema, SingleOrVec},
+    JsonSchema,
+};
+use serde_json::{json, Value};
+
+/// The name of the method serving the OpenRPC document.
+pub const DISCOVER_METHOD: &str = "rpc.discover";
+
+/// The OpenRPC specification version of the generated documents.
+const OPENRPC_VERSION: &str = "1.2.6";
+
+/// Generates the schemas of the params and the result of a method.
+pub(crate) type SchemaFn = fn(&mut SchemaGenerator) -> (Schema, Schema);
+
+pub(crate) fn schema_fn<P: JsonSchema, R: JsonSchema>() -> SchemaFn {
+    |gen| (gen.subschema_for::<P>(), gen.subschema_for::<R>())
+}
+
+/// Builds the OpenRPC document of the given methods, sorted by name, the ones without schemas are
+/// listed without params nor result.
+pub(crate) fn document(title: &str, version: &str, methods: &[(&str, Option<SchemaFn>)]) -> Value {
+    let mut gen = SchemaSettings::draft07()
+        .with(|settings| settings.definitions_path = "#/components/schemas/".into())
+        .into_generator();
+    let methods: Vec<_> = methods
+        .iter()
+        .map(|(name, schema_fn)| match schema_fn {
+            Some(schema_fn) => {
+                let (params, result) = schema_fn(&mut gen);
+                let (structure, params) = content_descriptors(&gen, params);
+                json!({
+                    "name": name,
+                    "paramStructure": structure,
+                    "params": params,
+                    "result": {"name": "result", "schema": result},
+                })
+            }
+            None => json!({"name": name, "params": []}),
+        })
+        .collect();
+    json!({
+        "openrpc": OPENRPC_VERSION,
+        "info": {"title": title, "version": version},
+        "methods": methods,
+        "components": {"schemas": gen.take_definitions()},
+    })
+}
+
+// splits the params schema into one content descriptor per param: the properties of an object
+// are named params, the items of a tuple are positional params, any other params are described
+// as a whole
+fn content_descriptors(gen: &SchemaGenerator, params: Schema) -> (&'static str, Vec<Value>) {
+    let resolved = match &params {
+        Schema::Object(object) => object
+            .reference
+            .as_deref()
+            .and_then(|reference| reference.strip_prefix("#/components/schemas/"))
+            .and_then(|name| gen.definitions().get(name))
+            .unwrap_or(&params),
+        Schema::Bool(_) => &params,
+    };
+    if let Schema::Object(object) = resolved {
+        if let Some(validation) = &object.object {
+            let descriptors = validation
+                .properties
+                .iter()
+                .map(|(name, schema)| {
+                    json!({
+                        "name": name,
+                        "required": validation.required.contains(name),
+                        "schema": schema,
+                    })
+                })
+                .collect();
+            return ("by-name", descriptors);
+        }
+        if let Some(SingleOrVec::Vec(items)) = object.array.as_ref().and_then(|a| a.items.as_ref())
+        {
+            let descriptors = items
+                .iter()
+                .enumerate()
+                .map(|(i, schema)| {
+                    json!({"name": format!("param{}", i), "required": true, "schema": schema})
+                })
+                .collect();
+            return ("by-position", descriptors);
+        }
+    }
+    ("either", vec![json!({"name": "params", "schema": params})])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use schemars::JsonSchema;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Named {
+        a: u32,
+        b: Option<String>,
+    }
+
+    #[test]
+    fn document() {
+        let methods = [
+            ("named", Some(schema_fn::<Named, bool>())),
+            ("positional", Some(schema_fn::<(u32, String), u32>())),
+            ("whole", Some(schema_fn::<Vec<u32>, ()>())),
+            ("raw", None),
+        ];
+        let doc = super::document("test", "1.0", &methods);
+        assert_eq!(doc["openrpc"], "1.2.6");
+        assert_eq!(doc["info"], json!({"title": "test", "version": "1.0"}));
+
+        let named = &doc["methods"][0];
+        assert_eq!(named["paramStructure"], "by-name");
+        let params: BTreeMap<_, _> = named["params"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| {
+                (
+                    p["name"].as_str().unwrap(),
+                    p["required"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(params, BTreeMap::from([("a", true), ("b", false)]));
+        assert_eq!(named["result"]["schema"]["type"], "boolean");
+        assert!(doc["components"]["schemas"]["Named"].is_object());
+
+        let positional = &doc["methods"][1];
+        assert_eq!(positional["paramStructure"], "by-position");
+        assert_eq!(positional["params"][1]["name"], "param1");
+        assert_eq!(positional["params"][1]["schema"]["type"], "string");
+
+        let whole = &doc["methods"][2];
+        assert_eq!(whole["paramStructure"], "either");
+        assert_eq!(whole["params"][0]["schema"]["type"], "array");
+
+        assert_eq!(doc["methods"][3], json!({"name": "raw", "params": []}));
+    }
+}
@@ -0,0 +1,99 @@
+//! Detection of method handlers calling back into the server handling them.
+
+use std::{
+    cell::Cell,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+thread_local! {
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The method handler calls in progress on a server, the threads running them are marked with
+/// the marker of the server.
+pub(crate) struct Handlers {
+    marker: u64,
+    running: AtomicUsize,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        Self {
+            marker: COUNTER.fetch_add(1, Ordering::Relaxed),
+            running: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Handlers {
+    /// Runs `f`, a method handler call, marking the current thread as running a handler of this
+    /// server.
+    pub(crate) fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.with(|current| current.replace(Some(self.marker)));
+        self.running.fetch_add(1, Ordering::SeqCst);
+        // restores the previous marker even if `f` panics
+        struct Restore<'a>(&'a Handlers, Option<u64>);
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                CURRENT.with(|current| current.set(self.1));
+                self.0.running.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        let _restore = Restore(self, previous);
+        f()
+    }
+
+    /// Returns true if the request from `client` is made by a method handler of this server:
+    /// dispatched in process by the thread running the handler, such as through a
+    /// [`crate::loopback::LoopbackClient`], or received from a loopback address that is the
+    /// local address of a socket of this process while a handler is running.
+    pub(crate) fn is_reentrant(&self, client: Option<SocketAddr>) -> bool {
+        if CURRENT.with(Cell::get) == Some(self.marker) {
+            return true;
+        }
+        let Some(client) = client.filter(|addr| addr.ip().is_loopback()) else {
+            return false;
+        };
+        if self.running.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let mut local = false;
+        crate::visit_sockets(|stream| {
+            local |= stream.local_addr().is_ok_and(|addr| addr == client);
+            false
+        });
+        local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::Handlers;
+
+    #[test]
+    fn reentrant() {
+        let (first, second) = (Handlers::default(), Handlers::default());
+        assert!(!first.is_reentrant(None));
+        first.scope(|| {
+            assert!(first.is_reentrant(None));
+            assert!(!second.is_reentrant(None));
+        });
+        assert!(!first.is_reentrant(None));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let client = Some(stream.local_addr().unwrap());
+        assert!(!first.is_reentrant(client));
+        // checked on another thread, like the worker receiving the request
+        let on_worker = || thread::scope(|s| s.spawn(|| first.is_reentrant(client)).join());
+        assert!(first.scope(on_worker).unwrap());
+        assert!(!on_worker().unwrap());
+    }
+}
@@ -0,0 +1,254 @@
+//! Dispatch of requests to handlers registered per method.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "openrpc")]
+use serde_json::Value;
+
+use crate::{Error, Request, Response};
+
+/// A handler of a single method, taking the whole request and building the response.
+pub type MethodHandler<T> =
+    Arc<dyn Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync>;
+
+/// A method handler declared with the `rpc_method` attribute, available with the `macros`
+/// feature, registered with [`Router::register_method`].
+pub trait RpcMethod<T> {
+    /// The method name.
+    const NAME: &'static str;
+
+    /// Deserializes the params, calls the handler and serializes its result.
+    fn call(request: Request, state: Arc<Mutex<T>>) -> Result<Response, Error>;
+}
+
+/// The state type of the `Arc<Mutex<T>>` taken by method handlers, letting the `rpc_method`
+/// attribute name `T` from the handler signature.
+pub trait SharedState {
+    type State;
+}
+
+impl<T> SharedState for Arc<Mutex<T>> {
+    type State = T;
+}
+
+/// Dispatches requests by method name to the registered handlers, answering unknown methods with
+/// a method not found error. See [`crate::JsonRpcServer::new_with_router`].
+///
+/// With the `openrpc` feature the router also answers `rpc.discover` with the OpenRPC document
+/// describing the registered methods, see the `openrpc` module.
+pub struct Router<T> {
+    methods: HashMap<String, MethodHandler<T>>,
+    #[cfg(feature = "openrpc")]
+    schemas: HashMap<String, crate::openrpc::SchemaFn>,
+    #[cfg(feature = "openrpc")]
+    info: (String, String),
+}
+
+impl<T: Send + 'static> Router<T> {
+    pub fn new() -> Self {
+        Self {
+            methods: HashMap::new(),
+            #[cfg(feature = "openrpc")]
+            schemas: HashMap::new(),
+            #[cfg(feature = "openrpc")]
+            info: ("JSON RPC server".into(), "0.0.0".into()),
+        }
+    }
+
+    /// Registers the handler of `method`, replacing the previous one if any.
+    ///
+    /// The params are deserialized into `P` with [`Request::params_as`], failing with an invalid
+    /// params error, and the result `R` is serialized into the response.
+    pub fn register<P, R, F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P, Arc<Mutex<T>>) -> Result<R, Error> + Send + Sync + 'static,
+    {
+        self.register_raw(method, move |request, state| {
+            let params = request.params_as()?;
+            let result = handler(params, state)?;
+            Response::ok(request.id, result)
+        })
+    }
+
+    /// Registers the handler of `method` taking the whole request, for handlers needing the
+    /// request id or building responses with HTTP extras such as [`Response::with_raw`].
+    pub fn register_raw<F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+    {
+        self.methods.insert(method.to_string(), Arc::new(handler));
+        #[cfg(feature = "openrpc")]
+        self.schemas.remove(method);
+        self
+    }
+
+    /// Registers the handler of `method` like [`Router::register`], documenting its params and
+    /// result in the OpenRPC document with the JSON schemas of `P` and `R`.
+    #[cfg(feature = "openrpc")]
+    pub fn register_with_schema<P, R, F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        P: DeserializeOwned + schemars::JsonSchema,
+        R: Serialize + schemars::JsonSchema,
+        F: Fn(P, Arc<Mutex<T>>) -> Result<R, Error> + Send + Sync + 'static,
+    {
+        self.register(method, handler);
+        let schema_fn = crate::openrpc::schema_fn::<P, R>();
+        self.schemas.insert(method.to_string(), schema_fn);
+        self
+    }
+
+    /// Sets the title and version of the API in the OpenRPC document.
+    #[cfg(feature = "openrpc")]
+    pub fn set_info(&mut self, title: &str, version: &str) -> &mut Self {
+        self.info = (title.to_string(), version.to_string());
+        self
+    }
+
+    /// Returns the OpenRPC document describing the registered methods.
+    #[cfg(feature = "openrpc")]
+    pub fn openrpc(&self) -> Value {
+        let methods: Vec<_> = self
+            .methods()
+            .into_iter()
+            .map(|method| (method, self.schemas.get(method).copied()))
+            .collect();
+        crate::openrpc::document(&self.info.0, &self.info.1, &methods)
+    }
+
+    /// Registers a method handler declared with the `rpc_method` attribute, such as
+    /// `router.register_method::<send>()` for the function `send`.
+    pub fn register_method<M: RpcMethod<T> + 'static>(&mut self) -> &mut Self {
+        self.register_raw(M::NAME, M::call)
+    }
+
+    /// Returns the registered methods, sorted.
+    pub fn methods(&self) -> Vec<&str> {
+        let mut methods: Vec<_> = self.methods.keys().map(String::as_str).collect();
+        methods.sort_unstable();
+        methods
+    }
+
+    /// Calls the handler registered for the request method.
+    pub fn handle(&self, request: Request, state: Arc<Mutex<T>>) -> Result<Response, Error> {
+        match self.methods.get(&request.method) {
+            Some(handler) => handler(request, state),
+            #[cfg(feature = "openrpc")]
+            None if request.method == crate::openrpc::DISCOVER_METHOD => {
+                Ok(Response::result(request.id, self.openrpc()))
+            }
+            None => {
+                let message = format!("Method not found: {}", request.method);
+                Ok(Response::unimplemented(request.id, message))
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Router<T> {
+    fn clone(&self) -> Self {
+        Self {
+            methods: self.methods.clone(),
+            #[cfg(feature = "openrpc")]
+            schemas: self.schemas.clone(),
+            #[cfg(feature = "openrpc")]
+            info: self.info.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Router<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut methods: Vec<_> = self.methods.keys().collect();
+        methods.sort_unstable();
+        f.debug_struct("Router").field("methods", &methods).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{error::AsRpcError, Id};
+
+    #[test]
+    fn router() {
+        let mut router = Router::new();
+        router
+            .register("add", |(a, b): (u64, u64), state: Arc<Mutex<u64>>| {
+                *state.lock().unwrap() += 1;
+                Ok(a + b)
+            })
+            .register_raw("id", |request, _state| {
+                Ok(Response::result(request.id.clone(), json!(request.id)))
+            });
+        assert_eq!(router.methods(), vec!["add", "id"]);
+
+        let state = Arc::new(Mutex::new(0));
+        let request = |method: &str, params| Request {
+            jsonrpc: "2.0".into(),
+            id: Some(Id::Number(1.into())),
+            method: method.into(),
+            params: Some(params),
+        };
+        let response = router.handle(request("add", json!([1, 2])), state.clone());
+        assert_eq!(response.unwrap().result, Some(json!(3)));
+        assert_eq!(*state.lock().unwrap(), 1);
+
+        let err = router
+            .handle(request("add", json!(["a"])), state.clone())
+            .unwrap_err();
+        assert_eq!(err.as_rpc_error().code, -32_602);
+
+        let response = router.handle(request("id", json!(null)), state.clone());
+        assert_eq!(response.unwrap().result, Some(json!(1)));
+
+        let response = router.handle(request("sub", json!([])), state).unwrap();
+        assert_eq!(response.error.unwrap().code, -32_601);
+    }
+
+    #[cfg(feature = "openrpc")]
+    #[test]
+    fn discover() {
+        let mut router = Router::new();
+        router
+            .set_info("calc", "1.2.3")
+            .register_with_schema(
+                "add",
+                |(a, b): (u64, u64), _state: Arc<Mutex<()>>| Ok(a + b),
+            )
+            .register("sub", |(a, b): (u64, u64), _state| Ok(a - b));
+        let state = Arc::new(Mutex::new(()));
+        let discover = Request {
+            jsonrpc: "2.0".into(),
+            id: Some(Id::Number(1.into())),
+            method: "rpc.discover".into(),
+            params: None,
+        };
+        let doc = router.handle(discover, state).unwrap().result.unwrap();
+        assert_eq!(doc["info"]["title"], "calc");
+        assert_eq!(doc["methods"][0]["name"], "add");
+        assert_eq!(doc["methods"][0]["params"].as_array().unwrap().len(), 2);
+        assert_eq!(doc["methods"][1], json!({"name": "sub", "params": []}));
+
+        // re-registering without schema drops it
+        router.register_raw("add", |request, _state| {
+            Ok(Response::result(request.id, json!(0)))
+        });
+        assert_eq!(router.openrpc()["methods"][0]["params"], json!([]));
+    }
+}
@@ -0,0 +1,170 @@
+//! Serves JSON RPC over a pair of streams, such as stdin and stdout, with the messages framed by
+//! a `Content-Length` header like in the Language Server Protocol:
+//!
+//! ```text
+//! Content-Length: 55\r\n
+//! \r\n
+//! {"jsonrpc":"2.0","id":1,"method":"version","params":[]}
+//! ```
+//!
+//! This allows running as a subprocess of another application without any network exposure.
+
+use std::{
+    io::{self, BufRead, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{loopback::LoopbackClient, Config, Error, Request, Response, MAX_UNREAD_BODY};
+
+/// Handles the requests read from stdin until it is closed or a handler returns
+/// [`Error::Stop`], writing the responses to stdout, see [`serve`].
+pub fn serve_stdio<F, T>(config: Config, state: Arc<Mutex<T>>, func: F) -> io::Result<()>
+where
+    F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    serve(io::stdin().lock(), io::stdout().lock(), config, state, func)
+}
+
+/// Handles the requests read from `reader` one at a time until the end of the stream or until a
+/// handler returns [`Error::Stop`], writing each response to `writer`. Notifications get no
+/// response.
+///
+/// The requests are validated and dispatched like the ones received by a
+/// [`crate::JsonRpcServer`], the message headers other than `Content-Length` are ignored.
+/// Returns an error if reading or writing fails, or if a message is not correctly framed.
+pub fn serve<R, W, F, T>(
+    mut reader: R,
+    mut writer: W,
+    config: Config,
+    state: Arc<Mutex<T>>,
+    func: F,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let client = LoopbackClient::new(config, state, func);
+    while let Some(length) = read_headers(&mut reader)? {
+        let mut body = Vec::new();
+        (&mut reader).take(length).read_to_end(&mut body)?;
+        if body.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (_, response) = client.send(&body);
+        if !response.is_empty() {
+            write!(
+                writer,
+                "Content-Length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            )?;
+            writer.flush()?;
+        }
+        if client.is_stopped() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// reads the headers of a message up to the empty line, returning its `Content-Length`, or `None`
+// at the end of the stream
+fn read_headers(reader: &mut impl BufRead) -> io::Result<Option<u64>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return match length {
+                None if line.is_empty() => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("invalid message header"))?;
+        if name.trim().eq_ignore_ascii_case("Content-Length") {
+            let value = value
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| invalid("invalid Content-Length"))?;
+            length = Some(value);
+        }
+    }
+    match length {
+        Some(length) if length > MAX_UNREAD_BODY => Err(invalid("message too large")),
+        Some(length) => Ok(Some(length)),
+        None => Err(invalid("missing Content-Length")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn frame(message: &Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn stdio() {
+        let input = [
+            frame(&json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1]})),
+            frame(&json!({"jsonrpc": "2.0", "method": "echo"})),
+            frame(&json!({"jsonrpc": "2.0", "id": 2, "method": "stop"})),
+            frame(&json!({"jsonrpc": "2.0", "id": 3, "method": "echo"})),
+        ]
+        .concat();
+        let mut output = Vec::new();
+        let state = Arc::new(Mutex::new(()));
+        serve(
+            input.as_bytes(),
+            &mut output,
+            Config::default(),
+            state,
+            |request: Request, _| match request.method.as_str() {
+                "stop" => Err(Error::Stop),
+                _ => Ok(Response::result(
+                    request.id,
+                    request.params.unwrap_or_default(),
+                )),
+            },
+        )
+        .unwrap();
+
+        // no response to the notification, nor to the request after stopping
+        let output = String::from_utf8(output).unwrap();
+        let mut reader = output.as_bytes();
+        let mut responses = vec![];
+        while let Some(length) = read_headers(&mut reader).unwrap() {
+            let (body, rest) = reader.split_at(length as usize);
+            responses.push(serde_json::from_slice::<Response>(body).unwrap());
+            reader = rest;
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].result, Some(json!([1])));
+        assert_eq!(responses[1].error.as_ref().unwrap().code, -32_099);
+
+        let mut output = Vec::new();
+        let state = Arc::new(Mutex::new(()));
+        let result = serve(
+            "Content-Type: application/json\r\n\r\n{}".as_bytes(),
+            &mut output,
+            Config::default(),
+            state,
+            |request: Request, _| Ok(Response::result(request.id, Value::Null)),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,132 @@
+//! Events long poll handlers wait for.
+
+use std::{
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// A source of events that long poll method handlers wait for, such as a new transaction, so
+/// that clients don't need to busy poll. See [`crate::Config::long_poll`].
+///
+/// Events are numbered from 1 in the order they are notified. Only the latest event is kept: a
+/// client passing the number of the last event it received gets the latest one if it missed
+/// any, or waits for the next. Handlers not receiving an event in time are expected to return
+/// [`crate::Response::long_poll_timeout`].
+pub struct Subscription<E> {
+    inner: Arc<(Mutex<State<E>>, Condvar)>,
+}
+
+struct State<E> {
+    seq: u64,
+    latest: Option<E>,
+}
+
+impl<E: Clone> Subscription<E> {
+    pub fn new() -> Self {
+        let state = State {
+            seq: 0,
+            latest: None,
+        };
+        Self {
+            inner: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    /// Notifies an event, waking up all the waiting handlers. Returns its number.
+    pub fn notify(&self, event: E) -> u64 {
+        let mut state = self.lock();
+        state.seq += 1;
+        state.latest = Some(event);
+        self.inner.1.notify_all();
+        state.seq
+    }
+
+    /// Returns the number of the latest event, 0 if none was notified yet.
+    pub fn last_seq(&self) -> u64 {
+        self.lock().seq
+    }
+
+    /// Returns the latest event and its number if it is after the event numbered `after`,
+    /// otherwise waits for the next event at most for `timeout`. Returns `None` if none was
+    /// notified in time.
+    pub fn wait(&self, after: u64, timeout: Duration) -> Option<(u64, E)> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while state.seq <= after {
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            state = self
+                .inner
+                .1
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        state.latest.clone().map(|event| (state.seq, event))
+    }
+
+    /// Waits for the next event at most for `timeout`, ignoring the already notified ones.
+    pub fn wait_next(&self, timeout: Duration) -> Option<(u64, E)> {
+        self.wait(self.last_seq(), timeout)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<E>> {
+        self.inner
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<E: Clone> Default for Subscription<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Clone for Subscription<E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for Subscription<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seq = self.inner.0.lock().map(|state| state.seq).ok();
+        f.debug_struct("Subscription").field("seq", &seq).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::Subscription;
+
+    #[test]
+    fn subscription() {
+        let txs = Subscription::new();
+        let timeout = Duration::from_millis(50);
+        assert_eq!(txs.wait(0, timeout), None);
+
+        let waiter = {
+            let txs = txs.clone();
+            thread::spawn(move || txs.wait_next(Duration::from_secs(10)))
+        };
+        // the waiter may not be waiting yet
+        while !waiter.is_finished() {
+            txs.notify("a");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(waiter.join().unwrap().unwrap().1, "a");
+
+        // missed events return the latest immediately
+        let seq = txs.notify("b");
+        txs.notify("c");
+        assert_eq!(txs.wait(seq, timeout), Some((seq + 1, "c")));
+        assert_eq!(txs.wait(seq + 1, timeout), None);
+        assert_eq!(txs.last_seq(), seq + 1);
+    }
+}
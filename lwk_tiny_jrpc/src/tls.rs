@@ -0,0 +1,457 @@
+//! HTTPS with rustls, available with the `tls` feature, see [`crate::JsonRpcServer::new_tls`].
+//!
+//! The TLS listener terminates the TLS connections and forwards the decrypted streams to a
+//! tiny_http server listening on the loopback interface, the requests are then handled as usual
+//! with the address of the TLS client.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    thread,
+    time::Duration,
+};
+
+use rustls::{
+    pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore, ServerConfig,
+    ServerConnection,
+};
+
+use crate::{error::InnerError, ClientCertificate, Config, ServerState, Shared, POLL_INTERVAL};
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The rustls configuration of the TLS listener, replaced when the certificate is reloaded.
+pub(crate) struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    pub(crate) fn new(config: &Config) -> Result<Self, InnerError> {
+        let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path)
+        else {
+            return Err(InnerError::Tls("missing certificate or key path".into()));
+        };
+        let client_ca_path = config.tls_client_ca_path.as_deref();
+        Ok(Self {
+            config: RwLock::new(load(cert_path, key_path, client_ca_path)?),
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            client_ca_path: client_ca_path.map(Path::to_path_buf),
+        })
+    }
+
+    /// Reads the certificate, key and client CA files again, the connections established
+    /// afterwards use them. The current ones are kept if loading fails.
+    pub(crate) fn reload(&self) -> Result<(), InnerError> {
+        let client_ca_path = self.client_ca_path.as_deref();
+        let config = load(&self.cert_path, &self.key_path, client_ca_path)?;
+        *self
+            .config
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = config;
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+fn load(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> Result<Arc<ServerConfig>, InnerError> {
+    let certs = read_certs(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| InnerError::Tls(format!("{}: {}", key_path.display(), e)))?
+        .ok_or_else(|| InnerError::Tls(format!("{}: no private key", key_path.display())))?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| InnerError::Tls(format!("{}: {}", path.display(), e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| InnerError::Tls(format!("{}: {}", path.display(), e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| InnerError::Tls(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, InnerError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| InnerError::Tls(format!("{}: {}", path.display(), e)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, InnerError> {
+    rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| InnerError::Tls(format!("{}: {}", path.display(), e)))
+}
+
+/// A TLS client.
+#[derive(Clone, Debug)]
+pub(crate) struct Peer {
+    pub(crate) addr: SocketAddr,
+    /// The certificate the client authenticated with, if client certificates are requested.
+    pub(crate) certificate: Option<ClientCertificate>,
+}
+
+/// The TLS clients by the local address of the connection forwarding their requests, which is
+/// the remote address seen by tiny_http.
+#[derive(Default)]
+pub(crate) struct Peers(Mutex<HashMap<SocketAddr, Peer>>);
+
+impl Peers {
+    /// Returns the TLS client forwarded from `addr`, if any.
+    pub(crate) fn get(&self, addr: &SocketAddr) -> Option<Peer> {
+        self.lock().get(addr).cloned()
+    }
+
+    /// Registers the client forwarded from `local_addr` until the guard is dropped.
+    fn register(&self, local_addr: SocketAddr, peer: Peer) -> PeerGuard<'_> {
+        self.lock().insert(local_addr, peer);
+        PeerGuard {
+            peers: self,
+            local_addr,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Peer>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Unregisters a forwarded client when dropped.
+struct PeerGuard<'a> {
+    peers: &'a Peers,
+    local_addr: SocketAddr,
+}
+
+impl Drop for PeerGuard<'_> {
+    fn drop(&mut self) {
+        self.peers.lock().remove(&self.local_addr);
+    }
+}
+
+/// Accepts TLS connections until the server is stopped, forwarding each one to the tiny_http
+/// server at `backend` from its own thread.
+pub(crate) fn listen(
+    listener: TcpListener,
+    backend: SocketAddr,
+    acceptor: Arc<TlsAcceptor>,
+    shared: Arc<Shared>,
+) {
+    // non blocking to notice the server stopping
+    if let Err(err) = listener.set_nonblocking(true) {
+        shared.record_error(format!("TLS listener error: {}", err));
+        return;
+    }
+    #[cfg(unix)]
+    let mut sighups = sighup::count();
+    #[cfg(unix)]
+    if shared.config.tls_reload_on_sighup {
+        sighup::watch();
+    }
+    while shared.state() != ServerState::Stopped {
+        #[cfg(unix)]
+        if shared.config.tls_reload_on_sighup && sighup::count() != sighups {
+            sighups = sighup::count();
+            match acceptor.reload() {
+                Ok(()) => tracing::info!("TLS certificate reloaded"),
+                Err(err) => shared.record_error(format!("TLS reload error: {}", err)),
+            }
+        }
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let (config, shared) = (acceptor.current(), shared.clone());
+                thread::spawn(move || {
+                    if let Err(err) = forward(stream, addr, backend, config, &shared) {
+                        tracing::debug!("TLS connection from {} closed: {}", addr, err);
+                    }
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => shared.record_error(format!("TLS accept error: {}", err)),
+        }
+    }
+}
+
+fn forward(
+    mut client: TcpStream,
+    addr: SocketAddr,
+    backend: SocketAddr,
+    config: Arc<ServerConfig>,
+    shared: &Shared,
+) -> io::Result<()> {
+    client.set_nonblocking(false)?;
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut conn = ServerConnection::new(config).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut client)?;
+    }
+    client.set_read_timeout(None)?;
+
+    let certificate = conn
+        .peer_certificates()
+        .and_then(<[_]>::first)
+        .map(|cert| ClientCertificate::new(cert.to_vec()));
+    // the first request may arrive with the end of the handshake
+    let mut early = Vec::new();
+    match conn.reader().read_to_end(&mut early) {
+        Err(err) if err.kind() != ErrorKind::WouldBlock => return Err(err),
+        _ => {}
+    }
+    let mut backend = TcpStream::connect(backend)?;
+    let peer = Peer { addr, certificate };
+    let _peer = shared.tls_peers.register(backend.local_addr()?, peer);
+    backend.write_all(&early)?;
+
+    // all the writes to the client happen with the connection locked
+    let conn = Arc::new(Mutex::new(conn));
+    let responses = {
+        let (conn, mut client, mut backend) =
+            (conn.clone(), client.try_clone()?, backend.try_clone()?);
+        thread::spawn(move || -> io::Result<()> {
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                let n = backend.read(&mut buf)?;
+                let mut conn = lock(&conn);
+                if n == 0 {
+                    conn.send_close_notify();
+                } else {
+                    conn.writer().write_all(&buf[..n])?;
+                }
+                while conn.wants_write() {
+                    conn.write_tls(&mut client)?;
+                }
+                if n == 0 {
+                    return client.shutdown(Shutdown::Both);
+                }
+            }
+        })
+    };
+
+    let result = forward_requests(&mut client, &mut backend, &conn);
+    let _ = backend.shutdown(Shutdown::Both);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = responses.join();
+    result
+}
+
+// decrypts the data received from the client and forwards it to the backend, until the client
+// closes the connection
+fn forward_requests(
+    client: &mut TcpStream,
+    backend: &mut TcpStream,
+    conn: &Mutex<ServerConnection>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut plaintext = Vec::new();
+    loop {
+        let n = client.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut received = &buf[..n];
+        let mut closed = false;
+        {
+            let mut conn = lock(conn);
+            while !received.is_empty() {
+                conn.read_tls(&mut received)?;
+                let state = match conn.process_new_packets() {
+                    Ok(state) => state,
+                    Err(err) => {
+                        // sends the alert
+                        let _ = conn.write_tls(client);
+                        return Err(tls_error(err));
+                    }
+                };
+                let start = plaintext.len();
+                plaintext.resize(start + state.plaintext_bytes_to_read(), 0);
+                conn.reader().read_exact(&mut plaintext[start..])?;
+                closed |= state.peer_has_closed();
+            }
+            while conn.wants_write() {
+                conn.write_tls(client)?;
+            }
+        }
+        backend.write_all(&plaintext)?;
+        plaintext.clear();
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+fn lock(conn: &Mutex<ServerConnection>) -> MutexGuard<'_, ServerConnection> {
+    conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn tls_error(err: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Counts the SIGHUP signals received by the process, once watched.
+#[cfg(unix)]
+mod sighup {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    };
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static WATCH: Once = Once::new();
+
+    pub(super) fn watch() {
+        WATCH.call_once(|| {
+            // SAFETY: the action only increments an atomic, which is async-signal-safe
+            let result = unsafe {
+                signal_hook_registry::register(libc::SIGHUP, || {
+                    COUNT.fetch_add(1, Ordering::SeqCst);
+                })
+            };
+            if let Err(err) = result {
+                tracing::error!("failed to watch SIGHUP: {}", err);
+            }
+        });
+    }
+
+    pub(super) fn count() -> usize {
+        COUNT.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use crate::{JsonRpcServer, Request, RequestContext, Response};
+
+    use super::*;
+
+    const TLS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test/tls");
+
+    #[test]
+    fn https() {
+        // copied to be able to break them
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("server.pem"), dir.path().join("server.key"));
+        fs::copy(format!("{}/server.pem", TLS_DIR), &cert).unwrap();
+        fs::copy(format!("{}/server.key", TLS_DIR), &key).unwrap();
+
+        let config = Config::builder().with_tls(cert, key.clone()).build();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state| {
+            Ok(Response::result(
+                request.id,
+                request.params.unwrap_or_default(),
+            ))
+        };
+        let rpc = JsonRpcServer::new_tls("127.0.0.1:0", config, state, process).unwrap();
+        let ca = fs::read(format!("{}/ca.pem", TLS_DIR)).unwrap();
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap())
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}", rpc.tls_addr().unwrap().port());
+        let call = || -> Value {
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["hi"]});
+            let response = client.post(&url).json(&request).send().unwrap();
+            response.json().unwrap()
+        };
+        assert_eq!(call()["result"], json!(["hi"]));
+
+        // the current certificate is kept if reloading fails
+        fs::write(&key, "").unwrap();
+        assert!(rpc.reload_tls().is_err());
+        assert_eq!(call()["result"], json!(["hi"]));
+        fs::copy(format!("{}/server.key", TLS_DIR), &key).unwrap();
+        rpc.reload_tls().unwrap();
+        assert_eq!(call()["result"], json!(["hi"]));
+
+        // plain HTTP isn't accepted
+        let plain = reqwest::blocking::Client::new()
+            .post(format!(
+                "http://localhost:{}",
+                rpc.tls_addr().unwrap().port()
+            ))
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "echo"}))
+            .send();
+        assert!(plain.is_err());
+
+        let config = Config::builder().build();
+        let state = Arc::new(Mutex::new(()));
+        assert!(JsonRpcServer::new_tls("127.0.0.1:0", config, state, process).is_err());
+    }
+
+    #[test]
+    fn client_certificate() {
+        let path = |name: &str| PathBuf::from(format!("{}/{}", TLS_DIR, name));
+        let config = Config::builder()
+            .with_tls(path("server.pem"), path("server.key"))
+            .with_tls_client_ca(Some(path("ca.pem")))
+            .build();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, context: &RequestContext, _state| {
+            let fingerprint = context.client_certificate.as_ref().map(|c| &c.fingerprint);
+            Ok(Response::result(request.id, json!(fingerprint)))
+        };
+        let rpc = JsonRpcServer::new_tls_with_context("127.0.0.1:0", config, state, process);
+        let rpc = rpc.unwrap();
+        let url = format!("https://localhost:{}", rpc.tls_addr().unwrap().port());
+        let ca = reqwest::Certificate::from_pem(&fs::read(path("ca.pem")).unwrap()).unwrap();
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "whoami"});
+
+        let mut identity = fs::read(path("client.pem")).unwrap();
+        identity.extend(fs::read(path("client.key")).unwrap());
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(ca.clone())
+            .identity(reqwest::Identity::from_pem(&identity).unwrap())
+            .build()
+            .unwrap();
+        let response: Value = client
+            .post(&url)
+            .json(&request)
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let fingerprint = "8587a24e38f94317d060843f12ac4f0c6074ef0e8acf32f2b3a72ec4df6b08e2";
+        assert_eq!(response["result"], json!(fingerprint));
+
+        // clients without certificate are rejected
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap();
+        let response = client.post(&url).json(&request).send();
+        assert!(response.is_err());
+    }
+}
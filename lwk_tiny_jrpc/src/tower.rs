@@ -0,0 +1,174 @@
+//! A [`tower_service::Service`] running the JSON RPC dispatch, available with the `tower`
+//! feature.
+//!
+//! This allows composing the JSON RPC handling with tower middleware and serving it with other
+//! HTTP implementations than tiny_http, such as hyper.
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderName, HeaderValue, StatusCode};
+use tiny_http::Header;
+
+use crate::{
+    dispatch, error::InnerError, handler::Dispatch, response_body, Config, Error, JsonRpcServer,
+    Reply, Request, RequestContext, Response, Shared,
+};
+
+/// Handles JSON RPC requests like a [`JsonRpcServer`], without the tiny_http transport.
+///
+/// The method handler runs within [`tower_service::Service::call`], handlers blocking for long
+/// should be moved off the async runtime, for instance with a blocking task. Features depending
+/// on the connection, such as the per IP limits, and static file serving are not available.
+#[derive(Clone)]
+pub struct JsonRpcService {
+    shared: Arc<Shared>,
+    handler: Arc<dyn Dispatch<Principal = ()>>,
+}
+
+impl JsonRpcService {
+    pub fn new<F, T>(config: Config, state: Arc<Mutex<T>>, func: F) -> Self
+    where
+        F: Fn(Request, Arc<Mutex<T>>) -> Result<Response, Error> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        Self {
+            shared: Arc::new(Shared::new(config)),
+            handler: JsonRpcServer::handler(state, func),
+        }
+    }
+
+    fn handle(&self, request: http::Request<Bytes>) -> http::Response<Bytes> {
+        if request.method() != http::Method::POST {
+            let err = InnerError::HttpMethodNotAllowed;
+            let mut response =
+                self.to_http(err.http_status(), Response::from_error(None, err).into());
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("POST"));
+            return response;
+        }
+        let headers: Vec<Header> = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Header::from_bytes(name.as_str(), value.as_bytes()).ok())
+            .collect();
+        let context = RequestContext::new(None, &request.uri().to_string(), headers);
+        let (status, reply) = dispatch(
+            &context,
+            request.body().as_ref(),
+            &self.shared,
+            &self.handler,
+        );
+        self.to_http(status, reply)
+    }
+
+    fn to_http(&self, status: u16, reply: Reply) -> http::Response<Bytes> {
+        let data = match reply {
+            Reply::Empty => Bytes::new(),
+            reply => Bytes::from(response_body(&reply, &self.shared)),
+        };
+        let empty = data.is_empty();
+        let mut http_response = http::Response::new(data);
+        *http_response.status_mut() =
+            StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let headers = http_response.headers_mut();
+        if !empty {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+        }
+        for header in self.shared.headers() {
+            let name = HeaderName::from_bytes(header.field.as_str().as_str().as_bytes());
+            let value = HeaderValue::from_bytes(header.value.as_str().as_bytes());
+            if let (Ok(name), Ok(value)) = (name, value) {
+                headers.insert(name, value);
+            }
+        }
+        http_response
+    }
+}
+
+impl tower_service::Service<http::Request<Bytes>> for JsonRpcService {
+    type Response = http::Response<Bytes>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Bytes>) -> Self::Future {
+        ready(Ok(self.handle(request)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::Future,
+        task::{RawWaker, RawWakerVTable, Waker},
+    };
+
+    use serde_json::{json, Value};
+    use tower_service::Service;
+
+    use super::*;
+
+    // the service futures are always ready, so they can be polled once with a no-op waker
+    fn call(service: &mut JsonRpcService, request: http::Request<Bytes>) -> http::Response<Bytes> {
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut future = service.call(request);
+        match std::pin::Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(Ok(response)) => response,
+            _ => panic!("service future not ready"),
+        }
+    }
+
+    #[test]
+    fn service() {
+        let state = Arc::new(Mutex::new(()));
+        let mut service = JsonRpcService::new(Config::default(), state, |request: Request, _| {
+            Ok(Response::result(
+                request.id,
+                request.params.unwrap_or_default(),
+            ))
+        });
+
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["hi"]});
+        let request = http::Request::post("/")
+            .header("Content-Type", "application/json")
+            .body(Bytes::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        let response = call(&mut service, request);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let response: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(response["result"], json!(["hi"]));
+
+        // missing content type
+        let request = http::Request::post("/").body(Bytes::new()).unwrap();
+        let response = call(&mut service, request);
+        let response: Response = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(response.error.unwrap().code, -32_001);
+
+        let request = http::Request::get("/").body(Bytes::new()).unwrap();
+        let response = call(&mut service, request);
+        assert_eq!(response.status(), 405);
+    }
+}
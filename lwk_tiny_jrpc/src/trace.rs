@@ -0,0 +1,206 @@
+//! W3C Trace Context propagation, available with the `tracing-propagation` feature.
+//!
+//! The `traceparent` header of incoming JSON RPC requests is parsed and each request is handled
+//! within a `jsonrpc` [`tracing`] span recording the `trace_id`, `span_id` and `parent_id` fields,
+//! so that subscribers exporting to a distributed tracing system can link it to the caller.
+//! Requests without a valid `traceparent` header start a fresh trace.
+//!
+//! Method handlers can read the context of the request being handled with
+//! [`TraceContext::current`] and propagate it to the services they call with
+//! [`TraceContext::traceparent`].
+//!
+//! See <https://www.w3.org/TR/trace-context/>.
+
+use std::cell::Cell;
+
+use tiny_http::Header;
+
+use crate::util::hex;
+
+/// The trace context of a JSON RPC request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The id of the whole trace, shared with the caller if it sent a valid `traceparent`.
+    pub trace_id: [u8; 16],
+    /// The id of the span handling the request, the parent of the spans of downstream calls.
+    pub span_id: [u8; 8],
+    /// The id of the caller span, `None` if the request started a fresh trace.
+    pub parent_id: Option<[u8; 8]>,
+    /// The trace flags, such as the sampled flag `0x01`.
+    pub flags: u8,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = const { Cell::new(None) };
+}
+
+impl TraceContext {
+    /// Returns the context of the JSON RPC request being handled by the calling thread, `None`
+    /// outside of a method handler.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(Cell::get)
+    }
+
+    /// Returns the context of a request with the given headers, continuing the trace of a valid
+    /// `traceparent` header or starting a fresh one.
+    pub fn from_headers(headers: &[Header]) -> Self {
+        let parent = crate::find_header(headers, "traceparent").and_then(parse_traceparent);
+        match parent {
+            Some((trace_id, parent_id, flags)) => Self {
+                trace_id,
+                span_id: random_span_id(),
+                parent_id: Some(parent_id),
+                flags,
+            },
+            None => Self::root(),
+        }
+    }
+
+    /// Returns the context of a fresh trace.
+    pub fn root() -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_span_id());
+        trace_id[8..].copy_from_slice(&random_span_id());
+        Self {
+            trace_id,
+            span_id: random_span_id(),
+            parent_id: None,
+            flags: 0,
+        }
+    }
+
+    /// The `traceparent` header value to send to downstream services, with the span handling the
+    /// request as parent.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.flags
+        )
+    }
+
+    /// Runs `f` with `self` as [`TraceContext::current`] within the `jsonrpc` span.
+    pub(crate) fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        let span = tracing::info_span!(
+            "jsonrpc",
+            trace_id = %hex(&self.trace_id),
+            span_id = %hex(&self.span_id),
+            parent_id = self.parent_id.map(|id| hex(&id)),
+        );
+        let previous = CURRENT.with(|current| current.replace(Some(self)));
+        // restores the previous context even if `f` panics
+        struct Restore(Option<TraceContext>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|current| current.set(self.0));
+            }
+        }
+        let _restore = Restore(previous);
+        span.in_scope(f)
+    }
+}
+
+// parses a traceparent header value into trace id, parent id and flags
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let value = value.trim();
+    let version = parse_hex::<1>(value.get(..2)?)?[0];
+    // version 00 has exactly 4 fields, future versions may append more
+    let valid_length = match version {
+        0xff => false,
+        0x00 => value.len() == 55,
+        _ => value.len() == 55 || value.as_bytes().get(55) == Some(&b'-'),
+    };
+    let separators = [2, 35, 52]
+        .iter()
+        .all(|&i| value.as_bytes().get(i) == Some(&b'-'));
+    if !valid_length || !separators {
+        return None;
+    }
+    let trace_id = parse_hex::<16>(value.get(3..35)?)?;
+    let parent_id = parse_hex::<8>(value.get(36..52)?)?;
+    let flags = parse_hex::<1>(value.get(53..55)?)?[0];
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags))
+}
+
+// parses lowercase hex, uppercase is invalid in traceparent
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn random_span_id() -> [u8; 8] {
+    // the all zero id is invalid
+    crate::id::random_u64().max(1).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parse() {
+        let (trace_id, parent_id, flags) = parse_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(hex(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&parent_id), "00f067aa0ba902b7");
+        assert_eq!(flags, 1);
+
+        // future versions may append fields
+        assert!(parse_traceparent(&format!("01{}-ab", &TRACEPARENT[2..])).is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-ab",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00_4bf92f3577b34da6a3ce929d0e0e4736_00f067aa0ba902b7_01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+        ] {
+            assert!(parse_traceparent(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn from_headers() {
+        let header = Header::from_str(&format!("traceparent: {}", TRACEPARENT)).unwrap();
+        let context = TraceContext::from_headers(&[header]);
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.parent_id.unwrap()), "00f067aa0ba902b7");
+        assert_ne!(context.span_id, context.parent_id.unwrap());
+        let traceparent = context.traceparent();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert!(parse_traceparent(&traceparent).is_some());
+
+        let header = Header::from_str("traceparent: invalid").unwrap();
+        let context = TraceContext::from_headers(&[header]);
+        assert_eq!(context.parent_id, None);
+        assert_ne!(context.trace_id, TraceContext::root().trace_id);
+        assert!(parse_traceparent(&context.traceparent()).is_some());
+    }
+
+    #[test]
+    fn current() {
+        assert_eq!(TraceContext::current(), None);
+        let context = TraceContext::root();
+        let inner = context.scope(TraceContext::current);
+        assert_eq!(inner, Some(context));
+        assert_eq!(TraceContext::current(), None);
+    }
+}
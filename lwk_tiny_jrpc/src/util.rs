@@ -0,0 +1,13 @@
+//! Small helpers shared by the optional modules.
+
+#[cfg(any(feature = "tls", feature = "tracing-propagation"))]
+use std::fmt::Write;
+
+/// Formats `bytes` as lowercase hex.
+#[cfg(any(feature = "tls", feature = "tracing-propagation"))]
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
@@ -0,0 +1,566 @@
+//! A WebSocket listener speaking JSON RPC, available with the `websocket` feature.
+//!
+//! Each text or binary message carries a request or a batch, answered with a message carrying the
+//! reply like the body of an HTTP response, if any. The notifications sent with
+//! [`crate::JsonRpcServer::broadcast`] are pushed to every connection as they are sent.
+//!
+//! See <https://www.rfc-editor.org/rfc/rfc6455>.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use base64::Engine;
+use bitcoin_hashes::{sha1, Hash};
+use tiny_http::Header;
+
+use crate::{
+    dispatch, handler::Dispatch, limit::Semaphore, response_body, InFlight, InnerError, Reply,
+    RequestContext, Response, ServerState, Shared, POLL_INTERVAL,
+};
+
+/// Appended to the client key to compute the `Sec-WebSocket-Accept` header.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The maximum size of the handshake request.
+const MAX_HANDSHAKE_BYTES: usize = 8 * 1024;
+
+/// The maximum size of a message if [`crate::Config::max_body_bytes`] is not set.
+const MAX_MESSAGE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How long a client may take to send the rest of a frame once it started.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Accepts connections until the server is stopped, each one served by its own thread, up to
+/// [`crate::Config::max_websocket_connections`].
+pub(crate) fn listen<D: Dispatch>(listener: TcpListener, shared: Arc<Shared>, handler: Arc<D>) {
+    // non blocking to notice the server stopping
+    if let Err(err) = listener.set_nonblocking(true) {
+        shared.record_error(format!("websocket listener error: {}", err));
+        return;
+    }
+    let connections = Arc::new(Semaphore::new(shared.config.max_websocket_connections));
+    while shared.state() != ServerState::Stopped {
+        match listener.accept() {
+            Ok((mut stream, addr)) => {
+                let Some(permit) = connections.try_acquire() else {
+                    tracing::debug!("Refusing websocket connection from {}", addr);
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n",
+                    );
+                    shared.count_response(503, None);
+                    continue;
+                };
+                let (shared, handler) = (shared.clone(), handler.clone());
+                thread::spawn(move || {
+                    let _permit = permit;
+                    if let Err(err) = serve(stream, addr, &shared, &handler) {
+                        tracing::debug!("websocket connection from {} closed: {}", addr, err);
+                    }
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => shared.record_error(format!("websocket accept error: {}", err)),
+        }
+    }
+}
+
+fn serve<D: Dispatch>(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(FRAME_TIMEOUT))?;
+    let (url, headers) = read_handshake(&mut stream)?;
+
+    // admitted like HTTP requests, the client IP slot is held until the connection is closed
+    let addr = shared.forwarded_addr(addr, &headers);
+    if let Some(filter) = &shared.config.accept_filter {
+        if !filter(addr.ip()) {
+            return refuse(&mut stream, shared, 403, "Forbidden");
+        }
+    }
+    if let (Some(cors), Some(origin)) = (&shared.config.cors, find(&headers, "Origin")) {
+        if !cors.allows(origin) {
+            return refuse(&mut stream, shared, 403, "Forbidden");
+        }
+    }
+    let _ip_permit = match &shared.ip_limits {
+        Some(limiter) => match limiter.try_acquire(addr.ip()) {
+            Some(permit) => Some(permit),
+            None => return refuse(&mut stream, shared, 429, "Too Many Requests"),
+        },
+        None => None,
+    };
+
+    let Some(key) = find(&headers, "Sec-WebSocket-Key") else {
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?;
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a websocket request",
+        ));
+    };
+    if handler.authenticate(&headers).is_err() {
+        stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nConnection: close\r\n\r\n")?;
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "unauthorized"));
+    }
+    let mut head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    for header in shared.headers() {
+        head.insert_str(
+            head.len() - 2,
+            &format!("{}: {}\r\n", header.field, header.value),
+        );
+    }
+    stream.write_all(head.as_bytes())?;
+    shared.count_response(101, None);
+
+    let context = RequestContext::new(Some(addr), &url, json_headers(headers));
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let closed = Arc::new(AtomicBool::new(false));
+    let notifier = {
+        let (writer, closed) = (writer.clone(), closed.clone());
+        let notifications = shared.subscribers.subscribe();
+        let shared = shared.clone();
+        thread::spawn(move || loop {
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            if shared.state() == ServerState::Stopped {
+                let _ = send_close(&writer, CLOSE_GOING_AWAY);
+                let _ = writer.lock().map(|w| w.shutdown(Shutdown::Both));
+                break;
+            }
+            match notifications.recv_timeout(POLL_INTERVAL) {
+                Ok(notification) => {
+                    let data = notification.to_string();
+                    if send(&writer, OPCODE_TEXT, data.as_bytes()).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        })
+    };
+
+    let result = receive(&mut stream, &writer, &context, shared, handler);
+    closed.store(true, Ordering::SeqCst);
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = notifier.join();
+    result
+}
+
+// answers the opening handshake with an HTTP error, without upgrading the connection
+fn refuse(stream: &mut TcpStream, shared: &Shared, status: u16, reason: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nConnection: close\r\n\r\n",
+        status, reason
+    )?;
+    shared.count_response(status, None);
+    Err(io::Error::new(ErrorKind::PermissionDenied, reason))
+}
+
+// handles the messages of the client until it closes the connection
+fn receive<D: Dispatch>(
+    stream: &mut TcpStream,
+    writer: &Mutex<TcpStream>,
+    context: &RequestContext,
+    shared: &Arc<Shared>,
+    handler: &Arc<D>,
+) -> io::Result<()> {
+    let max = shared.config.max_body_bytes.unwrap_or(MAX_MESSAGE_BYTES);
+    // the message being received, if any, possibly fragmented in several frames
+    let mut message: Option<Vec<u8>> = None;
+    loop {
+        let received = message.as_ref().map_or(0, Vec::len) as u64;
+        let frame = match read_frame(stream, max - received) {
+            Ok(frame) => frame,
+            Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                send_close(writer, CLOSE_TOO_BIG)?;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+        match frame.opcode {
+            OPCODE_TEXT | OPCODE_BINARY if message.is_none() => message = Some(frame.payload),
+            OPCODE_CONTINUATION if message.is_some() => {
+                message.get_or_insert_with(Vec::new).extend(frame.payload)
+            }
+            OPCODE_PING => {
+                send(writer, OPCODE_PONG, &frame.payload)?;
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_CLOSE => {
+                send(writer, OPCODE_CLOSE, frame.payload.get(..2).unwrap_or(&[]))?;
+                return Ok(());
+            }
+            _ => {
+                send_close(writer, CLOSE_PROTOCOL_ERROR)?;
+                return Err(io::Error::new(ErrorKind::InvalidData, "unexpected frame"));
+            }
+        }
+        if !frame.fin {
+            continue;
+        }
+
+        let body = message.take().unwrap_or_default();
+        let reply = {
+            // counted before checking the state, so that draining can't complete in between
+            let _in_flight = InFlight::new(shared);
+            if shared.state() == ServerState::Running {
+                dispatch(context, &body[..], shared, handler).1
+            } else {
+                Response::from_error(None, InnerError::Draining).into()
+            }
+        };
+        if !matches!(reply, Reply::Empty) {
+            send(
+                writer,
+                OPCODE_TEXT,
+                response_body(&reply, shared).as_bytes(),
+            )?;
+        }
+    }
+}
+
+// reads the request line and headers of the opening handshake
+fn read_handshake(stream: &mut TcpStream) -> io::Result<(String, Vec<Header>)> {
+    let mut data = Vec::new();
+    let mut byte = [0u8];
+    while !data.ends_with(b"\r\n\r\n") {
+        if data.len() >= MAX_HANDSHAKE_BYTES {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "handshake too large",
+            ));
+        }
+        stream.read_exact(&mut byte)?;
+        data.push(byte[0]);
+    }
+    let data = String::from_utf8_lossy(&data);
+    let mut lines = data.split("\r\n");
+    let url = lines
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|line| line.split(' ').next())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not a GET request"))?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .filter_map(|(field, value)| Header::from_bytes(field.trim(), value.trim()).ok())
+        .collect();
+    Ok((url.to_string(), headers))
+}
+
+fn find<'a>(headers: &'a [Header], field: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(field))
+        .map(|h| h.value.as_str())
+}
+
+// the messages are validated like the bodies of HTTP requests carrying JSON, the handshake
+// headers describing the body are replaced accordingly
+fn json_headers(headers: Vec<Header>) -> Vec<Header> {
+    let mut headers: Vec<_> = headers
+        .into_iter()
+        .filter(|h| {
+            !h.field.equiv("Content-Type")
+                && !h.field.equiv("Content-Length")
+                && !h.field.equiv("Content-Encoding")
+        })
+        .collect();
+    headers.extend(Header::from_bytes("Content-Type", "application/json"));
+    headers
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hash.to_byte_array())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// reads a client frame, waiting for its start until the connection is closed, failing with
+// `InvalidInput` if its payload is larger than `max`
+fn read_frame(stream: &mut TcpStream, max: u64) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    stream.set_read_timeout(None)?;
+    stream.read_exact(&mut head[..1])?;
+    stream.set_read_timeout(Some(FRAME_TIMEOUT))?;
+    stream.read_exact(&mut head[1..])?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "unmasked frame"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > max {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "message too large"));
+    }
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// writes an unmasked final frame, server frames are never fragmented
+fn send(writer: &Mutex<TcpStream>, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    let mut writer = writer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn send_close(writer: &Mutex<TcpStream>, code: u16) -> io::Result<()> {
+    send(writer, OPCODE_CLOSE, &code.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use serde_json::{json, Value};
+    use tiny_http::Server;
+
+    use super::*;
+    use crate::{Config, JsonRpcServer, Request};
+
+    #[test]
+    fn accept() {
+        // the example of RFC 6455
+        let key = accept_key("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(key, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    // writes a masked client frame
+    fn write_frame(stream: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8]) {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        stream.write_all(&frame).unwrap();
+    }
+
+    // reads an unmasked server frame
+    fn read_server_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80);
+        let len = match head[1] {
+            126 => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn read_json(stream: &mut TcpStream) -> Value {
+        let (opcode, payload) = read_server_frame(stream);
+        assert_eq!(opcode, OPCODE_TEXT);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    // sends the opening handshake, returning the status line of the response and the connection
+    fn handshake(addr: SocketAddr, headers: &str) -> (String, TcpStream) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{}\r\n",
+            headers
+        )
+        .unwrap();
+        let mut line = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        (line, stream)
+    }
+
+    #[test]
+    fn admission() {
+        let process = |request: Request, _state| Ok(Response::result(request.id, json!(null)));
+        let start = |config: crate::config::ConfigBuilder| {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let config = config
+                .with_websocket_addr(Some("127.0.0.1:0".parse().unwrap()))
+                .build();
+            JsonRpcServer::new(server, config, Arc::new(Mutex::new(())), process)
+        };
+
+        let rpc = start(Config::builder().with_accept_filter(|ip| !ip.is_loopback()));
+        let (status, _) = handshake(rpc.websocket_addr().unwrap(), "");
+        assert_eq!(status, "HTTP/1.1 403 Forbidden\r\n");
+
+        let cors = crate::config::CorsPolicy::origins(["https://wallet.example"]);
+        let rpc = start(Config::builder().with_cors(cors));
+        let addr = rpc.websocket_addr().unwrap();
+        let (status, _) = handshake(addr, "Origin: https://evil.example\r\n");
+        assert_eq!(status, "HTTP/1.1 403 Forbidden\r\n");
+        let (status, _) = handshake(addr, "Origin: https://wallet.example\r\n");
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+
+        let rpc = start(Config::builder().with_max_websocket_connections(1));
+        let addr = rpc.websocket_addr().unwrap();
+        let (status, _open) = handshake(addr, "");
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+        let (status, _) = handshake(addr, "");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable\r\n");
+    }
+
+    #[test]
+    fn websocket() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let state = Arc::new(Mutex::new(()));
+        let process = |request: Request, _state| {
+            Ok(Response::result(
+                request.id,
+                request.params.unwrap_or_default(),
+            ))
+        };
+        let config = Config::builder()
+            .with_websocket_addr(Some("127.0.0.1:0".parse().unwrap()))
+            .build();
+        let rpc = JsonRpcServer::new(server, config, state, process);
+        let mut stream = TcpStream::connect(rpc.websocket_addr().unwrap()).unwrap();
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+        let mut accept = None;
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Accept: ") {
+                accept = Some(value.trim().to_string());
+            }
+        }
+        assert_eq!(accept.unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["hi"]});
+        write_frame(
+            &mut stream,
+            true,
+            OPCODE_TEXT,
+            request.to_string().as_bytes(),
+        );
+        assert_eq!(read_json(&mut stream)["result"], json!(["hi"]));
+
+        // fragmented message
+        let request = json!({"jsonrpc": "2.0", "id": 2, "method": "echo", "params": [2]});
+        let data = request.to_string();
+        let (first, second) = data.as_bytes().split_at(10);
+        write_frame(&mut stream, false, OPCODE_TEXT, first);
+        write_frame(&mut stream, true, OPCODE_CONTINUATION, second);
+        assert_eq!(read_json(&mut stream)["id"], json!(2));
+
+        // notifications aren't answered, broadcasts are pushed
+        let notification = json!({"jsonrpc": "2.0", "method": "echo"});
+        write_frame(
+            &mut stream,
+            true,
+            OPCODE_TEXT,
+            notification.to_string().as_bytes(),
+        );
+        while rpc.broadcast("block", json!([1])) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read_json(&mut stream)["method"], json!("block"));
+
+        write_frame(&mut stream, true, OPCODE_PING, b"p");
+        assert_eq!(read_server_frame(&mut stream), (OPCODE_PONG, b"p".to_vec()));
+
+        write_frame(&mut stream, true, OPCODE_CLOSE, &1000u16.to_be_bytes());
+        let (opcode, payload) = read_server_frame(&mut stream);
+        assert_eq!(
+            (opcode, payload),
+            (OPCODE_CLOSE, 1000u16.to_be_bytes().to_vec())
+        );
+    }
+}
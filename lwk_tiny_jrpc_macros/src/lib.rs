@@ -0,0 +1,103 @@
+//! The `rpc_method` attribute declaring method handlers of the Tiny JSON RPC Server, re-exported
+//! by `lwk_tiny_jrpc` with the `macros` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, LitStr};
+
+/// Declares a function as the handler of a JSON RPC method, to register in a `Router` with
+/// `router.register_method::<f>()`.
+///
+/// The function takes up to two arguments, the params deserialized from the request and the
+/// `Arc<Mutex<T>>` server state, and returns a `Result` whose value is serialized as the result
+/// and whose error converts into `lwk_tiny_jrpc::Error`:
+///
+/// ```ignore
+/// #[rpc_method("add")]
+/// fn add((a, b): (u64, u64), state: Arc<Mutex<State>>) -> Result<u64, Error> {
+///     Ok(a + b)
+/// }
+/// ```
+///
+/// The function is left unchanged, a struct with the same name implementing
+/// `lwk_tiny_jrpc::router::RpcMethod` is declared alongside it.
+#[proc_macro_attribute]
+pub fn rpc_method(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(attr as LitStr);
+    let function = parse_macro_input!(item as ItemFn);
+    expand(name, function)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(name: LitStr, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let signature = &function.sig;
+    if !signature.generics.params.is_empty() || signature.asyncness.is_some() {
+        return Err(syn::Error::new_spanned(
+            signature,
+            "rpc_method handlers can't be generic or async",
+        ));
+    }
+    let mut types = Vec::new();
+    for input in &signature.inputs {
+        match input {
+            FnArg::Typed(arg) => types.push(&arg.ty),
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new_spanned(
+                    receiver,
+                    "rpc_method handlers can't take self",
+                ))
+            }
+        }
+    }
+
+    let ident = &signature.ident;
+    let vis = &function.vis;
+    let state = Ident::new("__State", Span::call_site());
+    let (impl_generics, state_type, call) = match types[..] {
+        [] => (quote!(<#state>), quote!(#state), quote!(#ident())),
+        [params] => (
+            quote!(<#state>),
+            quote!(#state),
+            quote!(#ident(request.params_as::<#params>()?)),
+        ),
+        [params, shared] => (
+            quote!(),
+            quote!(<#shared as ::lwk_tiny_jrpc::router::SharedState>::State),
+            quote!(#ident(request.params_as::<#params>()?, state)),
+        ),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &signature.inputs,
+                "rpc_method handlers take at most the params and the state",
+            ))
+        }
+    };
+    let unused_state = if types.len() < 2 {
+        quote!(let _ = state;)
+    } else {
+        quote!()
+    };
+
+    Ok(quote! {
+        #function
+
+        #[allow(non_camel_case_types)]
+        #[doc(hidden)]
+        #vis struct #ident {}
+
+        impl #impl_generics ::lwk_tiny_jrpc::router::RpcMethod<#state_type> for #ident {
+            const NAME: &'static str = #name;
+
+            fn call(
+                request: ::lwk_tiny_jrpc::Request,
+                state: ::std::sync::Arc<::std::sync::Mutex<#state_type>>,
+            ) -> ::std::result::Result<::lwk_tiny_jrpc::Response, ::lwk_tiny_jrpc::Error> {
+                #unused_state
+                let result = #call?;
+                ::lwk_tiny_jrpc::Response::ok(request.id, result)
+            }
+        }
+    })
+}
@@ -0,0 +1,56 @@
+use std::sync::{Arc, Mutex};
+
+use lwk_tiny_jrpc::{rpc_method, Error, Id, Request, Router};
+use serde_json::{json, Value};
+
+#[rpc_method("add")]
+fn add((a, b): (u64, u64), state: Arc<Mutex<u64>>) -> Result<u64, Error> {
+    *state.lock().unwrap() += 1;
+    Ok(a + b)
+}
+
+#[rpc_method("echo")]
+fn echo(params: Value) -> Result<Value, Error> {
+    Ok(params)
+}
+
+#[rpc_method("version")]
+fn version() -> Result<&'static str, Error> {
+    Ok("1.0")
+}
+
+fn request(method: &str, params: Value) -> Request {
+    Request {
+        jsonrpc: "2.0".into(),
+        id: Some(Id::Number(1.into())),
+        method: method.into(),
+        params: Some(params),
+    }
+}
+
+#[test]
+fn rpc_method() {
+    let mut router = Router::new();
+    router
+        .register_method::<add>()
+        .register_method::<echo>()
+        .register_method::<version>();
+    assert_eq!(router.methods(), vec!["add", "echo", "version"]);
+
+    let state = Arc::new(Mutex::new(0));
+    let response = router.handle(request("add", json!([1, 2])), state.clone());
+    assert_eq!(response.unwrap().result, Some(json!(3)));
+    assert_eq!(*state.lock().unwrap(), 1);
+    assert!(router
+        .handle(request("add", json!({"a": 1})), state.clone())
+        .is_err());
+
+    let response = router.handle(request("echo", json!({"a": 1})), state.clone());
+    assert_eq!(response.unwrap().result, Some(json!({"a": 1})));
+
+    let response = router.handle(request("version", json!(null)), state);
+    assert_eq!(response.unwrap().result, Some(json!("1.0")));
+
+    // the function is still callable
+    assert_eq!(add((2, 3), Arc::new(Mutex::new(0))).unwrap(), 5);
+}